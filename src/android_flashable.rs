@@ -1 +1,874 @@
+use crate::backup_factory::{
+    locate_firmware_parts, new_progress_bar, BackupIndexEntry, BackupTrait, BackupType,
+    FirmwarePart, ImageSink, SegmentReader, SegmentWriter, SlotGpt,
+};
+use crate::constants::USERDATA_NAME;
+///android flashable module
+/// Packs the images of a slot into a recovery flashable zip, equal to a normal rom.zip:
+/// images/<partition>.img (stored, 4096 aligned, zip64 if needed) and
+/// META-INF/com/google/android/update-binary (a shell installer) which runs the
+/// updater-script beside it (the flash list).
+/// Images are flashed with dd straight out of the zip, so recovery needs no zip64 aware unzip.
+/// With the slot gpt (`archive -g`) gpt/<disk>.primary.bin and gpt/<disk>.backup.bin are added,
+/// written back first, and images are then flashed to their disk offsets instead of by name.
+/// With `archive -s` images are android sparse images (see `simg_helper`), fastboot flashes them
+/// as is and the installer expands them with dd.
+/// `import_archive` takes such a zip back into a slot, images are read in place from the zip.
+use crate::error::RvabError;
+use crate::gpt_helper::{bytes2ieee, get_disk_sector_size, get_part_accelerate_location};
+use crate::metadata::{Metadata, PartitionRawTarget, Slot};
+use crate::simg_helper::{is_simg, SimgEncoder, SimgReader};
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
 
+const UPDATE_BINARY_PATH: &str = "META-INF/com/google/android/update-binary";
+const UPDATER_SCRIPT_PATH: &str = "META-INF/com/google/android/updater-script";
+/// images are aligned to this in the zip, dd reads them with this block size
+const IMAGE_ALIGNMENT: u64 = 4096;
+const IMAGE_COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// update-binary, recovery calls it with (api version,output fd,zip path)
+/// the updater-script is extracted with dd at the given offset and sourced
+const UPDATE_BINARY_HEAD: &str = r#"#!/sbin/sh
+# rvab flashable zip installer, generated by rvab archive
+OUTFD=$2
+ZIPFILE=$3
+
+ui_print() {
+  echo "ui_print $1" >> /proc/self/fd/$OUTFD
+  echo "ui_print" >> /proc/self/fd/$OUTFD
+}
+
+abort() {
+  ui_print "$1"
+  exit 1
+}
+
+# check_image <partition> <device> <skip blocks> <length> <block size> <sha256>
+check_image() {
+  if command -v sha256sum > /dev/null; then
+    sum=$(dd if="$2" bs=$5 skip=$3 count=$(($4 / $5)) 2>/dev/null | sha256sum | cut -d ' ' -f 1)
+    [ "$sum" = "$6" ] || abort "Error: sha256 of $1 not match"
+  fi
+}
+
+# flash_image <partition> <offset in zip> <length> <block size> <sha256>
+flash_image() {
+  target=/dev/block/by-name/$1
+  [ -b "$target" ] || abort "Error: partition $1 not found"
+  ui_print "Flashing $1"
+  dd if="$ZIPFILE" of="$target" bs=$4 skip=$(($2 / $4)) count=$(($3 / $4)) conv=notrunc 2>/dev/null || abort "Error: flash $1 failed"
+  check_image $1 "$target" 0 $3 $4 $5
+}
+
+# find_disk <disk name>, print its device node
+find_disk() {
+  for disk in /dev/block/$1 /dev/$1; do
+    if [ -b "$disk" ]; then
+      echo "$disk"
+      return 0
+    fi
+  done
+  return 1
+}
+
+# flash_gpt <disk name> <offset in zip> <length> <disk offset>
+flash_gpt() {
+  disk=$(find_disk $1) || abort "Error: disk $1 not found"
+  ui_print "Restoring gpt of $1"
+  dd if="$ZIPFILE" of="$disk" bs=512 skip=$(($2 / 512)) seek=$(($4 / 512)) count=$(($3 / 512)) conv=notrunc 2>/dev/null || abort "Error: restore gpt of $1 failed"
+  blockdev --rereadpt "$disk" 2>/dev/null
+}
+
+# flash_disk <partition> <disk name> <disk offset> <offset in zip> <length> <block size> <sha256>
+flash_disk() {
+  disk=$(find_disk $2) || abort "Error: disk $2 not found"
+  ui_print "Flashing $1"
+  dd if="$ZIPFILE" of="$disk" bs=$6 skip=$(($4 / $6)) seek=$(($3 / $6)) count=$(($5 / $6)) conv=notrunc 2>/dev/null || abort "Error: flash $1 failed"
+  check_image $1 "$disk" $(($3 / $6)) $5 $6 $7
+}
+
+# u32s <offset in zip> <count>, print little endian u32 values
+u32s() {
+  dd if="$ZIPFILE" bs=4 skip=$(($1 / 4)) count=$2 2>/dev/null | od -An -tu4
+}
+
+# write_simg <partition> <device> <device offset> <offset in zip>
+# expand a sparse image of rvab archive (28 bytes file head,12 bytes chunk heads,zero fills)
+write_simg() {
+  name=$1; dev=$2; out=$3; pos=$4
+  set -- $(u32s $pos 7)
+  [ "$1" = "3978755898" ] || abort "Error: $name is not a sparse image"
+  bsize=$4; chunks=$6; pos=$((pos + 28))
+  while [ $chunks -gt 0 ]; do
+    set -- $(u32s $pos 3)
+    size=$(($2 * bsize))
+    case $(($1 & 65535)) in
+      51905)
+        dd if="$ZIPFILE" of="$dev" bs=1048576 skip=$((pos + 12)) seek=$out count=$size iflag=skip_bytes,count_bytes oflag=seek_bytes conv=notrunc 2>/dev/null || abort "Error: flash $name failed" ;;
+      51906)
+        [ $(u32s $((pos + 12)) 1) = 0 ] || abort "Error: unsupported fill in $name"
+        dd if=/dev/zero of="$dev" bs=1048576 seek=$out count=$size iflag=count_bytes oflag=seek_bytes conv=notrunc 2>/dev/null || abort "Error: flash $name failed" ;;
+      *)
+        abort "Error: unsupported chunk in $name" ;;
+    esac
+    pos=$((pos + $3)); out=$((out + size)); chunks=$((chunks - 1))
+  done
+}
+
+# flash_simg_image <partition> <offset in zip> <length> <block size> <sha256>
+flash_simg_image() {
+  target=/dev/block/by-name/$1
+  [ -b "$target" ] || abort "Error: partition $1 not found"
+  ui_print "Flashing $1"
+  write_simg $1 "$target" 0 $2
+  check_image $1 "$target" 0 $3 $4 $5
+}
+
+# flash_simg_disk <partition> <disk name> <disk offset> <offset in zip> <length> <block size> <sha256>
+flash_simg_disk() {
+  disk=$(find_disk $2) || abort "Error: disk $2 not found"
+  ui_print "Flashing $1"
+  write_simg $1 "$disk" $3 $4
+  check_image $1 "$disk" $(($3 / $6)) $5 $6 $7
+}
+
+"#;
+
+/// An image written into the zip
+struct ArchivedImage {
+    name: String,
+    //offset of the image data in the zip
+    offset: u64,
+    length: u64,
+    sha256: [u8; 32],
+    //(disk name,byte offset on disk), set if the archive carries the gpt
+    location: Option<(String, u64)>,
+    //stored as android sparse image
+    simg: bool,
+}
+
+/// A gpt table written into the zip
+struct ArchivedGpt {
+    disk: String,
+    //offset of the table in the zip
+    offset: u64,
+    length: u64,
+    disk_offset: u64,
+}
+
+/// Writes image data into the zip, through the sparse encoder while one is set
+struct ImageWriter {
+    zip: ZipWriter<File>,
+    encoder: Option<SimgEncoder>,
+}
+
+impl Write for ImageWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.push(buf, &mut self.zip)?,
+            None => self.zip.write_all(buf)?,
+        };
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.zip.flush()
+    }
+}
+
+/// A recovery flashable zip being written
+pub struct FlashableZip {
+    out: ImageWriter,
+    //shares the file offset with the zip writer, tells where entry data starts
+    probe: File,
+    //write images as android sparse images
+    simg: bool,
+    images: Vec<ArchivedImage>,
+    gpts: Vec<ArchivedGpt>,
+}
+
+impl FlashableZip {
+    pub fn create(path: &str, simg: bool) -> Result<Self, RvabError> {
+        let file = File::create(path).map_err(|_| "Error: create archive file failed")?;
+        let probe = file
+            .try_clone()
+            .map_err(|_| "Error: create archive file failed")?;
+        Ok(FlashableZip {
+            out: ImageWriter {
+                zip: ZipWriter::new(file),
+                encoder: None,
+            },
+            probe,
+            simg,
+            images: Vec::new(),
+            gpts: Vec::new(),
+        })
+    }
+
+    /// start a stored entry, return offset of its data in the zip
+    fn start_entry(&mut self, path: &str, length: u64) -> Result<u64, RvabError> {
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .with_alignment(IMAGE_ALIGNMENT as u16)
+            .large_file(length >= u32::MAX as u64)
+            .unix_permissions(0o755);
+        self.out
+            .zip
+            .start_file(path, options)
+            .map_err(|_| "Error: write archive file failed")?;
+        self.probe
+            .stream_position()
+            .map_err(|_| RvabError::Backend("Error: write archive file failed"))
+    }
+
+    /// location is only kept if the archive carries the gpt
+    fn start_image(
+        &mut self,
+        name: &str,
+        length: u64,
+        location: Option<(&str, u64)>,
+    ) -> Result<(), RvabError> {
+        if self.images.iter().any(|x| x.name == name) {
+            eprintln!("Error: partition {} is archived twice", name);
+            return Err(RvabError::Backend("Error: duplicate partition in archive"));
+        };
+        let location = match location {
+            Some((driver, offset)) if !self.gpts.is_empty() => Some((disk_name(driver)?, offset)),
+            _ => None,
+        };
+        let path = format!("images/{}.img", name);
+        let offset = if self.simg {
+            let block_size = if length.is_multiple_of(IMAGE_ALIGNMENT) {
+                IMAGE_ALIGNMENT
+            } else {
+                512
+            };
+            let encoder = SimgEncoder::new(length, block_size as u32)?;
+            let offset = self.start_entry(&path, SimgEncoder::max_length(length))?;
+            self.out
+                .zip
+                .write_all(&encoder.head())
+                .map_err(|_| "Error: write archive file failed")?;
+            self.out.encoder = Some(encoder);
+            offset
+        } else {
+            self.start_entry(&path, length)?
+        };
+        self.images.push(ArchivedImage {
+            name: name.to_string(),
+            offset,
+            length,
+            sha256: [0; 32],
+            location,
+            simg: self.simg,
+        });
+        Ok(())
+    }
+
+    /// all data of the image is written, flush the last sparse chunk
+    fn end_image_data(&mut self) -> Result<(), RvabError> {
+        if let Some(encoder) = self.out.encoder.take() {
+            encoder
+                .finish(&mut self.out.zip)
+                .map_err(|_| "Error: write archive file failed")?;
+        };
+        Ok(())
+    }
+
+    /// add the primary and backup gpt of every disk, must be done before any image
+    pub fn add_gpt(&mut self, gpt: &SlotGpt) -> Result<(), RvabError> {
+        if !self.images.is_empty() {
+            return Err(RvabError::Backend(
+                "Error: gpt must be archived before images",
+            ));
+        };
+        for (driver, raw_gpt) in gpt.disks.iter() {
+            let disk = disk_name(driver)?;
+            for (kind, disk_offset, table) in [
+                ("primary", raw_gpt.primary_offset, &raw_gpt.primary),
+                ("backup", raw_gpt.backup_offset, &raw_gpt.backup),
+            ] {
+                let offset =
+                    self.start_entry(&format!("gpt/{}.{}.bin", disk, kind), table.len() as u64)?;
+                self.out
+                    .zip
+                    .write_all(table)
+                    .map_err(|_| "Error: write archive file failed")?;
+                self.gpts.push(ArchivedGpt {
+                    disk: disk.clone(),
+                    offset,
+                    length: table.len() as u64,
+                    disk_offset,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// copy a partition (bytes of driver) into the zip
+    pub fn add_partition(
+        &mut self,
+        name: &str,
+        driver: &str,
+        offset: u64,
+        length: u64,
+        pb: &ProgressBar,
+    ) -> Result<(), RvabError> {
+        self.start_image(name, length, Some((driver, offset)))?;
+        let file = File::open(driver).map_err(|_| "Error: open source disk failed")?;
+        let mut reader = SegmentReader::new(&file, offset, length);
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; IMAGE_COPY_BUFFER_SIZE];
+        let mut done = 0;
+        while done < length {
+            let size = reader
+                .read(&mut buffer)
+                .map_err(|_| "Error: read source disk failed")?;
+            if size == 0 {
+                return Err(RvabError::Backend("Error: read source disk failed"));
+            };
+            self.out
+                .write_all(&buffer[..size])
+                .map_err(|_| "Error: write archive file failed")?;
+            hasher.update(&buffer[..size]);
+            done += size as u64;
+            pb.inc(size as u64);
+        }
+        self.end_image_data()?;
+        self.images.last_mut().unwrap().sha256 = hasher.finalize().into();
+        Ok(())
+    }
+
+    /// the flash list sourced by update-binary
+    fn updater_script(&self, slot_name: &str) -> String {
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        let mut script = format!(
+            "# rvab archive of slot {}, created {}\nui_print \"rvab archive of slot {}\"\n",
+            slot_name, created, slot_name
+        );
+        for gpt in self.gpts.iter() {
+            script.push_str(&format!(
+                "flash_gpt {} {} {} {}\n",
+                gpt.disk, gpt.offset, gpt.length, gpt.disk_offset
+            ));
+        }
+        for image in self.images.iter() {
+            let disk_offset = image.location.as_ref().map_or(0, |x| x.1);
+            let block_size = if image.length.is_multiple_of(IMAGE_ALIGNMENT)
+                && disk_offset.is_multiple_of(IMAGE_ALIGNMENT)
+            {
+                IMAGE_ALIGNMENT
+            } else {
+                512
+            };
+            let sha256: String = image.sha256.iter().map(|x| format!("{:02x}", x)).collect();
+            let simg = if image.simg { "simg_" } else { "" };
+            match &image.location {
+                Some((disk, disk_offset)) => script.push_str(&format!(
+                    "flash_{}disk {} {} {} {} {} {} {}\n",
+                    simg,
+                    image.name,
+                    disk,
+                    disk_offset,
+                    image.offset,
+                    image.length,
+                    block_size,
+                    sha256
+                )),
+                None => script.push_str(&format!(
+                    "flash_{}image {} {} {} {} {}\n",
+                    simg, image.name, image.offset, image.length, block_size, sha256
+                )),
+            }
+        }
+        script.push_str("ui_print \"Done\"\n");
+        script
+    }
+
+    /// write the installer and close the zip
+    pub fn finish(mut self, slot_name: &str) -> Result<(), RvabError> {
+        let script = self.updater_script(slot_name);
+        let offset = self.start_entry(UPDATER_SCRIPT_PATH, script.len() as u64)?;
+        self.out
+            .zip
+            .write_all(script.as_bytes())
+            .map_err(|_| "Error: write archive file failed")?;
+        let binary = format!(
+            "{}dd if=\"$ZIPFILE\" of=/tmp/rvab-updater-script bs=1 skip={} count={} 2>/dev/null \
+|| abort \"Error: extract updater-script failed\"\n. /tmp/rvab-updater-script\nexit 0\n",
+            UPDATE_BINARY_HEAD,
+            offset,
+            script.len()
+        );
+        self.start_entry(UPDATE_BINARY_PATH, binary.len() as u64)?;
+        self.out
+            .zip
+            .write_all(binary.as_bytes())
+            .map_err(|_| "Error: write archive file failed")?;
+        let file = self
+            .out
+            .zip
+            .finish()
+            .map_err(|_| "Error: write archive file failed")?;
+        file.sync_all()
+            .map_err(|_| "Error: write archive file failed")?;
+        Ok(())
+    }
+}
+
+impl ImageSink for FlashableZip {
+    fn begin_image(&mut self, entry: &BackupIndexEntry) -> Result<&mut dyn Write, RvabError> {
+        //firmware partitions are not moved by switching, the live location is the slot location
+        let location = if self.gpts.is_empty() {
+            None
+        } else {
+            let (driver, _, first_lba, _, sector_size) = get_part_accelerate_location(&entry.name)?;
+            Some((driver, first_lba * sector_size))
+        };
+        self.start_image(
+            &entry.name,
+            entry.length,
+            location
+                .as_ref()
+                .map(|(driver, offset)| (driver.as_str(), *offset)),
+        )?;
+        Ok(&mut self.out)
+    }
+    fn end_image(&mut self, entry: &BackupIndexEntry) -> Result<(), RvabError> {
+        self.end_image_data()?;
+        self.images.last_mut().unwrap().sha256 = entry.sha256;
+        Ok(())
+    }
+}
+
+/// device name of a disk in the archive (sda,mmcblk0), recovery looks it up in /dev/block
+fn disk_name(driver: &str) -> Result<String, RvabError> {
+    Path::new(driver)
+        .file_name()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_string())
+        .ok_or(RvabError::Backend("Error: invalid disk path"))
+}
+
+/// raw targets of the dyn partitions of the slot (except userdata) with (offset,length) in bytes
+fn locate_dyn_targets(slot: &Slot) -> Result<Vec<(&PartitionRawTarget, u64, u64)>, RvabError> {
+    let mut targets = Vec::new();
+    for target in slot
+        .dyn_partition_set
+        .values()
+        .filter(|x| x.part_name != USERDATA_NAME)
+    {
+        let sector_size = get_disk_sector_size(&target.driver)?;
+        targets.push((
+            target,
+            target.start_lba * sector_size,
+            (target.end_lba - target.start_lba + 1) * sector_size,
+        ));
+    }
+    Ok(targets)
+}
+
+/// archive every partition image of the slot (except userdata) into a flashable zip
+/// firmware comes from the live partitions if the slot is current, else from its backup store,
+/// dyn partitions always come from their raw targets
+/// with gpt the gpt tables of all disks of the slot are archived and restored first,
+/// live ones for the current slot, else the snapshot taken when it was switched away
+/// with simg images are written as android sparse images
+pub fn archive_slot(
+    metadata: &Metadata,
+    slot_name: &str,
+    output: &str,
+    gpt: bool,
+    simg: bool,
+) -> Result<(), RvabError> {
+    let slot = metadata
+        .slots
+        .get(slot_name)
+        .ok_or("Error: no such slot found")?;
+    let backup = BackupType::code2type(slot.backup_type_code)?;
+    let slot_gpt = match gpt {
+        false => None,
+        true if slot_name == metadata.current_slot => Some(SlotGpt::capture(slot)?),
+        true => match backup.read_gpt(metadata, slot_name)? {
+            Some(slot_gpt) => Some(slot_gpt),
+            None => {
+                eprintln!(
+                    "Error: no gpt backup of slot {}, archive without -g or switch to it once",
+                    slot_name
+                );
+                return Err(RvabError::Backend("Error: no gpt backup found"));
+            }
+        },
+    };
+    let mut archive = FlashableZip::create(output, simg)?;
+    if let Some(slot_gpt) = &slot_gpt {
+        println!(
+            "Archive gpt of {} disks of slot {}",
+            slot_gpt.disks.len(),
+            slot_name
+        );
+        archive.add_gpt(slot_gpt)?;
+    };
+    if slot_name == metadata.current_slot {
+        println!(
+            "Archive firmware of slot {} from live partitions",
+            slot_name
+        );
+        let parts = locate_firmware_parts(slot)?;
+        let pb = new_progress_bar(parts.iter().map(|x| x.length).sum());
+        for part in parts.iter() {
+            archive.add_partition(&part.name, &part.driver, part.offset, part.length, &pb)?;
+        }
+        pb.finish_with_message("firmware archived");
+    } else {
+        println!(
+            "Archive firmware of slot {} from its backup ({})",
+            slot_name, backup
+        );
+        if !backup.export(metadata, slot_name, &mut archive)? {
+            return Err(RvabError::Backend("Error: no firmware backup found"));
+        };
+    };
+    let dyn_parts = locate_dyn_targets(slot)?;
+    let pb = new_progress_bar(dyn_parts.iter().map(|x| x.2).sum());
+    for (target, offset, length) in dyn_parts {
+        archive.add_partition(&target.part_name, &target.driver, offset, length, &pb)?;
+    }
+    pb.finish_with_message("dyn partitions archived");
+    let total: u64 = archive.images.iter().map(|x| x.length).sum();
+    let count = archive.images.len();
+    archive.finish(slot_name)?;
+    println!(
+        "Archived {} partitions ({}) of slot {} into {}",
+        count,
+        bytes2ieee(total),
+        slot_name,
+        output
+    );
+    Ok(())
+}
+
+/// An image stored in an archive
+struct ZipImage {
+    name: String,
+    //offset of the image data in the zip
+    offset: u64,
+    //length of the (expanded) image
+    length: u64,
+    //Some(bytes in the zip) if stored as android sparse image
+    simg_length: Option<u64>,
+    //hex sha256 from the updater-script
+    sha256: String,
+}
+
+/// list the images of an archive made by `archive_slot`
+fn read_archive_images(path: &str) -> Result<Vec<ZipImage>, RvabError> {
+    let file = File::open(path).map_err(|_| "Error: open archive file failed")?;
+    let mut zip = ZipArchive::new(&file).map_err(|_| "Error: invalid archive file")?;
+    let mut script = String::new();
+    zip.by_name(UPDATER_SCRIPT_PATH)
+        .map_err(|_| "Error: not an rvab archive")?
+        .read_to_string(&mut script)
+        .map_err(|_| "Error: read archive file failed")?;
+    //image flash lines (all but flash_gpt) start with the partition and end with its sha256
+    let sums: HashMap<&str, &str> = script
+        .lines()
+        .filter(|x| x.starts_with("flash_") && !x.starts_with("flash_gpt "))
+        .filter_map(|x| {
+            let words: Vec<&str> = x.split_whitespace().collect();
+            Some((*words.get(1)?, *words.last()?))
+        })
+        .collect();
+    let mut images = Vec::new();
+    for i in 0..zip.len() {
+        let entry = zip
+            .by_index_raw(i)
+            .map_err(|_| "Error: invalid archive file")?;
+        if entry.name().starts_with("gpt/") {
+            println!("Warning: {} in archive is not imported", entry.name());
+            continue;
+        };
+        let name = match entry
+            .name()
+            .strip_prefix("images/")
+            .and_then(|x| x.strip_suffix(".img"))
+        {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        if entry.compression() != CompressionMethod::Stored {
+            eprintln!("Error: image {} is compressed in archive", name);
+            return Err(RvabError::Backend("Error: unsupported archive file"));
+        };
+        let sha256 = sums
+            .get(name.as_str())
+            .ok_or("Error: image missing in updater-script")?;
+        let offset = entry.data_start();
+        let mut head = [0; 4];
+        let simg_length = match file.read_exact_at(&mut head, offset) {
+            Ok(_) if is_simg(&head) => Some(entry.size()),
+            _ => None,
+        };
+        let length = match simg_length {
+            Some(simg_length) => {
+                SimgReader::new(SegmentReader::new(&file, offset, simg_length))?.length()
+            }
+            None => entry.size(),
+        };
+        images.push(ZipImage {
+            offset,
+            length,
+            simg_length,
+            sha256: sha256.to_string(),
+            name,
+        });
+    }
+    Ok(images)
+}
+
+/// read an image from the zip (and copy it to target if any), return its hex sha256
+fn copy_zip_image(
+    zfile: &File,
+    image: &ZipImage,
+    target: Option<&mut dyn Write>,
+    pb: &ProgressBar,
+) -> Result<String, RvabError> {
+    let mut reader: Box<dyn Read + '_> = match image.simg_length {
+        Some(simg_length) => Box::new(SimgReader::new(SegmentReader::new(
+            zfile,
+            image.offset,
+            simg_length,
+        ))?),
+        None => Box::new(SegmentReader::new(zfile, image.offset, image.length)),
+    };
+    let mut target = target;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; IMAGE_COPY_BUFFER_SIZE];
+    let mut done = 0;
+    while done < image.length {
+        let size = reader
+            .read(&mut buffer)
+            .map_err(|_| "Error: read archive file failed")?;
+        if size == 0 {
+            return Err(RvabError::Backend("Error: archive file truncated"));
+        };
+        if let Some(target) = &mut target {
+            target
+                .write_all(&buffer[..size])
+                .map_err(|_| "Error: write target disk failed")?;
+        };
+        hasher.update(&buffer[..size]);
+        done += size as u64;
+        pb.inc(size as u64);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect())
+}
+
+/// write an image of the zip to driver at offset
+fn flash_zip_image(
+    zfile: &File,
+    image: &ZipImage,
+    driver: &str,
+    offset: u64,
+    pb: &ProgressBar,
+) -> Result<(), RvabError> {
+    let tfile = OpenOptions::new()
+        .write(true)
+        .open(driver)
+        .map_err(|_| "Error: open target disk failed")?;
+    let mut writer = SegmentWriter::new(&tfile, offset, image.length);
+    if copy_zip_image(zfile, image, Some(&mut writer), pb)? != image.sha256 {
+        eprintln!(
+            "Terrible!!!: image {} changed in archive during import",
+            image.name
+        );
+        return Err(RvabError::integrity("archive image sha256 not match"));
+    };
+    tfile
+        .sync_all()
+        .map_err(|_| RvabError::Backend("Error: sync target disk failed"))
+}
+
+/// import an archive into the slot, its images must match the firmware and dyn partitions
+/// of the slot by name and size, all images are checked before anything is written
+/// firmware goes to the live partitions if the slot is current, else into its backup store,
+/// dyn partitions always go to their raw targets
+pub fn import_archive(metadata: &Metadata, slot_name: &str, input: &str) -> Result<(), RvabError> {
+    let slot = metadata
+        .slots
+        .get(slot_name)
+        .ok_or("Error: no such slot found")?;
+    let images = read_archive_images(input)?;
+    let firmware = locate_firmware_parts(slot)?;
+    let dyn_parts = locate_dyn_targets(slot)?;
+    let mut expected = BTreeMap::new();
+    for part in firmware.iter() {
+        expected.insert(part.name.as_str(), part.length);
+    }
+    for (target, _, length) in dyn_parts.iter() {
+        expected.insert(target.part_name.as_str(), *length);
+    }
+    let mut compatible = true;
+    for image in images.iter() {
+        match expected.get(image.name.as_str()) {
+            None => {
+                eprintln!(
+                    "Error: partition {} of archive is not in slot {}",
+                    image.name, slot_name
+                );
+                compatible = false;
+            }
+            Some(length) if *length != image.length => {
+                eprintln!(
+                    "Error: partition {} is {} bytes in archive but {} bytes in slot {}",
+                    image.name, image.length, length, slot_name
+                );
+                compatible = false;
+            }
+            _ => {}
+        }
+    }
+    for name in expected.keys() {
+        if !images.iter().any(|x| x.name == *name) {
+            eprintln!(
+                "Error: partition {} of slot {} is missing in archive",
+                name, slot_name
+            );
+            compatible = false;
+        };
+    }
+    if !compatible {
+        return Err(RvabError::Backend(
+            "Error: archive does not match slot layout",
+        ));
+    };
+
+    let zfile = File::open(input).map_err(|_| "Error: open archive file failed")?;
+    let image = |name: &str| images.iter().find(|x| x.name == name).unwrap();
+    println!("Check {} images of archive {}", images.len(), input);
+    let pb = new_progress_bar(images.iter().map(|x| x.length).sum());
+    for image in images.iter() {
+        if copy_zip_image(&zfile, image, None, &pb)? != image.sha256 {
+            eprintln!("Error: image {} in archive is broken", image.name);
+            return Err(RvabError::integrity("archive image sha256 not match"));
+        };
+    }
+    pb.finish_with_message("archive checked");
+
+    if slot_name == metadata.current_slot {
+        println!("Import firmware of slot {} to live partitions", slot_name);
+        let pb = new_progress_bar(firmware.iter().map(|x| x.length).sum());
+        for part in firmware.iter() {
+            flash_zip_image(&zfile, image(&part.name), &part.driver, part.offset, &pb)?;
+        }
+        pb.finish_with_message("firmware imported");
+    } else {
+        let backup = BackupType::code2type(slot.backup_type_code)?;
+        println!(
+            "Import firmware of slot {} into its backup ({})",
+            slot_name, backup
+        );
+        //the zip is the source disk, images are stored uncompressed (maybe sparse) in it
+        let parts: Vec<FirmwarePart> = firmware
+            .iter()
+            .map(|part| FirmwarePart {
+                driver: input.to_string(),
+                offset: image(&part.name).offset,
+                simg_length: image(&part.name).simg_length,
+                ..part.clone()
+            })
+            .collect();
+        backup.store(metadata, slot_name, &parts)?;
+    };
+    let pb = new_progress_bar(dyn_parts.iter().map(|x| x.2).sum());
+    for (target, offset, _) in dyn_parts.iter() {
+        flash_zip_image(
+            &zfile,
+            image(&target.part_name),
+            &target.driver,
+            *offset,
+            &pb,
+        )?;
+    }
+    pb.finish_with_message("dyn partitions imported");
+    println!(
+        "Imported {} partitions of {} into slot {}",
+        images.len(),
+        input,
+        slot_name
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    /// run `write_simg` of the installer on a file holding the sparse image at `zip_offset`,
+    /// expand it to `out` at `out_offset`
+    fn run_write_simg(zip: &Path, zip_offset: u64, out: &Path, out_offset: u64) -> bool {
+        let script = format!(
+            "{}write_simg test \"$4\" {} {}\n",
+            UPDATE_BINARY_HEAD, out_offset, zip_offset
+        );
+        Command::new("sh")
+            .args(["-c", &script, "sh", "3", "1"])
+            .arg(zip)
+            .arg(out)
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    #[test]
+    fn installer_expands_simg() {
+        let dir = std::env::temp_dir().join(format!("rvab-simg-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (zip, out) = (dir.join("zip"), dir.join("out"));
+        for block_size in [512, 4096] {
+            //zero group,data group and a tail shorter than a group
+            let length = 2 * 1024 * 1024 + 3 * block_size as usize;
+            let image: Vec<u8> = (0..length)
+                .map(|i| {
+                    if i < 1024 * 1024 {
+                        0
+                    } else {
+                        (i % 241) as u8 + 1
+                    }
+                })
+                .collect();
+            let mut encoder = SimgEncoder::new(length as u64, block_size).unwrap();
+            //data of the zip entry starts aligned like in an archive
+            let mut simg = vec![0x55; IMAGE_ALIGNMENT as usize];
+            simg.extend_from_slice(&encoder.head());
+            encoder.push(&image, &mut simg).unwrap();
+            encoder.finish(&mut simg).unwrap();
+            std::fs::write(&zip, &simg).unwrap();
+            //stale bytes must be overwritten by the zero fill
+            std::fs::write(&out, vec![0xFF; 512 + length]).unwrap();
+            assert!(run_write_simg(&zip, IMAGE_ALIGNMENT, &out, 512));
+            let written = std::fs::read(&out).unwrap();
+            assert_eq!(&written[..512], &[0xFF; 512][..]);
+            assert!(written[512..] == image[..]);
+            //anything else than a sparse image is refused
+            assert!(!run_write_simg(&zip, 0, &out, 0));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1 +1,110 @@
+use crate::backup_factory::{
+    get_metadata_slot, BackupRegion, BackupTrait, FirmwarePart, ImageSink, SlotGpt, VerifyReport,
+};
+///binary space backup module
+/// Firmware of a slot is stored directly in the raw,unpartitioned lba range
+/// backup_target_start..=backup_target_end on backup_target, no gpt entry is needed.
+/// The region starts with a self-describing backup index (slot name,region length,
+/// partition index table), see `BackupIndex`
+use crate::error::RvabError;
+use crate::gpt_helper::{get_userdata_driver, is_disk_segment_free};
+use crate::metadata::{Metadata, Slot};
+use std::fs::OpenOptions;
 
+pub struct BinarySpaceBackup;
+
+impl BinarySpaceBackup {
+    /// raw region of the slot, make sure no partition is placed on it
+    fn prepare_region(slot: &Slot) -> Result<BackupRegion, RvabError> {
+        if !is_disk_segment_free(
+            &slot.backup_target,
+            slot.backup_target_start,
+            slot.backup_target_end,
+        )? {
+            eprintln!(
+                "Error: backup target {} {}-{} is used by some partitions",
+                slot.backup_target, slot.backup_target_start, slot.backup_target_end
+            );
+            return Err(RvabError::Backend(
+                "Error: backup target is used by some partitions",
+            ));
+        };
+        BackupRegion::from_slot(slot)
+    }
+}
+
+impl BackupTrait for BinarySpaceBackup {
+    fn store(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        parts: &[FirmwarePart],
+    ) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = BinarySpaceBackup::prepare_region(slot)?;
+        region.store_slot(metadata, slot, parts)?;
+        Ok(())
+    }
+    fn restore(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = BinarySpaceBackup::prepare_region(slot)?;
+        if !region.restore_slot(slot)? {
+            println!(
+                "Warning: no firmware backup found for slot {}, keep current firmware",
+                slot_name
+            );
+        };
+        Ok(())
+    }
+    fn backup_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = BinarySpaceBackup::prepare_region(slot)?;
+        region.store_slot_gpt(slot, &SlotGpt::capture(slot)?)
+    }
+    fn restore_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        self.read_gpt(metadata, slot_name)?
+            .ok_or("Error: no gpt backup found")?
+            .write()
+    }
+    fn read_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<Option<SlotGpt>, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = BinarySpaceBackup::prepare_region(slot)?;
+        region.read_slot_gpt(slot)
+    }
+    fn verify(&self, metadata: &Metadata, slot_name: &str) -> Result<VerifyReport, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = BinarySpaceBackup::prepare_region(slot)?;
+        region
+            .verify_slot(slot)?
+            .ok_or(RvabError::Backend("Error: no firmware backup found"))
+    }
+    fn export(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        sink: &mut dyn ImageSink,
+    ) -> Result<bool, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = BinarySpaceBackup::prepare_region(slot)?;
+        region.export_slot(slot, sink)
+    }
+    /// check every backup target (or userdata driver) can be opened for read and write
+    fn test(&self, metadata: Option<&Metadata>) -> Result<(), RvabError> {
+        let targets: Vec<String> = match metadata {
+            Some(metadata) => metadata
+                .slots
+                .values()
+                .map(|slot| slot.backup_target.clone())
+                .collect(),
+            None => vec![get_userdata_driver()?],
+        };
+        for target in targets {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&target)
+                .map_err(|_| "Error: open backup target failed")?;
+        }
+        Ok(())
+    }
+}
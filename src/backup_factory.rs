@@ -1,26 +1,124 @@
-use crate::metadata::Metadata;
+use crate::backup_diskspace::BinarySpaceBackup;
+use crate::backup_ftp::FtpBackup;
+use crate::backup_losetup::LosetupBackup;
+use crate::backup_partition::PartitionBackup;
+use crate::chunk_store::ChunkStore;
+use crate::compress_helper::{BlockUnpacker, Compression, CompressionSetting};
+use crate::config_helper::BackupTargetAttr;
+use crate::constants::get_block_dev_dir;
+use crate::error::RvabError;
+use crate::gpt_helper::{
+    bytes2ieee, dump_raw_gpt, get_disk_sector_size, get_part_accelerate_location, get_part_info,
+    list_firmware_partitions, write_raw_gpt, RawGpt,
+};
+use crate::metadata::{Metadata, Slot};
+use crate::simg_helper::SimgReader;
+use crate::sparse_helper::{
+    decode_extents, measure_sparse_size, SparseExpander, SparseExtent, SparseReader,
+    SPARSE_EXTENT_LEN,
+};
+use crc32fast::Hasher;
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
 use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// magic of the backup container at the start of every backup region
+pub const BACKUP_INDEX_MAGIC: &[u8; 8] = b"RVABBKIX";
+/// container format version written by this build, readers accept every version up to it
+/// 1: raw images, 2: adds image encoding (sparse) to entries, 3: adds compression to entries,
+/// 4: the index is limited to the first half of the reserved area, the gpt snapshot takes the rest
+pub const BACKUP_FORMAT_VERSION: u32 = 4;
+/// bytes reserved for the backup index at the start of every backup region, images follow it
+/// the second half holds the gpt snapshot of the slot since version 4, see `SlotGpt`
+pub const BACKUP_INDEX_RESERVED: u64 = 1024 * 1024;
+const BACKUP_GPT_OFFSET: u64 = BACKUP_INDEX_RESERVED / 2;
+/// magic of a gpt snapshot of all disks used by a slot
+pub const SLOT_GPT_MAGIC: &[u8; 8] = b"RVABGPTS";
+pub const SLOT_GPT_VERSION: u32 = 1;
+/// gpt snapshot head: magic,version (u32),disk count (u32),crc32 (u32) of everything behind it,
+/// reserved (u32),slot name (64 bytes utf8,zero padded)
+/// per disk: driver (64 bytes utf8,zero padded),primary offset,primary length,backup offset,
+/// backup length (u64), followed by the raw gpt data of all disks
+const SLOT_GPT_HEAD_LEN: usize = 24 + BACKUP_INDEX_NAME_LEN;
+const SLOT_GPT_DISK_LEN: usize = BACKUP_INDEX_NAME_LEN + 32;
+/// images are aligned to this in the backup region
+const BACKUP_IMAGE_ALIGNMENT: u64 = 4096;
+/// index head: magic,format version (u32),entry count (u32),crc32 (u32) of everything behind it,
+/// reserved (u32),creation time (u64 unix seconds),source slot name (64 bytes utf8,zero padded),
+/// region length (u64)
+const BACKUP_INDEX_HEAD_LEN: usize = 32 + BACKUP_INDEX_NAME_LEN + 8;
+/// index entry: name (64 bytes utf8,zero padded),gpt type guid (16 bytes,rfc4122 order),
+/// gpt flags (u64),offset (u64),length (u64),sha256 (32 bytes) of the image,
+/// since version 2: encoding (u32),extent count (u32),stored length (u64)
+/// since version 3: compression (u32),reserved (u32),packed length (u64)
+const BACKUP_INDEX_NAME_LEN: usize = 64;
+const BACKUP_INDEX_ENTRY_LEN_V1: usize = BACKUP_INDEX_NAME_LEN + 16 + 24 + 32;
+const BACKUP_INDEX_ENTRY_LEN_V2: usize = BACKUP_INDEX_ENTRY_LEN_V1 + 16;
+const BACKUP_INDEX_ENTRY_LEN: usize = BACKUP_INDEX_ENTRY_LEN_V2 + 16;
+const BACKUP_COPY_BUFFER_SIZE: usize = 1024 * 1024;
 
 /// BackupTrait is a trait for backup and restore object between original disk segement and unknown target
 /// The target could be a file, a partition, a disk, a network, a cloud, etc.
 /// Inner implementation ways include partition,binary space disk segement,losetup partition)
+/// All methods take the slot name whose backup target (and firmware list) should be used
 pub trait BackupTrait {
-    fn backup(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str>;
-    fn restore(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str>;
-    //backup gpt table
-    fn backup_gpt(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str>;
-    //restore gpt table
-    fn restore_gpt(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str>;
-    //verify backup
-    fn verify(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str>;
-    fn test(&self, metadata: Option<&Metadata>) -> Result<(), &str>;
+    //make the backup target of the slot ready, done by install before any switch
+    fn prepare(&self, _metadata: &Metadata, _slot_name: &str) -> Result<(), RvabError> {
+        Ok(())
+    }
+    //store the given images as firmware backup of the slot, replaces the old one
+    fn store(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        parts: &[FirmwarePart],
+    ) -> Result<(), RvabError>;
+    //backup firmware of the slot (its live firmware partitions) into its backup target
+    fn backup(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        self.store(metadata, slot_name, &locate_firmware_parts(slot)?)
+    }
+    //restore firmware of the slot from its backup target
+    fn restore(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError>;
+    //backup gpt tables of every disk used by the slot into its backup target
+    fn backup_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError>;
+    //write the stored gpt tables of the slot back to their disks
+    fn restore_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError>;
+    //read the stored gpt tables of the slot, None if not stored
+    fn read_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<Option<SlotGpt>, RvabError>;
+    //verify backup, compare every stored image with its live partition
+    fn verify(&self, metadata: &Metadata, slot_name: &str) -> Result<VerifyReport, RvabError>;
+    //expand every stored image of the slot into sink, return false if nothing is stored
+    fn export(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        sink: &mut dyn ImageSink,
+    ) -> Result<bool, RvabError>;
+    fn test(&self, metadata: Option<&Metadata>) -> Result<(), RvabError>;
+}
+
+/// Receives the images of a backup expanded by `BackupTrait::export`
+pub trait ImageSink {
+    /// start an image, return the writer for its logical bytes
+    fn begin_image(&mut self, entry: &BackupIndexEntry) -> Result<&mut dyn Write, RvabError>;
+    /// the image is complete and its sha256 matches the index
+    fn end_image(&mut self, entry: &BackupIndexEntry) -> Result<(), RvabError>;
 }
 #[derive(Debug, Serialize, Deserialize, Copy, Clone)]
 pub enum BackupType {
     Partition,
     BinarySpace,
     Losetup,
+    Ftp,
 }
 impl fmt::Display for BackupType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -31,6 +129,7 @@ impl fmt::Display for BackupType {
                 BackupType::Partition => "partition",
                 BackupType::BinarySpace => "binaryspace",
                 BackupType::Losetup => "losetup",
+                BackupType::Ftp => "ftp",
             }
         )
     }
@@ -38,12 +137,13 @@ impl fmt::Display for BackupType {
 
 impl BackupType {
     /// Convert a code number to a BackupType,typically from a config file
-    pub fn code2type(code: i32) -> Result<BackupType, &'static str> {
+    pub fn code2type(code: i32) -> Result<BackupType, RvabError> {
         match code {
             0 => Ok(BackupType::Partition),
             1 => Ok(BackupType::Losetup),
             2 => Ok(BackupType::BinarySpace),
-            _ => Err("Invalid BackupType code"),
+            3 => Ok(BackupType::Ftp),
+            _ => Err(RvabError::Backend("Invalid BackupType code")),
         }
     }
     /// Convert a BackupType to a code number,typically for a config file
@@ -52,10 +152,12 @@ impl BackupType {
             BackupType::Partition => 0,
             BackupType::Losetup => 1,
             BackupType::BinarySpace => 2,
+            BackupType::Ftp => 3,
         }
     }
     /// Guess the backup target partition size (bytes)
-    /// over 10% of the firmware size
+    /// over 10% of the firmware size, pass the measured stored size (see
+    /// `measure_firmware_stored_size`) since images are stored sparse
     pub fn guess_backup_target_partition_size(&self, firmware_size_b: u64) -> u64 {
         match self {
             BackupType::Partition => {
@@ -70,6 +172,8 @@ impl BackupType {
                 let add_on = firmware_size_b / 10;
                 firmware_size_b + add_on
             }
+            //firmware is stored remotely, no device space needed
+            BackupType::Ftp => 0,
         }
     }
 
@@ -87,28 +191,1140 @@ impl BackupType {
     }
 }
 
+impl BackupType {
+    /// get the backend implementing this backup type
+    fn backend(&self) -> Box<dyn BackupTrait> {
+        match self {
+            BackupType::Partition => Box::new(PartitionBackup),
+            BackupType::BinarySpace => Box::new(BinarySpaceBackup),
+            BackupType::Losetup => Box::new(LosetupBackup),
+            BackupType::Ftp => Box::new(FtpBackup),
+        }
+    }
+}
+
 impl BackupTrait for BackupType {
-    fn backup(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str> {
-        Ok(())
+    fn prepare(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        self.backend().prepare(metadata, slot_name)
     }
-    fn restore(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str> {
-        Ok(())
+    fn store(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        parts: &[FirmwarePart],
+    ) -> Result<(), RvabError> {
+        self.backend().store(metadata, slot_name, parts)
     }
-    fn backup_gpt(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str> {
-        Ok(())
+    fn restore(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        self.backend().restore(metadata, slot_name)
+    }
+    fn backup_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        self.backend().backup_gpt(metadata, slot_name)
+    }
+    fn restore_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        self.backend().restore_gpt(metadata, slot_name)
     }
-    fn restore_gpt(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str> {
+    fn read_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<Option<SlotGpt>, RvabError> {
+        self.backend().read_gpt(metadata, slot_name)
+    }
+    fn verify(&self, metadata: &Metadata, slot_name: &str) -> Result<VerifyReport, RvabError> {
+        self.backend().verify(metadata, slot_name)
+    }
+    fn export(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        sink: &mut dyn ImageSink,
+    ) -> Result<bool, RvabError> {
+        self.backend().export(metadata, slot_name, sink)
+    }
+    fn test(&self, metadata: Option<&Metadata>) -> Result<(), RvabError> {
+        self.backend().test(metadata)
+    }
+}
+
+/// A firmware partition to be stored, located on its main driver (bytes)
+#[derive(Debug, Clone)]
+pub struct FirmwarePart {
+    pub name: String,
+    pub driver: String,
+    pub offset: u64,
+    pub length: u64,
+    pub type_guid: Uuid,
+    pub flags: u64,
+    //Some(bytes) if driver holds an android sparse image of the part at offset
+    pub simg_length: Option<u64>,
+}
+impl FirmwarePart {
+    /// reader over the (expanded) bytes of the part
+    pub fn open_source(&self) -> Result<Box<dyn Read>, RvabError> {
+        let mut file = File::open(&self.driver).map_err(|e| RvabError::io(&self.driver, e))?;
+        file.seek(SeekFrom::Start(self.offset))
+            .map_err(|e| RvabError::io(&self.driver, e))?;
+        match self.simg_length {
+            Some(simg_length) => {
+                let reader = SimgReader::new(file.take(simg_length))?;
+                if reader.length() != self.length {
+                    eprintln!("Error: sparse image of {} has a wrong size", self.name);
+                    return Err(RvabError::integrity("sparse image size not match"));
+                };
+                Ok(Box::new(reader))
+            }
+            None => Ok(Box::new(file.take(self.length))),
+        }
+    }
+}
+
+/// locate all firmware partitions of the slot (excluding its exclude list and dyn partitions)
+pub fn locate_firmware_parts(slot: &Slot) -> Result<Vec<FirmwarePart>, RvabError> {
+    let mut parts = Vec::new();
+    for (name, _) in list_firmware_partitions(&slot.backup_exclude_set())? {
+        let (driver, _, first_lba, last_lba, sector_size) = get_part_accelerate_location(&name)?;
+        let (type_guid, flags) = get_part_info(&name)?;
+        parts.push(FirmwarePart {
+            type_guid: Uuid::parse_str(&type_guid)
+                .map_err(|_| "Error: invalid partition type guid")?,
+            flags,
+            name,
+            driver,
+            offset: first_lba * sector_size,
+            length: (last_lba - first_lba + 1) * sector_size,
+            simg_length: None,
+        });
+    }
+    Ok(parts)
+}
+
+/// measure the bytes a backup of the firmware partitions occupies in a backup region
+/// (sparse and compressed images, extent tables, alignment and the reserved index),
+/// reads (and compresses) every partition
+pub fn measure_firmware_stored_size(
+    ex_back_list: &HashSet<String>,
+    compression: CompressionSetting,
+) -> Result<u64, RvabError> {
+    let mut total = BACKUP_INDEX_RESERVED;
+    for (name, _) in list_firmware_partitions(ex_back_list)? {
+        let (driver, _, first_lba, last_lba, sector_size) = get_part_accelerate_location(&name)?;
+        let file = File::open(&driver).map_err(|e| RvabError::io(&driver, e))?;
+        let stored = measure_sparse_size(
+            &file,
+            first_lba * sector_size,
+            (last_lba - first_lba + 1) * sector_size,
+            compression,
+        )?;
+        total += stored.div_ceil(BACKUP_IMAGE_ALIGNMENT) * BACKUP_IMAGE_ALIGNMENT;
+    }
+    println!("firmware stored size:{} , {}", total, bytes2ieee(total));
+    Ok(total)
+}
+
+/// true if the slot stores into a chunk store shared by all dedup slots on the same region
+pub fn slot_uses_chunk_store(slot: &Slot) -> Result<bool, RvabError> {
+    Ok(BackupTargetAttr::parse(&slot.backup_target_attr).get_bool("dedup", false)?)
+}
+
+/// get slot from metadata by name
+pub fn get_metadata_slot<'a>(
+    metadata: &'a Metadata,
+    slot_name: &str,
+) -> Result<&'a Slot, RvabError> {
+    metadata
+        .slots
+        .get(slot_name)
+        .ok_or(RvabError::Backend("Error: no such slot found"))
+}
+
+/// How an image is laid out in the container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageEncoding {
+    /// the full image
+    Raw,
+    /// non-zero blocks followed by the extent table, see `sparse_helper`
+    Sparse,
+    /// chunk id list in a chunk store manifest, see `chunk_store`
+    Chunked,
+}
+impl ImageEncoding {
+    pub fn code2encoding(code: u32) -> Result<ImageEncoding, RvabError> {
+        match code {
+            0 => Ok(ImageEncoding::Raw),
+            1 => Ok(ImageEncoding::Sparse),
+            2 => Ok(ImageEncoding::Chunked),
+            _ => Err(RvabError::Backend(
+                "Error: unknown image encoding in backup index",
+            )),
+        }
+    }
+    pub fn encoding2code(encoding: ImageEncoding) -> u32 {
+        match encoding {
+            ImageEncoding::Raw => 0,
+            ImageEncoding::Sparse => 1,
+            ImageEncoding::Chunked => 2,
+        }
+    }
+}
+
+/// One stored image in a backup container, offset is relative to the region start
+/// (or to the image file for backends storing one file per image)
+#[derive(Debug, Clone)]
+pub struct BackupIndexEntry {
+    pub name: String,
+    pub type_guid: Uuid,
+    pub flags: u64,
+    pub offset: u64,
+    //logical length of the image
+    pub length: u64,
+    //sha256 of the logical image
+    pub sha256: [u8; 32],
+    pub encoding: ImageEncoding,
+    pub extent_count: u32,
+    //sparse data length before compression
+    pub stored_length: u64,
+    pub compression: Compression,
+    //length of the (compressed) data in the container, the extent table follows it
+    pub packed_length: u64,
+}
+impl BackupIndexEntry {
+    /// entry for a firmware part, the rest is filled while storing
+    pub fn from_part(part: &FirmwarePart, offset: u64) -> Self {
+        BackupIndexEntry {
+            name: part.name.clone(),
+            type_guid: part.type_guid,
+            flags: part.flags,
+            offset,
+            length: part.length,
+            sha256: [0; 32],
+            encoding: ImageEncoding::Sparse,
+            extent_count: 0,
+            stored_length: 0,
+            compression: Compression::None,
+            packed_length: 0,
+        }
+    }
+
+    /// fill the result of a `SparseReader` which read the whole image
+    pub fn set_sparse_result(&mut self, reader: SparseReader, compression: Compression) {
+        self.compression = compression;
+        self.packed_length = reader.packed_length();
+        let (extents, stored_length, sha256) = reader.finish();
+        self.encoding = ImageEncoding::Sparse;
+        self.extent_count = extents.len() as u32;
+        self.stored_length = stored_length;
+        self.sha256 = sha256;
+    }
+
+    /// bytes of the extent table behind the stored data
+    pub fn table_length(&self) -> u64 {
+        match self.encoding {
+            ImageEncoding::Raw | ImageEncoding::Chunked => 0,
+            ImageEncoding::Sparse => self.extent_count as u64 * SPARSE_EXTENT_LEN as u64,
+        }
+    }
+
+    /// bytes occupied in the container
+    pub fn total_stored_length(&self) -> u64 {
+        self.packed_length + self.table_length()
+    }
+
+    /// writer taking the packed data of the image and passing the sparse data to expander
+    pub fn unpacker<'a>(&self, expander: SparseExpander<'a>) -> BlockUnpacker<SparseExpander<'a>> {
+        BlockUnpacker::new(expander, self.compression, self.packed_length)
+    }
+
+    /// extents of the image, table is the extent table read behind the stored data
+    pub fn extents(&self, table: &[u8]) -> Result<Vec<SparseExtent>, RvabError> {
+        match self.encoding {
+            ImageEncoding::Raw => Ok(vec![SparseExtent {
+                offset: 0,
+                length: self.length,
+            }]),
+            ImageEncoding::Sparse => Ok(decode_extents(table, self.length, self.stored_length)?),
+            ImageEncoding::Chunked => Err(RvabError::Backend(
+                "Error: chunked image can only be read by chunk store",
+            )),
+        }
+    }
+}
+
+/// Backup container header and index table, shared by all backends
+/// so that restore,verify and external tools can read a backup without knowing its writer
+#[derive(Debug, Clone, Default)]
+pub struct BackupIndex {
+    pub version: u32,
+    //unix seconds
+    pub created: u64,
+    //slot the images belong to
+    pub slot_name: String,
+    //length of the whole region, bytes
+    pub region_length: u64,
+    pub entries: Vec<BackupIndexEntry>,
+}
+impl BackupIndex {
+    pub fn new(slot_name: &str, region_length: u64) -> Self {
+        BackupIndex {
+            version: BACKUP_FORMAT_VERSION,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_secs())
+                .unwrap_or_default(),
+            slot_name: slot_name.to_string(),
+            region_length,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RvabError> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&[0; 4]);
+        payload.extend_from_slice(&self.created.to_le_bytes());
+        payload.extend_from_slice(&encode_index_name(&self.slot_name)?);
+        payload.extend_from_slice(&self.region_length.to_le_bytes());
+        for entry in self.entries.iter() {
+            payload.extend_from_slice(&encode_index_name(&entry.name)?);
+            payload.extend_from_slice(entry.type_guid.as_bytes());
+            payload.extend_from_slice(&entry.flags.to_le_bytes());
+            payload.extend_from_slice(&entry.offset.to_le_bytes());
+            payload.extend_from_slice(&entry.length.to_le_bytes());
+            payload.extend_from_slice(&entry.sha256);
+            payload.extend_from_slice(&ImageEncoding::encoding2code(entry.encoding).to_le_bytes());
+            payload.extend_from_slice(&entry.extent_count.to_le_bytes());
+            payload.extend_from_slice(&entry.stored_length.to_le_bytes());
+            payload
+                .extend_from_slice(&Compression::compression2code(entry.compression).to_le_bytes());
+            payload.extend_from_slice(&[0; 4]);
+            payload.extend_from_slice(&entry.packed_length.to_le_bytes());
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(BACKUP_INDEX_MAGIC);
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&hasher.finalize().to_le_bytes());
+        buffer.extend_from_slice(&payload);
+        if buffer.len() as u64 > BACKUP_GPT_OFFSET {
+            return Err(RvabError::Backend("Error: backup index overflow"));
+        };
+        Ok(buffer)
+    }
+
+    /// parse index, return None if there is no index (magic not match)
+    pub fn from_bytes(buffer: &[u8]) -> Result<Option<Self>, RvabError> {
+        if buffer.len() < BACKUP_INDEX_HEAD_LEN || &buffer[..8] != BACKUP_INDEX_MAGIC {
+            return Ok(None);
+        };
+        let version = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        if version == 0 || version > BACKUP_FORMAT_VERSION {
+            eprintln!(
+                "Error: backup format version {} , this build supports up to {}",
+                version, BACKUP_FORMAT_VERSION
+            );
+            return Err(RvabError::Backend(
+                "Error: unsupported backup format version",
+            ));
+        };
+        let count = u32::from_le_bytes(buffer[12..16].try_into().unwrap()) as usize;
+        let crc32 = u32::from_le_bytes(buffer[16..20].try_into().unwrap());
+        let entry_len = match version {
+            1 => BACKUP_INDEX_ENTRY_LEN_V1,
+            2 => BACKUP_INDEX_ENTRY_LEN_V2,
+            _ => BACKUP_INDEX_ENTRY_LEN,
+        };
+        let end = BACKUP_INDEX_HEAD_LEN + count * entry_len;
+        if end > buffer.len() {
+            return Err(RvabError::Backend("Error: backup index overflow"));
+        };
+        let mut hasher = Hasher::new();
+        hasher.update(&buffer[20..end]);
+        if hasher.finalize() != crc32 {
+            return Err(RvabError::integrity("backup index crc32 not match"));
+        };
+        let head = &buffer[24..BACKUP_INDEX_HEAD_LEN];
+        let mut index = BackupIndex {
+            version,
+            created: u64::from_le_bytes(head[..8].try_into().unwrap()),
+            slot_name: decode_index_name(&head[8..8 + BACKUP_INDEX_NAME_LEN])?,
+            region_length: u64::from_le_bytes(
+                head[8 + BACKUP_INDEX_NAME_LEN..].try_into().unwrap(),
+            ),
+            entries: Vec::new(),
+        };
+        for raw in buffer[BACKUP_INDEX_HEAD_LEN..end].chunks(entry_len) {
+            let name = decode_index_name(&raw[..BACKUP_INDEX_NAME_LEN])?;
+            let raw = &raw[BACKUP_INDEX_NAME_LEN..];
+            let length = u64::from_le_bytes(raw[32..40].try_into().unwrap());
+            let mut entry = BackupIndexEntry {
+                name,
+                type_guid: Uuid::from_bytes(raw[0..16].try_into().unwrap()),
+                flags: u64::from_le_bytes(raw[16..24].try_into().unwrap()),
+                offset: u64::from_le_bytes(raw[24..32].try_into().unwrap()),
+                length,
+                sha256: raw[40..72].try_into().unwrap(),
+                encoding: ImageEncoding::Raw,
+                extent_count: 0,
+                stored_length: length,
+                compression: Compression::None,
+                packed_length: length,
+            };
+            if version >= 2 {
+                entry.encoding = ImageEncoding::code2encoding(u32::from_le_bytes(
+                    raw[72..76].try_into().unwrap(),
+                ))?;
+                entry.extent_count = u32::from_le_bytes(raw[76..80].try_into().unwrap());
+                entry.stored_length = u64::from_le_bytes(raw[80..88].try_into().unwrap());
+                entry.packed_length = entry.stored_length;
+            };
+            if version >= 3 {
+                entry.compression = Compression::code2compression(u32::from_le_bytes(
+                    raw[88..92].try_into().unwrap(),
+                ))?;
+                entry.packed_length = u64::from_le_bytes(raw[96..104].try_into().unwrap());
+            };
+            index.entries.push(entry);
+        }
+        Ok(Some(index))
+    }
+}
+
+/// Result of comparing a stored image with the live partition of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyStatus {
+    Match,
+    Mismatch,
+    /// live firmware partition without stored image
+    NotStored,
+    /// stored image without live partition
+    NoLivePartition,
+    SizeChanged,
+}
+impl fmt::Display for VerifyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                VerifyStatus::Match => "match",
+                VerifyStatus::Mismatch => "mismatch",
+                VerifyStatus::NotStored => "not stored",
+                VerifyStatus::NoLivePartition => "no live partition",
+                VerifyStatus::SizeChanged => "size changed",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyEntry {
+    pub name: String,
+    pub status: VerifyStatus,
+    //None if there is no stored image
+    pub stored_length: Option<u64>,
+    //None if there is no live partition
+    pub live_length: Option<u64>,
+}
+
+/// Per partition verify result of a slot backup
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub slot_name: String,
+    pub entries: Vec<VerifyEntry>,
+}
+impl VerifyReport {
+    /// true if every stored image matches its live partition
+    pub fn is_match(&self) -> bool {
+        self.entries.iter().all(|x| x.status == VerifyStatus::Match)
+    }
+
+    /// compare every image of the index with the live partition of the same name,
+    /// hash_stored returns sha256 of a stored image
+    pub fn from_index(
+        slot: &Slot,
+        index: &BackupIndex,
+        hash_stored: &mut dyn FnMut(&BackupIndexEntry) -> Result<[u8; 32], RvabError>,
+    ) -> Result<Self, RvabError> {
+        if index.slot_name != slot.slot_name {
+            return Err(RvabError::Backend(
+                "Error: backup does not belong to this slot",
+            ));
+        };
+        let mut report = VerifyReport {
+            slot_name: slot.slot_name.clone(),
+            entries: Vec::new(),
+        };
+        for entry in index.entries.iter() {
+            println!("Verifying partition {}", entry.name);
+            let mut result = VerifyEntry {
+                name: entry.name.clone(),
+                status: VerifyStatus::NoLivePartition,
+                stored_length: Some(entry.length),
+                live_length: None,
+            };
+            if !Path::new(&format!("{}{}", get_block_dev_dir()?, entry.name)).exists() {
+                report.entries.push(result);
+                continue;
+            };
+            let (driver, _, first_lba, last_lba, sector_size) =
+                get_part_accelerate_location(&entry.name)?;
+            let live_length = (last_lba - first_lba + 1) * sector_size;
+            result.live_length = Some(live_length);
+            if live_length != entry.length {
+                result.status = VerifyStatus::SizeChanged;
+                report.entries.push(result);
+                continue;
+            };
+            let stored = hash_stored(entry)?;
+            if stored != entry.sha256 {
+                println!(
+                    "Warning: stored image of partition {} is broken",
+                    entry.name
+                );
+            };
+            let file = File::open(&driver).map_err(|e| RvabError::io(&driver, e))?;
+            let live = sha256_segment(&file, first_lba * sector_size, live_length)?;
+            result.status = if stored == entry.sha256 && live == stored {
+                VerifyStatus::Match
+            } else {
+                VerifyStatus::Mismatch
+            };
+            report.entries.push(result);
+        }
+        //live firmware partitions never stored
+        for (name, _) in list_firmware_partitions(&slot.backup_exclude_set())? {
+            if index.entries.iter().any(|x| x.name == name) {
+                continue;
+            };
+            let (_, _, first_lba, last_lba, sector_size) = get_part_accelerate_location(&name)?;
+            report.entries.push(VerifyEntry {
+                name,
+                status: VerifyStatus::NotStored,
+                stored_length: None,
+                live_length: Some((last_lba - first_lba + 1) * sector_size),
+            });
+        }
+        Ok(report)
+    }
+}
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Verify report of slot {}", self.slot_name)?;
+        for entry in self.entries.iter() {
+            writeln!(
+                f,
+                "\t{:<24} {:<14} stored: {} live: {}",
+                entry.name,
+                entry.status.to_string(),
+                entry
+                    .stored_length
+                    .map_or("-".to_string(), |x| x.to_string()),
+                entry.live_length.map_or("-".to_string(), |x| x.to_string())
+            )?;
+        }
         Ok(())
     }
-    fn verify(&self, metadata: &Metadata, orig_file_path: &str) -> Result<(), &str> {
+}
+
+/// Raw gpt tables of every disk used by a slot
+#[derive(Debug, Clone)]
+pub struct SlotGpt {
+    pub slot_name: String,
+    //(driver,raw gpt)
+    pub disks: Vec<(String, RawGpt)>,
+}
+impl SlotGpt {
+    /// disks holding firmware,dyn partitions or the backup target of the slot
+    pub fn slot_disks(slot: &Slot) -> Result<Vec<String>, RvabError> {
+        let mut disks = BTreeSet::new();
+        for part in locate_firmware_parts(slot)? {
+            disks.insert(part.driver);
+        }
+        for target in slot.dyn_partition_set.values() {
+            disks.insert(target.driver.clone());
+        }
+        if !matches!(
+            BackupType::code2type(slot.backup_type_code)?,
+            BackupType::Ftp
+        ) {
+            disks.insert(slot.backup_target.clone());
+        };
+        Ok(disks.into_iter().collect())
+    }
+
+    /// dump the live gpt of every disk used by the slot
+    pub fn capture(slot: &Slot) -> Result<Self, RvabError> {
+        let mut disks = Vec::new();
+        for driver in SlotGpt::slot_disks(slot)? {
+            let raw_gpt = dump_raw_gpt(&driver)?;
+            disks.push((driver, raw_gpt));
+        }
+        Ok(SlotGpt {
+            slot_name: slot.slot_name.clone(),
+            disks,
+        })
+    }
+
+    /// write every gpt back to its disk
+    pub fn write(&self) -> Result<(), RvabError> {
+        for (driver, raw_gpt) in self.disks.iter() {
+            println!("Restore gpt of disk {}", driver);
+            write_raw_gpt(driver, raw_gpt)?;
+        }
         Ok(())
     }
-    fn test(&self, metadata: Option<&Metadata>) -> Result<(), &str> {
-        match self {
-            BackupType::Partition => {}
-            BackupType::BinarySpace => {}
-            BackupType::Losetup => {}
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, RvabError> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&[0; 4]);
+        payload.extend_from_slice(&encode_index_name(&self.slot_name)?);
+        for (driver, raw_gpt) in self.disks.iter() {
+            payload.extend_from_slice(&encode_index_name(driver)?);
+            payload.extend_from_slice(&raw_gpt.primary_offset.to_le_bytes());
+            payload.extend_from_slice(&(raw_gpt.primary.len() as u64).to_le_bytes());
+            payload.extend_from_slice(&raw_gpt.backup_offset.to_le_bytes());
+            payload.extend_from_slice(&(raw_gpt.backup.len() as u64).to_le_bytes());
         }
+        for (_, raw_gpt) in self.disks.iter() {
+            payload.extend_from_slice(&raw_gpt.primary);
+            payload.extend_from_slice(&raw_gpt.backup);
+        }
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(SLOT_GPT_MAGIC);
+        buffer.extend_from_slice(&SLOT_GPT_VERSION.to_le_bytes());
+        buffer.extend_from_slice(&(self.disks.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&hasher.finalize().to_le_bytes());
+        buffer.extend_from_slice(&payload);
+        Ok(buffer)
+    }
+
+    /// parse gpt snapshot, return None if there is none (magic not match)
+    pub fn from_bytes(buffer: &[u8]) -> Result<Option<Self>, RvabError> {
+        if buffer.len() < SLOT_GPT_HEAD_LEN || &buffer[..8] != SLOT_GPT_MAGIC {
+            return Ok(None);
+        };
+        let version = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
+        if version == 0 || version > SLOT_GPT_VERSION {
+            return Err(RvabError::Backend("Error: unsupported gpt backup version"));
+        };
+        let count = u32::from_le_bytes(buffer[12..16].try_into().unwrap()) as usize;
+        let crc32 = u32::from_le_bytes(buffer[16..20].try_into().unwrap());
+        let mut end = SLOT_GPT_HEAD_LEN + count * SLOT_GPT_DISK_LEN;
+        if end > buffer.len() {
+            return Err(RvabError::Backend("Error: gpt backup truncated"));
+        };
+        let mut layout = Vec::new();
+        for raw in buffer[SLOT_GPT_HEAD_LEN..end].chunks(SLOT_GPT_DISK_LEN) {
+            let driver = decode_index_name(&raw[..BACKUP_INDEX_NAME_LEN])?;
+            let raw = &raw[BACKUP_INDEX_NAME_LEN..];
+            let field = |i: usize| u64::from_le_bytes(raw[i * 8..i * 8 + 8].try_into().unwrap());
+            layout.push((driver, field(0), field(1), field(2), field(3)));
+            //lengths come from disk, a corrupted snapshot must not overflow
+            end = field(1)
+                .checked_add(field(3))
+                .and_then(|x| usize::try_from(x).ok())
+                .and_then(|x| x.checked_add(end))
+                .ok_or(RvabError::integrity("gpt backup lengths overflow"))?;
+        }
+        if end > buffer.len() {
+            return Err(RvabError::Backend("Error: gpt backup truncated"));
+        };
+        let mut hasher = Hasher::new();
+        hasher.update(&buffer[20..end]);
+        if hasher.finalize() != crc32 {
+            return Err(RvabError::integrity("gpt backup crc32 not match"));
+        };
+        let mut pointer = SLOT_GPT_HEAD_LEN + count * SLOT_GPT_DISK_LEN;
+        let mut disks = Vec::new();
+        for (driver, primary_offset, primary_length, backup_offset, backup_length) in layout {
+            let primary = buffer[pointer..pointer + primary_length as usize].to_vec();
+            pointer += primary_length as usize;
+            let backup = buffer[pointer..pointer + backup_length as usize].to_vec();
+            pointer += backup_length as usize;
+            disks.push((
+                driver,
+                RawGpt {
+                    primary_offset,
+                    primary,
+                    backup_offset,
+                    backup,
+                },
+            ));
+        }
+        Ok(Some(SlotGpt {
+            slot_name: decode_index_name(&buffer[24..SLOT_GPT_HEAD_LEN])?,
+            disks,
+        }))
+    }
+}
+
+/// name field of backup index, utf8 zero padded
+fn encode_index_name(name: &str) -> Result<[u8; BACKUP_INDEX_NAME_LEN], RvabError> {
+    let name = name.as_bytes();
+    if name.len() > BACKUP_INDEX_NAME_LEN {
+        return Err(RvabError::Backend("Error: name too long for backup index"));
+    };
+    let mut buffer = [0; BACKUP_INDEX_NAME_LEN];
+    buffer[..name.len()].copy_from_slice(name);
+    Ok(buffer)
+}
+
+fn decode_index_name(buffer: &[u8]) -> Result<String, RvabError> {
+    let length = buffer.iter().position(|&x| x == 0).unwrap_or(buffer.len());
+    let name = std::str::from_utf8(&buffer[..length])
+        .map_err(|_| "Error: invalid name in backup index")?;
+    Ok(name.to_string())
+}
+
+/// A continuous byte range of a disk or file holding a backup index followed by images
+#[derive(Debug, Clone)]
+pub struct BackupRegion {
+    pub path: String,
+    pub offset: u64,
+    pub length: u64,
+}
+impl BackupRegion {
+    /// raw lba range backup_target_start..=backup_target_end of the slot
+    pub fn from_slot(slot: &Slot) -> Result<Self, RvabError> {
+        if slot.backup_target_end < slot.backup_target_start {
+            return Err(RvabError::Backend("Error: invalid backup target range"));
+        };
+        let sector_size = get_disk_sector_size(&slot.backup_target)?;
+        Ok(BackupRegion {
+            path: slot.backup_target.clone(),
+            offset: slot.backup_target_start * sector_size,
+            length: (slot.backup_target_end - slot.backup_target_start + 1) * sector_size,
+        })
+    }
+
+    /// store firmware of the slot, into the shared chunk store if the slot enables dedup
+    pub fn store_slot(
+        &self,
+        metadata: &Metadata,
+        slot: &Slot,
+        parts: &[FirmwarePart],
+    ) -> Result<(), RvabError> {
+        let compression = CompressionSetting::from_attr(&slot.backup_target_attr)?;
+        if slot_uses_chunk_store(slot)? {
+            if compression.compression != Compression::None {
+                return Err(RvabError::Backend(
+                    "Error: chunk store keeps chunks uncompressed, remove compression from dedup slot",
+                ));
+            };
+            ChunkStore::open(self)?.store_firmware(metadata, &slot.slot_name, parts)?;
+        } else {
+            self.store_firmware(&slot.slot_name, parts, compression)?;
+        };
         Ok(())
     }
+
+    /// restore firmware of the slot, return false if nothing is stored for it
+    pub fn restore_slot(&self, slot: &Slot) -> Result<bool, RvabError> {
+        if slot_uses_chunk_store(slot)? {
+            ChunkStore::open(self)?.restore_firmware(&slot.slot_name)
+        } else {
+            self.restore_firmware(&slot.slot_name)
+        }
+    }
+
+    /// verify firmware of the slot, return None if nothing is stored for it
+    pub fn verify_slot(&self, slot: &Slot) -> Result<Option<VerifyReport>, RvabError> {
+        if slot_uses_chunk_store(slot)? {
+            ChunkStore::open(self)?.verify_firmware(slot)
+        } else {
+            self.verify_firmware(slot)
+        }
+    }
+
+    /// export firmware of the slot, return false if nothing is stored for it
+    pub fn export_slot(&self, slot: &Slot, sink: &mut dyn ImageSink) -> Result<bool, RvabError> {
+        if slot_uses_chunk_store(slot)? {
+            ChunkStore::open(self)?.export_firmware(&slot.slot_name, sink)
+        } else {
+            self.export_firmware(&slot.slot_name, sink)
+        }
+    }
+
+    /// store the gpt snapshot of the slot, into the shared chunk store if the slot enables dedup
+    pub fn store_slot_gpt(&self, slot: &Slot, gpt: &SlotGpt) -> Result<(), RvabError> {
+        if slot_uses_chunk_store(slot)? {
+            return ChunkStore::open(self)?.store_gpt(&slot.slot_name, gpt);
+        };
+        let buffer = gpt.to_bytes()?;
+        if buffer.len() as u64 > BACKUP_INDEX_RESERVED - BACKUP_GPT_OFFSET {
+            return Err(RvabError::Backend("Error: gpt backup overflow"));
+        };
+        let tfile = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .map_err(|e| RvabError::io(&self.path, e))?;
+        tfile
+            .write_all_at(&buffer, self.offset + BACKUP_GPT_OFFSET)
+            .map_err(|e| RvabError::io(&self.path, e))?;
+        tfile
+            .sync_all()
+            .map_err(|_| RvabError::Backend("Error: sync backup target failed"))
+    }
+
+    /// read the gpt snapshot of the slot, None if nothing stored
+    pub fn read_slot_gpt(&self, slot: &Slot) -> Result<Option<SlotGpt>, RvabError> {
+        let gpt = if slot_uses_chunk_store(slot)? {
+            ChunkStore::open(self)?.read_gpt(&slot.slot_name)?
+        } else {
+            let file = File::open(&self.path).map_err(|e| RvabError::io(&self.path, e))?;
+            let mut buffer = vec![0; (BACKUP_INDEX_RESERVED - BACKUP_GPT_OFFSET) as usize];
+            file.read_exact_at(&mut buffer, self.offset + BACKUP_GPT_OFFSET)
+                .map_err(|e| RvabError::io(&self.path, e))?;
+            SlotGpt::from_bytes(&buffer)?
+        };
+        match gpt {
+            Some(gpt) if gpt.slot_name != slot.slot_name => Err(RvabError::Backend(
+                "Error: gpt backup does not belong to this slot",
+            )),
+            gpt => Ok(gpt),
+        }
+    }
+
+    /// read index of the region, None if nothing stored yet
+    pub fn read_index(&self) -> Result<Option<BackupIndex>, RvabError> {
+        let file = File::open(&self.path).map_err(|e| RvabError::io(&self.path, e))?;
+        let mut buffer = vec![0; BACKUP_INDEX_RESERVED.min(self.length) as usize];
+        file.read_exact_at(&mut buffer, self.offset)
+            .map_err(|e| RvabError::io(&self.path, e))?;
+        BackupIndex::from_bytes(&buffer)
+    }
+
+    /// store all firmware parts of the slot into region (sparse,compressed if set),
+    /// index is written at last
+    pub fn store_firmware(
+        &self,
+        slot_name: &str,
+        parts: &[FirmwarePart],
+        compression: CompressionSetting,
+    ) -> Result<BackupIndex, RvabError> {
+        let mut index = BackupIndex::new(slot_name, self.length);
+        let tfile = OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .map_err(|e| RvabError::io(&self.path, e))?;
+        // invalidate old index first, a half written backup must never be trusted
+        tfile
+            .write_all_at(&[0; BACKUP_INDEX_HEAD_LEN], self.offset)
+            .map_err(|e| RvabError::io(&self.path, e))?;
+
+        let pb = new_progress_bar(parts.iter().map(|x| x.length).sum());
+        let mut pointer = BACKUP_INDEX_RESERVED;
+        for part in parts {
+            if pointer > self.length {
+                return Err(RvabError::Backend(
+                    "Error: firmware size is larger than backup target size",
+                ));
+            };
+            let mut reader = SparseReader::new(part.open_source()?, part.length, Some(pb.clone()))
+                .compress(compression);
+            let mut writer =
+                SegmentWriter::new(&tfile, self.offset + pointer, self.length - pointer);
+            std::io::copy(&mut reader, &mut writer)
+                .map_err(|_| "Error: firmware size is larger than backup target size")?;
+            let mut entry = BackupIndexEntry::from_part(part, pointer);
+            entry.set_sparse_result(reader, compression.compression);
+            pointer += entry.total_stored_length().div_ceil(BACKUP_IMAGE_ALIGNMENT)
+                * BACKUP_IMAGE_ALIGNMENT;
+            index.entries.push(entry);
+        }
+        tfile
+            .write_all_at(&index.to_bytes()?, self.offset)
+            .map_err(|e| RvabError::io(&self.path, e))?;
+        tfile.sync_all().map_err(|e| RvabError::io(&self.path, e))?;
+        pb.finish_with_message("backup finished");
+        let stored: u64 = index.entries.iter().map(|x| x.packed_length).sum();
+        println!(
+            "Stored {} of {} firmware",
+            bytes2ieee(stored),
+            bytes2ieee(index.entries.iter().map(|x| x.length).sum())
+        );
+        Ok(index)
+    }
+
+    /// read extents of a stored image
+    fn read_extents(
+        &self,
+        file: &File,
+        entry: &BackupIndexEntry,
+    ) -> Result<Vec<SparseExtent>, RvabError> {
+        if entry.offset + entry.total_stored_length() > self.length {
+            return Err(RvabError::Backend(
+                "Error: backup image overflow backup region",
+            ));
+        };
+        let mut table = vec![0; entry.table_length() as usize];
+        file.read_exact_at(&mut table, self.offset + entry.offset + entry.packed_length)
+            .map_err(|e| RvabError::io(&self.path, e))?;
+        entry.extents(&table)
+    }
+
+    /// expand a stored image to target if any, return sha256 of the image
+    fn expand_image(
+        &self,
+        file: &File,
+        entry: &BackupIndexEntry,
+        target: Option<&mut dyn Write>,
+        pb: Option<ProgressBar>,
+    ) -> Result<[u8; 32], RvabError> {
+        let extents = self.read_extents(file, entry)?;
+        let mut reader = SegmentReader::new(file, self.offset + entry.offset, entry.packed_length);
+        let mut unpacker = entry.unpacker(SparseExpander::new(target, entry.length, extents, pb));
+        std::io::copy(&mut reader, &mut unpacker).map_err(|_| "Error: expand backup failed")?;
+        Ok(unpacker.finish()?.finish()?)
+    }
+
+    /// restore all stored images of the slot to the live partitions with the same name
+    /// all images are checked before anything is written
+    /// return false if nothing is stored in the region
+    pub fn restore_firmware(&self, slot_name: &str) -> Result<bool, RvabError> {
+        let index = match self.read_index()? {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        if index.slot_name != slot_name || index.region_length != self.length {
+            eprintln!(
+                "Error: backup region holds slot {} ({} bytes), expect slot {} ({} bytes)",
+                index.slot_name, index.region_length, slot_name, self.length
+            );
+            return Err(RvabError::Backend(
+                "Error: backup region does not belong to this slot",
+            ));
+        };
+        let sfile = File::open(&self.path).map_err(|e| RvabError::io(&self.path, e))?;
+        let mut targets = Vec::new();
+        let mut total_size = 0;
+        for entry in index.entries.iter() {
+            let (driver, _, first_lba, last_lba, sector_size) =
+                get_part_accelerate_location(&entry.name)?;
+            if (last_lba - first_lba + 1) * sector_size != entry.length {
+                eprintln!("Error: size of partition {} changed", entry.name);
+                return Err(RvabError::Backend("Error: firmware partition size changed"));
+            };
+            warn_part_type_changed(entry);
+            if self.expand_image(&sfile, entry, None, None)? != entry.sha256 {
+                eprintln!("Error: backup of partition {} is broken", entry.name);
+                return Err(RvabError::integrity("backup image sha256 not match"));
+            };
+            total_size += entry.length;
+            targets.push((driver, first_lba * sector_size));
+        }
+        let pb = new_progress_bar(total_size);
+        for (entry, (driver, offset)) in index.entries.iter().zip(targets) {
+            let tfile = OpenOptions::new()
+                .write(true)
+                .open(&driver)
+                .map_err(|e| RvabError::io(&driver, e))?;
+            let mut writer = SegmentWriter::new(&tfile, offset, entry.length);
+            if self.expand_image(&sfile, entry, Some(&mut writer), Some(pb.clone()))?
+                != entry.sha256
+            {
+                eprintln!(
+                    "Terrible!!!: partition {} changed in backup region during restore",
+                    entry.name
+                );
+                return Err(RvabError::integrity("backup image sha256 not match"));
+            };
+            tfile.sync_all().map_err(|e| RvabError::io(&driver, e))?;
+        }
+        pb.finish_with_message("restore finished");
+        Ok(true)
+    }
+
+    /// expand all stored images of the slot into sink
+    /// return false if nothing is stored in the region
+    pub fn export_firmware(
+        &self,
+        slot_name: &str,
+        sink: &mut dyn ImageSink,
+    ) -> Result<bool, RvabError> {
+        let index = match self.read_index()? {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        if index.slot_name != slot_name || index.region_length != self.length {
+            return Err(RvabError::Backend(
+                "Error: backup region does not belong to this slot",
+            ));
+        };
+        let sfile = File::open(&self.path).map_err(|e| RvabError::io(&self.path, e))?;
+        let pb = new_progress_bar(index.entries.iter().map(|x| x.length).sum());
+        for entry in index.entries.iter() {
+            let writer = sink.begin_image(entry)?;
+            if self.expand_image(&sfile, entry, Some(writer), Some(pb.clone()))? != entry.sha256 {
+                eprintln!("Error: backup of partition {} is broken", entry.name);
+                return Err(RvabError::integrity("backup image sha256 not match"));
+            };
+            sink.end_image(entry)?;
+        }
+        pb.finish_with_message("export finished");
+        Ok(true)
+    }
+
+    /// verify stored images of the slot against live partitions
+    /// return None if nothing is stored in the region
+    pub fn verify_firmware(&self, slot: &Slot) -> Result<Option<VerifyReport>, RvabError> {
+        let index = match self.read_index()? {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        if index.region_length != self.length {
+            return Err(RvabError::Backend(
+                "Error: backup region does not belong to this slot",
+            ));
+        };
+        let sfile = File::open(&self.path).map_err(|e| RvabError::io(&self.path, e))?;
+        let report = VerifyReport::from_index(slot, &index, &mut |entry| {
+            self.expand_image(&sfile, entry, None, None)
+        })?;
+        Ok(Some(report))
+    }
+}
+
+/// warn if the live partition no longer has the type guid recorded in the backup
+pub fn warn_part_type_changed(entry: &BackupIndexEntry) {
+    if let Ok((type_guid, _)) = get_part_info(&entry.name) {
+        if Uuid::parse_str(&type_guid).ok() != Some(entry.type_guid) {
+            println!(
+                "Warning: type of partition {} changed from {} to {}",
+                entry.name, entry.type_guid, type_guid
+            );
+        };
+    };
+}
+
+/// progress bar for firmware copy
+pub fn new_progress_bar(total_size: u64) -> ProgressBar {
+    let pb = ProgressBar::new(total_size);
+    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-"));
+    pb
+}
+
+/// Reader over a segment of a file (bytes)
+pub struct SegmentReader<'a> {
+    file: &'a File,
+    offset: u64,
+    remain: u64,
+}
+
+impl<'a> SegmentReader<'a> {
+    pub fn new(file: &'a File, offset: u64, length: u64) -> Self {
+        SegmentReader {
+            file,
+            offset,
+            remain: length,
+        }
+    }
+}
+
+impl Read for SegmentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = (buf.len() as u64).min(self.remain) as usize;
+        if size == 0 {
+            return Ok(0);
+        };
+        let size = self.file.read_at(&mut buf[..size], self.offset)?;
+        self.offset += size as u64;
+        self.remain -= size as u64;
+        Ok(size)
+    }
+}
+
+/// Writer over a segment of a file (bytes), refuses to write past the end
+pub struct SegmentWriter<'a> {
+    file: &'a File,
+    offset: u64,
+    remain: u64,
+}
+
+impl<'a> SegmentWriter<'a> {
+    pub fn new(file: &'a File, offset: u64, length: u64) -> Self {
+        SegmentWriter {
+            file,
+            offset,
+            remain: length,
+        }
+    }
+}
+
+impl Write for SegmentWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() as u64 > self.remain {
+            return Err(std::io::Error::other("segment overflow"));
+        };
+        self.file.write_all_at(buf, self.offset)?;
+        self.offset += buf.len() as u64;
+        self.remain -= buf.len() as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// sha256 of a file segment (bytes)
+pub fn sha256_segment(file: &File, offset: u64, length: u64) -> Result<[u8; 32], RvabError> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BACKUP_COPY_BUFFER_SIZE];
+    let mut done = 0;
+    while done < length {
+        let size = (length - done).min(BACKUP_COPY_BUFFER_SIZE as u64) as usize;
+        file.read_exact_at(&mut buffer[..size], offset + done)
+            .map_err(|_| "Error: read backup failed")?;
+        hasher.update(&buffer[..size]);
+        done += size as u64;
+    }
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_gpt() -> SlotGpt {
+        SlotGpt {
+            slot_name: "a".to_string(),
+            disks: vec![(
+                "/dev/block/sda".to_string(),
+                RawGpt {
+                    primary_offset: 0,
+                    primary: vec![1; 1024],
+                    backup_offset: 4096,
+                    backup: vec![2; 512],
+                },
+            )],
+        }
+    }
+
+    #[test]
+    fn slot_gpt_round_trip() {
+        let bytes = sample_gpt().to_bytes().unwrap();
+        let gpt = SlotGpt::from_bytes(&bytes).unwrap().unwrap();
+        assert_eq!(gpt.slot_name, "a");
+        assert_eq!(gpt.disks.len(), 1);
+        assert_eq!(gpt.disks[0].1.primary, vec![1; 1024]);
+        assert_eq!(gpt.disks[0].1.backup_offset, 4096);
+        assert_eq!(gpt.disks[0].1.backup, vec![2; 512]);
+        assert!(SlotGpt::from_bytes(&[0; 64]).unwrap().is_none());
+        assert!(SlotGpt::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn slot_gpt_length_overflow() {
+        let mut bytes = sample_gpt().to_bytes().unwrap();
+        // primary and backup lengths of the first disk, their sum wraps to 1
+        let field = SLOT_GPT_HEAD_LEN + BACKUP_INDEX_NAME_LEN;
+        bytes[field + 8..field + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        bytes[field + 24..field + 32].copy_from_slice(&2u64.to_le_bytes());
+        assert!(matches!(
+            SlotGpt::from_bytes(&bytes),
+            Err(RvabError::Integrity(_))
+        ));
+    }
 }
@@ -1 +1,248 @@
+use crate::backup_factory::{
+    get_metadata_slot, BackupRegion, BackupTrait, FirmwarePart, ImageSink, SlotGpt, VerifyReport,
+};
+use crate::device_env::device_env;
+///losetup backup module
+/// Firmware of a slot is stored inside a loop device attached to the backup region
+/// (backup_target_start..=backup_target_end on backup_target) or to a whole image file
+/// if backup_target is a regular file.
+/// The loop device holds a backup index followed by the firmware images, see `BackupRegion`
+use crate::error::RvabError;
+use crate::gpt_helper::is_disk_segment_free;
+use crate::metadata::{Metadata, Slot};
+use nix::errno::Errno;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::Duration;
 
+const LOOP_CONTROL_PATH: &str = "/dev/loop-control";
+/// android puts loop nodes under /dev/block, desktop linux under /dev
+const LOOP_DEV_PREFIXES: [&str; 2] = ["/dev/block/loop", "/dev/loop"];
+/// wait for ueventd to create a new loop node
+const LOOP_DEV_WAIT_RETRY: u32 = 20;
+
+// linux/loop.h
+const LOOP_SET_FD: u32 = 0x4C00;
+const LOOP_CLR_FD: u32 = 0x4C01;
+const LOOP_SET_STATUS64: u32 = 0x4C04;
+const LOOP_CONFIGURE: u32 = 0x4C0A;
+const LOOP_CTL_GET_FREE: u32 = 0x4C82;
+const LO_NAME_SIZE: usize = 64;
+const LO_KEY_SIZE: usize = 32;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; LO_KEY_SIZE],
+    lo_init: [u64; 2],
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8],
+}
+
+nix::ioctl_none_bad!(loop_ctl_get_free, LOOP_CTL_GET_FREE);
+nix::ioctl_write_int_bad!(loop_set_fd, LOOP_SET_FD);
+nix::ioctl_none_bad!(loop_clr_fd, LOOP_CLR_FD);
+nix::ioctl_write_ptr_bad!(loop_set_status64, LOOP_SET_STATUS64, LoopInfo64);
+nix::ioctl_write_ptr_bad!(loop_configure, LOOP_CONFIGURE, LoopConfig);
+
+/// An attached loop device, detached on drop
+pub struct LoopDevice {
+    path: String,
+    file: File,
+}
+
+impl LoopDevice {
+    /// attach a segment (bytes) of backing file to a free loop device
+    /// sizelimit 0 means up to the end of backing file
+    pub fn attach(backing: &str, offset: u64, sizelimit: u64) -> Result<Self, RvabError> {
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(LOOP_CONTROL_PATH)
+            .map_err(|_| "Error: open loop control failed")?;
+        let number = unsafe { loop_ctl_get_free(control.as_raw_fd()) }
+            .map_err(|_| "Error: get free loop device failed")?;
+        let (path, file) = LoopDevice::open_node(number)?;
+        let backing_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(backing)
+            .map_err(|_| "Error: open loop backing file failed")?;
+
+        let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+        info.lo_offset = offset;
+        info.lo_sizelimit = sizelimit;
+        let name = backing.as_bytes();
+        let name_len = name.len().min(LO_NAME_SIZE - 1);
+        info.lo_file_name[..name_len].copy_from_slice(&name[..name_len]);
+        let config = LoopConfig {
+            fd: backing_file.as_raw_fd() as u32,
+            block_size: 0,
+            info,
+            reserved: [0; 8],
+        };
+        match unsafe { loop_configure(file.as_raw_fd(), &config) } {
+            Ok(_) => {}
+            // LOOP_CONFIGURE is only available since linux 5.8
+            Err(Errno::EINVAL) | Err(Errno::ENOTTY) => {
+                unsafe { loop_set_fd(file.as_raw_fd(), backing_file.as_raw_fd()) }
+                    .map_err(|_| "Error: set loop fd failed")?;
+                if unsafe { loop_set_status64(file.as_raw_fd(), &config.info) }.is_err() {
+                    let _ = unsafe { loop_clr_fd(file.as_raw_fd()) };
+                    return Err(RvabError::Backend("Error: set loop status failed"));
+                };
+            }
+            Err(_) => return Err(RvabError::Backend("Error: configure loop device failed")),
+        }
+        println!("Attached {} to {}", backing, path);
+        Ok(LoopDevice { path, file })
+    }
+
+    fn open_node(number: i32) -> Result<(String, File), RvabError> {
+        for _ in 0..LOOP_DEV_WAIT_RETRY {
+            for prefix in LOOP_DEV_PREFIXES {
+                let path = format!("{}{}", prefix, number);
+                if let Ok(file) = OpenOptions::new().read(true).write(true).open(&path) {
+                    return Ok((path, file));
+                };
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Err(RvabError::Backend("Error: loop device node not found"))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = self.file.sync_all();
+        if unsafe { loop_clr_fd(self.file.as_raw_fd()) }.is_err() {
+            eprintln!("Warning: detach loop device {} failed", self.path);
+        };
+    }
+}
+
+pub struct LosetupBackup;
+
+impl LosetupBackup {
+    /// attach backup target of the slot to a loop device, return (loop device,region on it)
+    fn prepare_region(slot: &Slot) -> Result<(LoopDevice, BackupRegion), RvabError> {
+        let target_meta =
+            fs::metadata(&slot.backup_target).map_err(|_| "Error: backup target not found")?;
+        //disks of an image dir env are regular files too
+        let is_image_file = target_meta.is_file()
+            && device_env()
+                .logical_block_size(&slot.backup_target)
+                .is_err();
+        let (offset, length) = if is_image_file {
+            (0, target_meta.len())
+        } else {
+            if !is_disk_segment_free(
+                &slot.backup_target,
+                slot.backup_target_start,
+                slot.backup_target_end,
+            )? {
+                return Err(RvabError::Backend(
+                    "Error: backup target is used by some partitions",
+                ));
+            };
+            let region = BackupRegion::from_slot(slot)?;
+            (region.offset, region.length)
+        };
+        let loop_device = LoopDevice::attach(&slot.backup_target, offset, length)?;
+        let region = BackupRegion {
+            path: loop_device.path().to_string(),
+            offset: 0,
+            length,
+        };
+        Ok((loop_device, region))
+    }
+}
+
+impl BackupTrait for LosetupBackup {
+    fn store(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        parts: &[FirmwarePart],
+    ) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let (_loop_device, region) = LosetupBackup::prepare_region(slot)?;
+        region.store_slot(metadata, slot, parts)?;
+        Ok(())
+    }
+    fn restore(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let (_loop_device, region) = LosetupBackup::prepare_region(slot)?;
+        if !region.restore_slot(slot)? {
+            println!(
+                "Warning: no firmware backup found for slot {}, keep current firmware",
+                slot_name
+            );
+        };
+        Ok(())
+    }
+    fn backup_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let (_loop_device, region) = LosetupBackup::prepare_region(slot)?;
+        region.store_slot_gpt(slot, &SlotGpt::capture(slot)?)
+    }
+    fn restore_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        self.read_gpt(metadata, slot_name)?
+            .ok_or("Error: no gpt backup found")?
+            .write()
+    }
+    fn read_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<Option<SlotGpt>, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let (_loop_device, region) = LosetupBackup::prepare_region(slot)?;
+        region.read_slot_gpt(slot)
+    }
+    fn verify(&self, metadata: &Metadata, slot_name: &str) -> Result<VerifyReport, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let (_loop_device, region) = LosetupBackup::prepare_region(slot)?;
+        region
+            .verify_slot(slot)?
+            .ok_or(RvabError::Backend("Error: no firmware backup found"))
+    }
+    fn export(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        sink: &mut dyn ImageSink,
+    ) -> Result<bool, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let (_loop_device, region) = LosetupBackup::prepare_region(slot)?;
+        region.export_slot(slot, sink)
+    }
+    /// check /dev/loop-control is usable and hands out a free loop device
+    fn test(&self, _metadata: Option<&Metadata>) -> Result<(), RvabError> {
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(LOOP_CONTROL_PATH)
+            .map_err(|_| "Error: open loop control failed")?;
+        unsafe { loop_ctl_get_free(control.as_raw_fd()) }
+            .map_err(|_| "Error: get free loop device failed")?;
+        Ok(())
+    }
+}
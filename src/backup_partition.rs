@@ -1 +1,193 @@
+use crate::backup_factory::{
+    get_metadata_slot, slot_uses_chunk_store, BackupRegion, BackupTrait, BackupType, FirmwarePart,
+    ImageSink, SlotGpt, VerifyReport,
+};
+use crate::constants::{BACKUP_PARTITION_PREFIX, CHUNK_STORE_PARTITION_NAME};
+///partition backup module
+/// Firmware of a slot is stored in a dedicated gpt partition named `rvab_backup_<slot>`
+/// (or the shared `rvab_chunk_store` if the slot enables dedup),
+/// spanning backup_target_start..=backup_target_end on backup_target.
+/// The partition holds a backup index followed by the firmware images, see `BackupRegion`
+use crate::error::RvabError;
+use crate::gpt_helper::{get_gpt_disk, get_userdata_driver, new_partition, try_get_disk_lba};
+use crate::metadata::{Metadata, Slot};
+use gpt::{partition_types, GptConfig};
+use std::collections::HashSet;
 
+pub struct PartitionBackup;
+
+impl PartitionBackup {
+    /// name of the backup partition of a slot, dedup slots share the chunk store partition
+    pub fn partition_name(slot: &Slot) -> Result<String, RvabError> {
+        if slot_uses_chunk_store(slot)? {
+            return Ok(CHUNK_STORE_PARTITION_NAME.to_string());
+        };
+        Ok(format!("{}{}", BACKUP_PARTITION_PREFIX, slot.slot_name))
+    }
+
+    /// find the backup partition of the slot and create it if allowed
+    /// return None if it does not exist and create is false
+    fn prepare_region(slot: &Slot, create: bool) -> Result<Option<BackupRegion>, RvabError> {
+        let name = PartitionBackup::partition_name(slot)?;
+        let gptcfg = GptConfig::new()
+            .writable(create)
+            .logical_block_size(try_get_disk_lba(&slot.backup_target)?);
+        let mut disk = gptcfg
+            .open(&slot.backup_target)
+            .map_err(|_| "Error: open backup target failed")?;
+        let exist = disk
+            .partitions()
+            .values()
+            .find(|part| part.name == name)
+            .map(|part| (part.first_lba, part.last_lba));
+        match exist {
+            Some((first_lba, last_lba)) => {
+                if first_lba != slot.backup_target_start || last_lba != slot.backup_target_end {
+                    eprintln!(
+                        "Error: partition {} is {}-{} but backup target is {}-{}",
+                        name, first_lba, last_lba, slot.backup_target_start, slot.backup_target_end
+                    );
+                    return Err(RvabError::Backend(
+                        "Error: backup partition mismatch backup target",
+                    ));
+                };
+            }
+            None => {
+                if !create {
+                    return Ok(None);
+                };
+                let fits = disk.find_free_sectors().iter().any(|(start, length)| {
+                    slot.backup_target_start >= *start && slot.backup_target_end < *start + *length
+                });
+                if !fits {
+                    return Err(RvabError::Backend(
+                        "Error: backup target is used by some partitions",
+                    ));
+                };
+                let id = disk
+                    .find_next_partition_id()
+                    .ok_or("Error: no free gpt entry for backup partition")?;
+                new_partition(
+                    &mut disk,
+                    &name,
+                    id,
+                    slot.backup_target_start,
+                    slot.backup_target_end - slot.backup_target_start + 1,
+                    partition_types::BASIC,
+                    0,
+                )
+                .map_err(|_| "Error: create backup partition failed")?;
+                disk.write()
+                    .map_err(|_| "Error: write backup target gpt failed")?;
+                println!("Created backup partition {}", name);
+            }
+        }
+        Ok(Some(BackupRegion::from_slot(slot)?))
+    }
+}
+
+impl BackupTrait for PartitionBackup {
+    /// the partition is created here and not on the first switch,
+    /// a switch rolling back its gpt tables would drop a partition made during it
+    fn prepare(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        PartitionBackup::prepare_region(slot, true)?;
+        Ok(())
+    }
+    fn store(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        parts: &[FirmwarePart],
+    ) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = PartitionBackup::prepare_region(slot, false)?.ok_or(RvabError::Backend(
+            "Error: backup partition not found, run install -u first",
+        ))?;
+        region.store_slot(metadata, slot, parts)?;
+        Ok(())
+    }
+    fn restore(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        //install creates the partition, without it the backup of the slot is lost
+        let region = PartitionBackup::prepare_region(slot, false)?.ok_or(RvabError::Backend(
+            "Error: backup partition of slot not found",
+        ))?;
+        if !region.restore_slot(slot)? {
+            println!(
+                "Warning: no firmware backup found for slot {}, keep current firmware",
+                slot_name
+            );
+        };
+        Ok(())
+    }
+    fn backup_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = PartitionBackup::prepare_region(slot, false)?
+            .ok_or(RvabError::Backend("Error: no firmware backup found"))?;
+        region.store_slot_gpt(slot, &SlotGpt::capture(slot)?)
+    }
+    fn restore_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<(), RvabError> {
+        self.read_gpt(metadata, slot_name)?
+            .ok_or("Error: no gpt backup found")?
+            .write()
+    }
+    fn read_gpt(&self, metadata: &Metadata, slot_name: &str) -> Result<Option<SlotGpt>, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        match PartitionBackup::prepare_region(slot, false)? {
+            Some(region) => region.read_slot_gpt(slot),
+            None => Ok(None),
+        }
+    }
+    fn verify(&self, metadata: &Metadata, slot_name: &str) -> Result<VerifyReport, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        let region = PartitionBackup::prepare_region(slot, false)?
+            .ok_or(RvabError::Backend("Error: no firmware backup found"))?;
+        region
+            .verify_slot(slot)?
+            .ok_or(RvabError::Backend("Error: no firmware backup found"))
+    }
+    fn export(
+        &self,
+        metadata: &Metadata,
+        slot_name: &str,
+        sink: &mut dyn ImageSink,
+    ) -> Result<bool, RvabError> {
+        let slot = get_metadata_slot(metadata, slot_name)?;
+        match PartitionBackup::prepare_region(slot, false)? {
+            Some(region) => region.export_slot(slot, sink),
+            None => Ok(false),
+        }
+    }
+    /// check there is a backup partition or a free gpt entry on every backup target
+    fn test(&self, metadata: Option<&Metadata>) -> Result<(), RvabError> {
+        let mut targets = HashSet::new();
+        match metadata {
+            Some(metadata) => {
+                for slot in metadata.slots.values() {
+                    if let Ok(BackupType::Partition) = BackupType::code2type(slot.backup_type_code)
+                    {
+                        targets.insert((
+                            slot.backup_target.clone(),
+                            PartitionBackup::partition_name(slot)?,
+                        ));
+                    };
+                }
+            }
+            None => {
+                targets.insert((get_userdata_driver()?, String::new()));
+            }
+        }
+        for (target, name) in targets {
+            let disk = get_gpt_disk(&target, false)?;
+            if disk.find_next_partition_id().is_none()
+                && !disk.partitions().values().any(|part| part.name == name)
+            {
+                return Err(RvabError::Backend(
+                    "Error: no free gpt entry for backup partition",
+                ));
+            };
+        }
+        Ok(())
+    }
+}
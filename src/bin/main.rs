@@ -1,13 +1,27 @@
 use argh::FromArgs;
 use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use librvab_cli_r::bootctrl::{
+    android_slot_from_number, android_slot_number, change_active_android_slot,
+    get_android_slot_attr, get_android_slot_count, get_current_android_slot,
+    mark_android_boot_successful, set_android_slot_unbootable,
+};
+use librvab_cli_r::device_env::{set_device_env, ImageDirEnv};
+use librvab_cli_r::error::RvabError;
+use librvab_cli_r::report::{
+    CheckReport, JsonCheck, JsonCurrent, JsonDocument, JsonError, JsonListing, JsonSlot, JsonVerify,
+};
 use librvab_cli_r::{
-    check_slots_config, dump_current_metadata, generate_template_init_config_file, list_slots,
-    show_current_slot, try_init_partition_table_layout, try_init_userdata_partition,
-    update_config_to_all_slots,
+    archive_slot, check_slots_config, dump_current_metadata, generate_template_init_config_file,
+    get_current_slot, import_archive, list_slots, recover_interrupted_switch, switch_to_slot,
+    try_init_partition_table_layout, try_init_userdata_partition, update_config_to_all_slots,
+    verify_slot_backup,
 };
+use nix::unistd::{dup, dup2};
 use rand::Rng;
+use serde::Serialize;
 use std::cmp::min;
 use std::fmt::Write;
+use std::os::unix::io::FromRawFd;
 use std::thread::current;
 use std::time::Duration;
 use std::{fs, thread};
@@ -20,6 +34,10 @@ struct CmdProg {
     #[argh(switch, short = 's')]
     /// silent mode, allow all dangerous actions
     silent: bool,
+    #[argh(option, short = 'e')]
+    /// run against a dir of raw gpt disk images described by its env.toml, not this device,
+    /// its block/by-name dir is rebuilt from the gpt tables on every run like after a reboot
+    env: Option<String>,
     #[argh(subcommand)]
     /// subcommand
     mode: Mode,
@@ -31,9 +49,13 @@ enum Mode {
     Init(InitMode),
     Install(InstallMode),
     Switch(SwitchMode),
+    Recover(RecoverMode),
+    Verify(VerifyMode),
     List(ListMode),
     Current(Current),
     Archive(ArchiveMode),
+    Import(ImportMode),
+    Bootctl(BootctlMode),
     Test(TestMode),
 }
 
@@ -47,7 +69,8 @@ Use -f <config> to init userdata partition then. \
 Use -full <config> to init and sync(clone) all dyn partitions except userdata",
     example = "rvab init -f <config> ",
     example = "rvab init -f <config> --slot a",
-    example = "rvab init -full <config> "
+    example = "rvab init -full <config> ",
+    example = "rvab init -c <config> --json"
 )]
 /// set necessary gpt layout
 struct InitMode {
@@ -69,9 +92,18 @@ struct InitMode {
     /// dyn partitions list (userdata auto included) , will be added to exclude list automatically
     #[argh(option)]
     dynpt: Option<String>,
+    /// compression written into the template and used to size the backup target (zstd:<level>,lz4,none)
+    #[argh(option)]
+    compression: Option<String>,
+    /// let both slots of the template share one deduplicating backup region
+    #[argh(switch)]
+    dedup: bool,
     /// check and test config file without modify disk
     #[argh(option, short = 'c')]
     check: Option<String>,
+    /// print the check result as a json document
+    #[argh(switch)]
+    json: bool,
 }
 
 #[derive(FromArgs)]
@@ -97,9 +129,18 @@ struct InstallMode {
     /// dual partitions list (userdata auto included) , will be added to exclude list automatically
     #[argh(option)]
     dynpt: Option<String>,
+    /// compression written into the template and used to size the backup target (zstd:<level>,lz4,none)
+    #[argh(option)]
+    compression: Option<String>,
+    /// let both slots of the template share one deduplicating backup region
+    #[argh(switch)]
+    dedup: bool,
     /// check and test config file without modify disk
     #[argh(option, short = 'c')]
     check: Option<String>,
+    /// print the check result as a json document
+    #[argh(switch)]
+    json: bool,
     /// dump current metadata to file
     #[argh(option, short = 'd')]
     dump: Option<String>,
@@ -115,7 +156,49 @@ struct SwitchMode {
 }
 
 #[derive(FromArgs)]
-#[argh(subcommand, name = "list", example = "rvab list [slot]")]
+#[argh(
+    subcommand,
+    name = "recover",
+    description = "finish or roll back a switch interrupted by power loss or crash, \
+using the switch journal stored in metadata area. \
+Use -c <config> (e.g. dumped by install -d) if userdata partition can not be found",
+    example = "rvab recover",
+    example = "rvab recover -c <config>"
+)]
+/// recover an interrupted switch
+struct RecoverMode {
+    /// config file used to locate metadata areas
+    #[argh(option, short = 'c')]
+    config: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "verify",
+    description = "hash every stored firmware image of the slot (default current slot) \
+and compare it with the live partition of the same name",
+    example = "rvab verify",
+    example = "rvab verify [slot]",
+    example = "rvab verify --json"
+)]
+/// verify firmware backup against live partitions
+struct VerifyMode {
+    /// target slot
+    #[argh(positional)]
+    slot: Option<String>,
+    /// print the report as a json document
+    #[argh(switch)]
+    json: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "list",
+    example = "rvab list [slot]",
+    example = "rvab list --json"
+)]
 /// list all slots and metadata
 struct ListMode {
     /// target slot
@@ -124,6 +207,9 @@ struct ListMode {
     /// only show current slot name
     #[argh(switch, short = 'n')]
     name: bool,
+    /// print metadata and slots as a json document
+    #[argh(switch)]
+    json: bool,
 }
 
 #[derive(FromArgs)]
@@ -133,6 +219,9 @@ struct Current {
     /// only show current slot name
     #[argh(switch, short = 'n')]
     name: bool,
+    /// print the current slot as a json document
+    #[argh(switch)]
+    json: bool,
 }
 
 #[derive(FromArgs)]
@@ -148,6 +237,240 @@ struct ArchiveMode {
     /// enable backup full gpt table
     #[argh(switch, short = 'g')]
     gpt: bool,
+    /// write images as android sparse images
+    #[argh(switch, short = 's')]
+    sparse: bool,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+/// import a zip file made by archive into certain slot
+struct ImportMode {
+    /// archive file
+    #[argh(positional)]
+    input: String,
+    /// import slot name
+    #[argh(option, short = 's')]
+    slot: String,
+}
+
+#[derive(FromArgs)]
+#[argh(
+    subcommand,
+    name = "bootctl",
+    example = "rvab bootctl get-current-slot",
+    example = "rvab bootctl set-active-boot-slot 1"
+)]
+/// android bootctl compatible commands, output and exit codes follow AOSP bootctl
+struct BootctlMode {
+    #[argh(subcommand)]
+    /// bootctl command
+    command: BootctlCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum BootctlCommand {
+    GetNumberSlots(GetNumberSlots),
+    GetCurrentSlot(GetCurrentSlot),
+    MarkBootSuccessful(MarkBootSuccessful),
+    SetActiveBootSlot(SetActiveBootSlot),
+    SetSlotAsUnbootable(SetSlotAsUnbootable),
+    IsSlotBootable(IsSlotBootable),
+    IsSlotMarkedSuccessful(IsSlotMarkedSuccessful),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-number-slots")]
+/// prints number of slots
+struct GetNumberSlots {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-current-slot")]
+/// prints currently running SLOT
+struct GetCurrentSlot {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "mark-boot-successful")]
+/// mark current slot as GOOD
+struct MarkBootSuccessful {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "set-active-boot-slot")]
+/// set SLOT as the active slot
+struct SetActiveBootSlot {
+    /// slot number, 0 or 1
+    #[argh(positional)]
+    slot: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "set-slot-as-unbootable")]
+/// mark SLOT as invalid
+struct SetSlotAsUnbootable {
+    /// slot number, 0 or 1
+    #[argh(positional)]
+    slot: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "is-slot-bootable")]
+/// returns 0 only if SLOT is bootable
+struct IsSlotBootable {
+    /// slot number, 0 or 1
+    #[argh(positional)]
+    slot: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "is-slot-marked-successful")]
+/// returns 0 only if SLOT is marked GOOD
+struct IsSlotMarkedSuccessful {
+    /// slot number, 0 or 1
+    #[argh(positional)]
+    slot: String,
+}
+
+// exit codes of AOSP bootctl, from sysexits.h
+const EX_OK: i32 = 0;
+const EX_USAGE: i32 = 64;
+const EX_SOFTWARE: i32 = 70;
+
+/// move stdout to stderr so that progress and banners of a command do not break its json document,
+/// return the original stdout for the document
+fn take_json_stdout() -> fs::File {
+    let fd = dup(1)
+        .and_then(|fd| dup2(2, 1).map(|_| fd))
+        .unwrap_or_else(|e| {
+            eprintln!("Error: redirect stdout failed {}", e);
+            std::process::exit(1);
+        });
+    unsafe { fs::File::from_raw_fd(fd) }
+}
+
+/// write the json document of a command result, exit 1 with an error document if it failed
+fn print_json<T: Serialize>(
+    out: &mut fs::File,
+    kind: &'static str,
+    ret: Result<T, RvabError>,
+) -> ! {
+    std::process::exit(write_json(out, kind, ret));
+}
+
+/// write the json document of a command result, return the exit code:
+/// 1 with an error document if it failed
+fn write_json<T: Serialize>(
+    out: &mut fs::File,
+    kind: &'static str,
+    ret: Result<T, RvabError>,
+) -> i32 {
+    let (doc, code) = match ret {
+        Ok(data) => (JsonDocument::new(kind, data).to_json(), 0),
+        Err(e) => (
+            JsonDocument::new(
+                "error",
+                JsonError {
+                    message: e.to_string(),
+                },
+            )
+            .to_json(),
+            1,
+        ),
+    };
+    let ret = doc.and_then(|doc| {
+        std::io::Write::write_all(out, format!("{}\n", doc).as_bytes())
+            .map_err(|e| RvabError::io("stdout", e))
+    });
+    if let Err(e) = ret {
+        eprintln!("{}", e);
+        return 1;
+    };
+    code
+}
+
+/// render a check report with the overall verdict
+fn print_check_report(ret: Result<CheckReport, RvabError>) {
+    match ret {
+        Ok(report) => {
+            print!("{}", report);
+            if report.is_pass() {
+                println!("##### PASS #####");
+            } else {
+                println!("##### FAIL #####");
+            }
+        }
+        Err(e) => {
+            eprintln!("Check failed {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// parse a bootctl slot number, exit with EX_USAGE like AOSP if it is not a number
+fn parse_bootctl_slot(slot: &str) -> u32 {
+    slot.parse().unwrap_or_else(|_| {
+        eprintln!("Error: invalid slot number {}", slot);
+        std::process::exit(EX_USAGE);
+    })
+}
+
+/// run a bootctl command, return the exit code
+fn run_bootctl(command: BootctlCommand) -> i32 {
+    //AOSP prints errors as "<what failed>: <reason>"
+    let report = |what: &str, e: RvabError| {
+        eprintln!("{}: {}", what, e.to_string().trim_start_matches("Error: "));
+        EX_SOFTWARE
+    };
+    let unit = |what: &str, ret: Result<(), RvabError>| match ret {
+        Ok(_) => EX_OK,
+        Err(e) => report(what, e),
+    };
+    let boolean = |what: &str, ret: Result<bool, RvabError>| match ret {
+        Ok(true) => EX_OK,
+        Ok(false) => EX_SOFTWARE,
+        Err(e) => report(what, e),
+    };
+    match command {
+        BootctlCommand::GetNumberSlots(_) => {
+            println!("{}", get_android_slot_count());
+            EX_OK
+        }
+        BootctlCommand::GetCurrentSlot(_) => {
+            match get_current_android_slot().and_then(|x| android_slot_number(&x)) {
+                Ok(number) => {
+                    println!("{}", number);
+                    EX_OK
+                }
+                Err(e) => report("Error getting current slot", e),
+            }
+        }
+        BootctlCommand::MarkBootSuccessful(_) => unit(
+            "Error marking as having booted successfully",
+            mark_android_boot_successful(),
+        ),
+        BootctlCommand::SetActiveBootSlot(cmd) => unit(
+            "Error setting active boot slot",
+            android_slot_from_number(parse_bootctl_slot(&cmd.slot))
+                .and_then(change_active_android_slot),
+        ),
+        BootctlCommand::SetSlotAsUnbootable(cmd) => unit(
+            "Error setting slot as unbootable",
+            android_slot_from_number(parse_bootctl_slot(&cmd.slot))
+                .and_then(set_android_slot_unbootable),
+        ),
+        BootctlCommand::IsSlotBootable(cmd) => boolean(
+            "Error calling isSlotBootable()",
+            android_slot_from_number(parse_bootctl_slot(&cmd.slot))
+                .and_then(get_android_slot_attr)
+                .map(|x| !x.unbootable),
+        ),
+        BootctlCommand::IsSlotMarkedSuccessful(cmd) => boolean(
+            "Error calling isSlotMarkedSuccessful()",
+            android_slot_from_number(parse_bootctl_slot(&cmd.slot))
+                .and_then(get_android_slot_attr)
+                .map(|x| x.successful),
+        ),
+    }
 }
 
 #[derive(FromArgs)]
@@ -156,31 +479,63 @@ struct ArchiveMode {
 struct TestMode {}
 fn main() {
     let args: CmdProg = argh::from_env();
+    if let Some(dir) = &args.env {
+        if let Err(e) = ImageDirEnv::open(dir).and_then(|x| set_device_env(Box::new(x))) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        };
+    };
+    let json = match &args.mode {
+        Mode::Init(init) => init.json && init.check.is_some(),
+        Mode::Install(install) => install.json && install.check.is_some(),
+        Mode::Verify(verify) => verify.json,
+        Mode::List(list) => list.json,
+        Mode::Current(current) => current.json,
+        _ => false,
+    };
+    let mut json_out = if json { Some(take_json_stdout()) } else { None };
     match args.mode {
         Mode::Init(init) => {
             println!("Init mode");
             if let Some(out) = init.template {
                 let path = std::path::PathBuf::from(out);
-                generate_template_init_config_file(path, init.exclude, init.dynpt).unwrap();
+                if let Err(e) = generate_template_init_config_file(
+                    path,
+                    init.exclude,
+                    init.dynpt,
+                    init.compression,
+                    init.dedup,
+                ) {
+                    eprintln!("Generate template failed {}", e);
+                    std::process::exit(1);
+                };
                 return;
             }
             if let Some(check) = init.check {
-                check_slots_config(&check);
+                let ret = check_slots_config(&check);
+                if let Some(out) = &mut json_out {
+                    print_json(out, "check", ret.map(|x| JsonCheck::from(&x)));
+                };
+                print_check_report(ret);
                 return;
             }
             //only init userdata
             if let Some(config) = init.config {
-                let ret = try_init_userdata_partition(&config, &init.slot, args.silent);
-                if ret.is_err() {
-                    eprintln!("Init failed ");
+                if let Err(e) = try_init_userdata_partition(&config, &init.slot, args.silent) {
+                    eprintln!("Init failed {}", e);
+                    std::process::exit(1);
                 }
                 return;
             }
             if let Some(config) = init.full {
                 let ret = try_init_partition_table_layout(&config, &init.slot, true, args.silent);
-                if ret.is_err() {
-                    eprintln!("Init failed {}", ret.err().unwrap());
-                }
+                match ret {
+                    Ok(report) => print!("{}", report),
+                    Err(e) => {
+                        eprintln!("Init failed {}", e);
+                        std::process::exit(1);
+                    }
+                };
                 println!("Done , please keep your config , reboot to run install mode");
                 return;
             }
@@ -191,37 +546,154 @@ fn main() {
             println!("Install mode");
             if let Some(out) = install.template {
                 let path = std::path::PathBuf::from(out);
-                generate_template_init_config_file(path, install.exclude, install.dynpt).unwrap();
+                if let Err(e) = generate_template_init_config_file(
+                    path,
+                    install.exclude,
+                    install.dynpt,
+                    install.compression,
+                    install.dedup,
+                ) {
+                    eprintln!("Generate template failed {}", e);
+                    std::process::exit(1);
+                };
                 return;
             }
             if let Some(check) = install.check {
-                check_slots_config(&check);
+                let ret = check_slots_config(&check);
+                if let Some(out) = &mut json_out {
+                    print_json(out, "check", ret.map(|x| JsonCheck::from(&x)));
+                };
+                print_check_report(ret);
                 return;
             }
             if let Some(config) = install.update {
-                update_config_to_all_slots(&config);
+                if let Err(e) = update_config_to_all_slots(&config) {
+                    eprintln!("Update failed {}", e);
+                    std::process::exit(1);
+                };
                 return;
             }
             if let Some(dump_file) = install.dump {
-                dump_current_metadata(&dump_file);
+                if let Err(e) = dump_current_metadata(&dump_file) {
+                    eprintln!("Dump failed {}", e);
+                    std::process::exit(1);
+                };
                 return;
             };
             println!("Option required");
             return;
         }
-        Mode::Switch(_) => {
+        Mode::Switch(switch) => {
             println!("Switch mode");
+            //a failed or rolled back switch exits 1
+            if let Err(e) = switch_to_slot(&switch.slot, args.silent) {
+                eprintln!("Switch failed {}", e);
+                std::process::exit(1);
+            }
+            println!("Done , please reboot to enter slot {}", switch.slot);
+        }
+        Mode::Recover(recover) => {
+            println!("Recover mode");
+            if let Err(e) = recover_interrupted_switch(&recover.config) {
+                eprintln!("Recover failed {}", e);
+                std::process::exit(1);
+            }
+        }
+        Mode::Verify(verify) => {
+            println!("Verify mode");
+            let ret = verify_slot_backup(verify.slot);
+            //a backup that does not pass exits 1 as well
+            if let Some(out) = &mut json_out {
+                let ret = ret.map(|x| JsonVerify::from(&x));
+                let pass = ret.as_ref().is_ok_and(|x| x.pass);
+                let code = write_json(out, "verify", ret);
+                std::process::exit(if pass { code } else { 1 });
+            };
+            match ret {
+                Ok(report) => {
+                    print!("{}", report);
+                    if report.is_match() {
+                        println!("##### PASS #####");
+                    } else {
+                        println!("##### FAIL #####");
+                        std::process::exit(1);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Verify failed {}", err);
+                    std::process::exit(1);
+                }
+            }
         }
         Mode::List(list) => {
             println!("List mode");
-            list_slots(list.slot, list.name);
+            let single = list.slot.is_some();
+            let ret = list_slots(list.slot);
+            if let Some(out) = &mut json_out {
+                print_json(out, "list", ret.map(|x| JsonListing::from(&x)));
+            };
+            match ret {
+                Ok(listing) if single => {
+                    for slot in listing.slots.iter() {
+                        println!("{}", slot);
+                    }
+                }
+                Ok(listing) if list.name => {
+                    for (counter, slot) in listing.slots.iter().enumerate() {
+                        println!("{} : {}", counter + 1, slot.slot_name);
+                    }
+                }
+                Ok(listing) => println!("{}", listing),
+                Err(e) => {
+                    eprintln!("List failed {}", e);
+                    std::process::exit(1);
+                }
+            };
         }
         Mode::Current(current) => {
             println!("Current mode");
-            show_current_slot(current.name);
+            let ret = get_current_slot();
+            if let Some(out) = &mut json_out {
+                print_json(
+                    out,
+                    "current",
+                    ret.map(|x| JsonCurrent {
+                        current_slot: JsonSlot::from_slot(&x),
+                    }),
+                );
+            };
+            match ret {
+                Ok(slot) => {
+                    println!("Current Slot : {}", slot.slot_name);
+                    if !current.name {
+                        println!("Slot Name : {:?}", slot);
+                    };
+                }
+                Err(e) => {
+                    eprintln!("Current failed {}", e);
+                    std::process::exit(1);
+                }
+            };
         }
-        Mode::Archive(_) => {
+        Mode::Archive(archive) => {
             println!("Archive mode");
+            if let Err(err) =
+                archive_slot(&archive.slot, &archive.output, archive.gpt, archive.sparse)
+            {
+                eprintln!("Archive failed {}", err);
+                std::process::exit(1);
+            };
+        }
+        Mode::Import(import) => {
+            println!("Import mode");
+            if let Err(err) = import_archive(&import.input, &import.slot) {
+                eprintln!("Import failed {}", err);
+                std::process::exit(1);
+            };
+        }
+        Mode::Bootctl(bootctl) => {
+            //no mode banner,scripts parse the output like AOSP bootctl
+            std::process::exit(run_bootctl(bootctl.command));
         }
         Mode::Test(_) => {
             println!("Test mode");
@@ -1,4 +1,11 @@
+use crate::constants::get_block_dev_dir;
+use crate::device_env::device_env;
 /// from android-13-r43
+use crate::error::RvabError;
+use crate::gpt_helper::{get_gpt_disk, get_part_accelerate_location, get_part_info};
+use gpt::partition_types::Type;
+use std::collections::BTreeMap;
+use std::path::Path;
 /******************************************************************************
  * AB RELATED DEFINES
  ******************************************************************************/
@@ -55,13 +62,254 @@ const AB_PTN_LIST: [&str; 21] = [
     "modem",
     "product",
 ];
+const BOOT_PARAM_SLOT_SUFFIX: &str = "androidboot.slot_suffix";
 const BOOT_DEV_DIR: &str = "/dev/block/bootdevice/by-name";
 
-/// Get the current active android slot
-pub fn get_current_android_slot() -> String {
-    // TODO
-    "a".to_string()
+/// AB attributes of an android slot, byte AB_FLAG_OFFSET of the gpt attribute field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AbSlotAttr {
+    pub active: bool,
+    pub successful: bool,
+    pub unbootable: bool,
 }
-pub fn change_active_android_slot(slot: &str) {
-    // TODO
+impl AbSlotAttr {
+    fn from_flags(flags: u64) -> Self {
+        let attr = ab_attr(flags);
+        AbSlotAttr {
+            active: attr & AB_PARTITION_ATTR_SLOT_ACTIVE != 0,
+            successful: attr & AB_PARTITION_ATTR_BOOT_SUCCESSFUL != 0,
+            unbootable: attr & AB_PARTITION_ATTR_UNBOOTABLE != 0,
+        }
+    }
+}
+
+fn ab_attr(flags: u64) -> u8 {
+    (flags >> (AB_FLAG_OFFSET * 8)) as u8
+}
+
+fn with_ab_attr(flags: u64, attr: u8) -> u64 {
+    flags & !(0xFF << (AB_FLAG_OFFSET * 8)) | (attr as u64) << (AB_FLAG_OFFSET * 8)
+}
+
+/// suffix of an android slot, "a" or "_a" is "_a"
+fn slot_suffix(slot: &str) -> Result<&'static str, RvabError> {
+    match slot.trim_start_matches('_') {
+        "a" => Ok(AB_SLOT_A_SUFFIX),
+        "b" => Ok(AB_SLOT_B_SUFFIX),
+        _ => Err(RvabError::Backend("Error: android slot must be a or b")),
+    }
+}
+
+/// AB attributes of the android slot, read from its boot partition
+pub fn get_android_slot_attr(slot: &str) -> Result<AbSlotAttr, RvabError> {
+    let name = format!("boot{}", slot_suffix(slot)?);
+    let (_, flags) =
+        get_part_info(&name).map_err(|_| "Error: boot partition of android slot not found")?;
+    Ok(AbSlotAttr::from_flags(flags))
+}
+
+/// androidboot.slot_suffix of kernel cmdline ("key=value" words) or bootconfig ("key = \"value\"" lines)
+fn boot_param_slot_suffix(params: &str) -> Option<&str> {
+    params.lines().find_map(|line| {
+        if let Some(value) = line.trim().strip_prefix(BOOT_PARAM_SLOT_SUFFIX) {
+            if let Some(value) = value.trim_start().strip_prefix('=') {
+                return Some(value.trim().trim_matches('"'));
+            };
+        };
+        line.split_whitespace()
+            .find_map(|x| x.strip_prefix(BOOT_PARAM_SLOT_SUFFIX)?.strip_prefix('='))
+    })
+}
+
+/// Get the running android slot ("a" or "b") from androidboot.slot_suffix the bootloader passed,
+/// unlike the gpt active bit it does not change with set-active-boot-slot before a reboot
+pub fn get_current_android_slot() -> Result<String, RvabError> {
+    let params = device_env().boot_params();
+    let suffix = boot_param_slot_suffix(&params).ok_or(RvabError::Backend(
+        "Error: androidboot.slot_suffix not found in kernel cmdline or bootconfig",
+    ))?;
+    Ok(slot_suffix(suffix)?.trim_start_matches('_').to_string())
+}
+
+/// Get the active android slot ("a" or "b"), the one whose boot partition is active,
+/// this is the slot of the next boot
+pub fn get_active_android_slot() -> Result<String, RvabError> {
+    let attr_a = get_android_slot_attr(AB_SLOT_A_SUFFIX)?;
+    let attr_b = get_android_slot_attr(AB_SLOT_B_SUFFIX)?;
+    match (attr_a.active, attr_b.active) {
+        (true, false) => Ok("a".to_string()),
+        (false, true) => Ok("b".to_string()),
+        (true, true) => Err(RvabError::Backend("Error: both android slots are active")),
+        (false, false) => Err(RvabError::Backend("Error: no android slot is active")),
+    }
+}
+
+/// Set the active android slot like the qualcomm boot_control HAL:
+/// every AB_PTN_LIST partition of the slot is marked active (retry count reset, successful and
+/// unbootable cleared) and its other slot copy inactive,
+/// PTN_SWAP_LIST partitions also swap type guids, the active copy carries the active guid.
+/// Partitions missing on this device are skipped.
+/// On UFS the HAL also switches the boot LUN when xbl_a and xbl_b sit on separate LUNs
+/// (xbl_a on LUN1,xbl_b on LUN2), that is not supported here, such a switch is refused
+/// before any partition entry is written.
+pub fn change_active_android_slot(slot: &str) -> Result<(), RvabError> {
+    let suffix = slot_suffix(slot)?;
+    let other_suffix = if suffix == AB_SLOT_A_SUFFIX {
+        AB_SLOT_B_SUFFIX
+    } else {
+        AB_SLOT_A_SUFFIX
+    };
+    let block_dev_dir = get_block_dev_dir()?;
+    //new (type guid,flags) of partition entries,by disk and partition id
+    let mut updates: BTreeMap<String, BTreeMap<u32, (Type, u64)>> = BTreeMap::new();
+    for base in AB_PTN_LIST {
+        let name = format!("{}{}", base, suffix);
+        let other_name = format!("{}{}", base, other_suffix);
+        let exists = |x: &str| Path::new(&format!("{}{}", block_dev_dir, x)).exists();
+        if !exists(&name) || !exists(&other_name) {
+            continue;
+        };
+        let (driver, id, ..) = get_part_accelerate_location(&name)?;
+        let (other_driver, other_id, ..) = get_part_accelerate_location(&other_name)?;
+        let entry = read_part_entry(&driver, id)?;
+        let other_entry = read_part_entry(&other_driver, other_id)?;
+        let (mut type_guid, mut other_type_guid) = (entry.0, other_entry.0);
+        let active = ab_attr(entry.1) & AB_PARTITION_ATTR_SLOT_ACTIVE != 0;
+        let other_active = ab_attr(other_entry.1) & AB_PARTITION_ATTR_SLOT_ACTIVE != 0;
+        if base == PTN_XBL && driver != other_driver && !active {
+            eprintln!(
+                "Error: {} is on {} and {} on {}, switching the ufs boot lun is not supported",
+                name, driver, other_name, other_driver
+            );
+            return Err(RvabError::Backend(
+                "Error: android slot needs a boot lun switch",
+            ));
+        };
+        if PTN_SWAP_LIST.contains(&base) && !active && other_active {
+            std::mem::swap(&mut type_guid, &mut other_type_guid);
+        };
+        updates
+            .entry(driver)
+            .or_default()
+            .insert(id, (type_guid, with_ab_attr(entry.1, AB_SLOT_ACTIVE_VAL)));
+        updates.entry(other_driver).or_default().insert(
+            other_id,
+            (
+                other_type_guid,
+                with_ab_attr(
+                    other_entry.1,
+                    ab_attr(other_entry.1) & !AB_PARTITION_ATTR_SLOT_ACTIVE,
+                ),
+            ),
+        );
+    }
+    write_part_updates(updates)
+}
+
+/// Set AB attribute bits on every AB_PTN_LIST partition of the android slot,
+/// partitions missing on this device are skipped.
+fn set_android_slot_attr_bits(slot: &str, bits: u8) -> Result<(), RvabError> {
+    let suffix = slot_suffix(slot)?;
+    let block_dev_dir = get_block_dev_dir()?;
+    let mut updates: BTreeMap<String, BTreeMap<u32, (Type, u64)>> = BTreeMap::new();
+    for base in AB_PTN_LIST {
+        let name = format!("{}{}", base, suffix);
+        if !Path::new(&format!("{}{}", block_dev_dir, name)).exists() {
+            continue;
+        };
+        let (driver, id, ..) = get_part_accelerate_location(&name)?;
+        let (type_guid, flags) = read_part_entry(&driver, id)?;
+        updates
+            .entry(driver)
+            .or_default()
+            .insert(id, (type_guid, with_ab_attr(flags, ab_attr(flags) | bits)));
+    }
+    write_part_updates(updates)
+}
+
+/// Mark the running android slot as booted successfully
+pub fn mark_android_boot_successful() -> Result<(), RvabError> {
+    let slot = get_current_android_slot()?;
+    set_android_slot_attr_bits(&slot, AB_PARTITION_ATTR_BOOT_SUCCESSFUL)
+}
+
+/// Mark the android slot as unbootable
+pub fn set_android_slot_unbootable(slot: &str) -> Result<(), RvabError> {
+    set_android_slot_attr_bits(slot, AB_PARTITION_ATTR_UNBOOTABLE)
+}
+
+/// Number of android slots, counted by boot partitions like the HAL
+pub fn get_android_slot_count() -> u32 {
+    [AB_SLOT_A_SUFFIX, AB_SLOT_B_SUFFIX]
+        .iter()
+        .filter(|x| get_part_info(&format!("boot{}", x)).is_ok())
+        .count() as u32
+}
+
+/// Android slot number used by bootctl, "a" is 0 and "b" is 1
+pub fn android_slot_number(slot: &str) -> Result<u32, RvabError> {
+    match slot_suffix(slot)? {
+        AB_SLOT_A_SUFFIX => Ok(0),
+        _ => Ok(1),
+    }
+}
+
+/// Android slot ("a" or "b") of a bootctl slot number
+pub fn android_slot_from_number(number: u32) -> Result<&'static str, RvabError> {
+    match number {
+        0 => Ok("a"),
+        1 => Ok("b"),
+        _ => Err(RvabError::Backend("Invalid slot")),
+    }
+}
+
+/// Write new (type guid,flags) of partition entries,by disk and partition id
+fn write_part_updates(
+    updates: BTreeMap<String, BTreeMap<u32, (Type, u64)>>,
+) -> Result<(), RvabError> {
+    if updates.is_empty() {
+        return Err(RvabError::Backend("Error: no android ab partitions found"));
+    };
+    for (driver, entries) in updates {
+        let mut disk = get_gpt_disk(&driver, true)?;
+        let mut partitions = disk.take_partitions();
+        for (id, (type_guid, flags)) in entries {
+            let part = partitions
+                .get_mut(&id)
+                .ok_or("Error: partition not found")?;
+            part.part_type_guid = type_guid;
+            part.flags = flags;
+        }
+        disk.update_partitions(partitions)
+            .map_err(|_| "Error: update partition table failed")?;
+        disk.write().map_err(|_| "Error: write disk failed")?;
+    }
+    Ok(())
+}
+
+/// (type guid,flags) of a partition entry
+fn read_part_entry(driver: &str, id: u32) -> Result<(Type, u64), RvabError> {
+    let disk = get_gpt_disk(driver, false)?;
+    let part = disk
+        .partitions()
+        .get(&id)
+        .ok_or("Error: partition not found")?;
+    Ok((part.part_type_guid.clone(), part.flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slot_suffix_from_boot_params() {
+        let cmdline = "console=ttyMSM0 androidboot.hardware=qcom androidboot.slot_suffix=_b rw";
+        assert_eq!(boot_param_slot_suffix(cmdline), Some("_b"));
+        let bootconfig = "androidboot.hardware = \"qcom\"\nandroidboot.slot_suffix = \"_a\"\n";
+        assert_eq!(boot_param_slot_suffix(bootconfig), Some("_a"));
+        let both = format!("{}\n{}", "androidboot.mode=normal", bootconfig);
+        assert_eq!(boot_param_slot_suffix(&both), Some("_a"));
+        assert_eq!(boot_param_slot_suffix("androidboot.slot_suffixes=_a"), None);
+        assert_eq!(boot_param_slot_suffix(""), None);
+    }
 }
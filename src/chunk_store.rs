@@ -0,0 +1,789 @@
+use crate::backup_factory::{
+    new_progress_bar, warn_part_type_changed, BackupIndex, BackupIndexEntry, BackupRegion,
+    FirmwarePart, ImageEncoding, ImageSink, SegmentWriter, SlotGpt, VerifyReport,
+};
+///chunk store module
+/// A deduplicating store of firmware images, shared by all slots which use the same backup
+/// region and set `dedup` in backup_target_attr.
+/// Images are split into fixed size chunks keyed by sha256, identical chunks are stored once
+/// and reference counted by the manifests (image lists) of all slots in `Metadata::slots`.
+/// Layout: two table copies (the newest valid one wins, a commit overwrites the other one),
+/// then the chunk data area, one CHUNK_SIZE slot per chunk id.
+use crate::error::RvabError;
+use crate::gpt_helper::{bytes2ieee, get_part_accelerate_location, list_firmware_partitions};
+use crate::metadata::{Metadata, Slot};
+use crc32fast::Hasher;
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+
+pub const CHUNK_STORE_MAGIC: &[u8; 8] = b"RVABCHNK";
+pub const CHUNK_STORE_VERSION: u32 = 2;
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+const CHUNK_TABLE_COPY_SIZE: u64 = 4 * 1024 * 1024;
+const CHUNK_DATA_OFFSET: u64 = 2 * CHUNK_TABLE_COPY_SIZE;
+/// chunk id of an all-zero chunk, nothing is stored for it
+pub const CHUNK_ZERO: u32 = u32::MAX;
+/// table head: magic,version (u32),crc32 (u32) of the payload,sequence (u64),payload length (u64)
+/// payload: chunk count (u32),chunk records (sha256,length u32,refcount u32),
+/// manifest count (u32),manifests (index length u32,backup index,chunk id lists per entry,
+/// since version 2: gpt snapshot length u32,gpt snapshot)
+const CHUNK_TABLE_HEAD_LEN: usize = 32;
+const CHUNK_RECORD_LEN: usize = 40;
+
+/// A chunk slot of the data area, refcount 0 means free
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChunkRecord {
+    pub sha256: [u8; 32],
+    pub length: u32,
+    pub refcount: u32,
+}
+
+/// Stored images of a slot, chunks holds the chunk ids of every index entry
+#[derive(Debug, Clone)]
+pub struct SlotManifest {
+    pub index: BackupIndex,
+    pub chunks: Vec<Vec<u32>>,
+    //SlotGpt bytes of the slot, empty if no gpt is stored
+    pub gpt: Vec<u8>,
+}
+
+/// A valid table copy read from disk
+struct TableCopy {
+    sequence: u64,
+    version: u32,
+    payload: Vec<u8>,
+}
+
+pub struct ChunkStore {
+    region: BackupRegion,
+    //sequence and table copy of the loaded table
+    sequence: u64,
+    copy: u64,
+    chunks: Vec<ChunkRecord>,
+    manifests: BTreeMap<String, SlotManifest>,
+}
+
+impl ChunkStore {
+    /// open the chunk store in region, an empty store if nothing is stored yet
+    pub fn open(region: &BackupRegion) -> Result<Self, RvabError> {
+        if region.length < CHUNK_DATA_OFFSET + CHUNK_SIZE {
+            return Err(RvabError::Backend(
+                "Error: backup target too small for chunk store",
+            ));
+        };
+        let capacity = ((region.length - CHUNK_DATA_OFFSET) / CHUNK_SIZE).min(CHUNK_ZERO as u64);
+        let mut store = ChunkStore {
+            region: region.clone(),
+            sequence: 0,
+            copy: 1,
+            chunks: vec![ChunkRecord::default(); capacity as usize],
+            manifests: BTreeMap::new(),
+        };
+        let file = File::open(&region.path).map_err(|e| RvabError::io(&region.path, e))?;
+        let mut newest: Option<(u64, TableCopy)> = None;
+        for copy in 0..2 {
+            if let Some(table) = store.read_table(&file, copy)? {
+                if newest
+                    .as_ref()
+                    .is_none_or(|(_, x)| x.sequence < table.sequence)
+                {
+                    newest = Some((copy, table));
+                };
+            };
+        }
+        if let Some((copy, table)) = newest {
+            store.sequence = table.sequence;
+            store.copy = copy;
+            store.load_payload(&table.payload, table.version)?;
+        };
+        Ok(store)
+    }
+
+    /// read a table copy, None if it is not valid
+    fn read_table(&self, file: &File, copy: u64) -> Result<Option<TableCopy>, RvabError> {
+        let offset = self.region.offset + copy * CHUNK_TABLE_COPY_SIZE;
+        let mut head = [0; CHUNK_TABLE_HEAD_LEN];
+        file.read_exact_at(&mut head, offset)
+            .map_err(|e| RvabError::io(&self.region.path, e))?;
+        if &head[..8] != CHUNK_STORE_MAGIC {
+            return Ok(None);
+        };
+        let version = u32::from_le_bytes(head[8..12].try_into().unwrap());
+        if version > CHUNK_STORE_VERSION {
+            return Err(RvabError::Backend("Error: unsupported chunk store version"));
+        };
+        let crc32 = u32::from_le_bytes(head[12..16].try_into().unwrap());
+        let sequence = u64::from_le_bytes(head[16..24].try_into().unwrap());
+        let length = u64::from_le_bytes(head[24..32].try_into().unwrap());
+        if length > CHUNK_TABLE_COPY_SIZE - CHUNK_TABLE_HEAD_LEN as u64 {
+            return Ok(None);
+        };
+        let mut payload = vec![0; length as usize];
+        file.read_exact_at(&mut payload, offset + CHUNK_TABLE_HEAD_LEN as u64)
+            .map_err(|e| RvabError::io(&self.region.path, e))?;
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+        if hasher.finalize() != crc32 {
+            println!(
+                "Warning: chunk store table copy {} is broken, ignored",
+                copy
+            );
+            return Ok(None);
+        };
+        Ok(Some(TableCopy {
+            sequence,
+            version,
+            payload,
+        }))
+    }
+
+    fn load_payload(&mut self, payload: &[u8], version: u32) -> Result<(), RvabError> {
+        let mut reader = PayloadReader { buffer: payload };
+        let count = reader.u32()? as usize;
+        if count > self.chunks.len() {
+            return Err(RvabError::Backend(
+                "Error: chunk store does not match backup target size",
+            ));
+        };
+        for record in self.chunks.iter_mut().take(count) {
+            let raw = reader.take(CHUNK_RECORD_LEN)?;
+            record.sha256 = raw[..32].try_into().unwrap();
+            record.length = u32::from_le_bytes(raw[32..36].try_into().unwrap());
+            record.refcount = u32::from_le_bytes(raw[36..40].try_into().unwrap());
+        }
+        for _ in 0..reader.u32()? {
+            let length = reader.u32()? as usize;
+            let index = BackupIndex::from_bytes(reader.take(length)?)?
+                .ok_or("Error: invalid manifest in chunk store")?;
+            let mut chunks = Vec::new();
+            for _ in index.entries.iter() {
+                let count = reader.u32()? as usize;
+                let ids: Vec<u32> = reader
+                    .take(count * 4)?
+                    .chunks(4)
+                    .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+                    .collect();
+                if ids
+                    .iter()
+                    .any(|id| *id != CHUNK_ZERO && *id as usize >= self.chunks.len())
+                {
+                    return Err(RvabError::integrity(
+                        "chunk id out of range in chunk store manifest",
+                    ));
+                };
+                chunks.push(ids);
+            }
+            let gpt = if version >= 2 {
+                let length = reader.u32()? as usize;
+                reader.take(length)?.to_vec()
+            } else {
+                Vec::new()
+            };
+            self.manifests
+                .insert(index.slot_name.clone(), SlotManifest { index, chunks, gpt });
+        }
+        Ok(())
+    }
+
+    fn payload(&self) -> Result<Vec<u8>, RvabError> {
+        //trailing free records are not written
+        let count = self
+            .chunks
+            .iter()
+            .rposition(|x| x.refcount != 0)
+            .map_or(0, |x| x + 1);
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(count as u32).to_le_bytes());
+        for record in self.chunks.iter().take(count) {
+            payload.extend_from_slice(&record.sha256);
+            payload.extend_from_slice(&record.length.to_le_bytes());
+            payload.extend_from_slice(&record.refcount.to_le_bytes());
+        }
+        payload.extend_from_slice(&(self.manifests.len() as u32).to_le_bytes());
+        for manifest in self.manifests.values() {
+            let index = manifest.index.to_bytes()?;
+            payload.extend_from_slice(&(index.len() as u32).to_le_bytes());
+            payload.extend_from_slice(&index);
+            for ids in manifest.chunks.iter() {
+                payload.extend_from_slice(&(ids.len() as u32).to_le_bytes());
+                for id in ids {
+                    payload.extend_from_slice(&id.to_le_bytes());
+                }
+            }
+            payload.extend_from_slice(&(manifest.gpt.len() as u32).to_le_bytes());
+            payload.extend_from_slice(&manifest.gpt);
+        }
+        if (payload.len() + CHUNK_TABLE_HEAD_LEN) as u64 > CHUNK_TABLE_COPY_SIZE {
+            return Err(RvabError::Backend("Error: chunk store table overflow"));
+        };
+        Ok(payload)
+    }
+
+    /// write the table into the older copy, it becomes the newest one
+    fn commit(&mut self, file: &File) -> Result<(), RvabError> {
+        let payload = self.payload()?;
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+        let copy = 1 - self.copy;
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(CHUNK_STORE_MAGIC);
+        buffer.extend_from_slice(&CHUNK_STORE_VERSION.to_le_bytes());
+        buffer.extend_from_slice(&hasher.finalize().to_le_bytes());
+        buffer.extend_from_slice(&(self.sequence + 1).to_le_bytes());
+        buffer.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        buffer.extend_from_slice(&payload);
+        file.write_all_at(&buffer, self.region.offset + copy * CHUNK_TABLE_COPY_SIZE)
+            .map_err(|e| RvabError::io(&self.region.path, e))?;
+        file.sync_all()
+            .map_err(|e| RvabError::io(&self.region.path, e))?;
+        self.sequence += 1;
+        self.copy = copy;
+        Ok(())
+    }
+
+    fn chunk_offset(&self, id: u32) -> u64 {
+        self.region.offset + CHUNK_DATA_OFFSET + id as u64 * CHUNK_SIZE
+    }
+
+    /// recount references of all chunks from the manifests
+    fn recount(&mut self) {
+        for record in self.chunks.iter_mut() {
+            record.refcount = 0;
+        }
+        for manifest in self.manifests.values() {
+            for id in manifest.chunks.iter().flatten() {
+                if *id != CHUNK_ZERO {
+                    self.chunks[*id as usize].refcount += 1;
+                };
+            }
+        }
+    }
+
+    /// store firmware parts of the slot, replacing its old manifest.
+    /// manifests of slots no longer in metadata are dropped and their chunks freed.
+    /// New chunks only go to free slots, so the old table stays valid until the commit
+    pub fn store_firmware(
+        &mut self,
+        metadata: &Metadata,
+        slot_name: &str,
+        parts: &[FirmwarePart],
+    ) -> Result<BackupIndex, RvabError> {
+        let tfile = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.region.path)
+            .map_err(|e| RvabError::io(&self.region.path, e))?;
+        let mut known: HashMap<[u8; 32], u32> = HashMap::new();
+        let mut free = Vec::new();
+        for (id, record) in self.chunks.iter().enumerate() {
+            if record.refcount == 0 {
+                free.push(id as u32);
+            } else {
+                known.insert(record.sha256, id as u32);
+            };
+        }
+        free.reverse();
+        let reused = known.len();
+
+        let mut index = BackupIndex::new(slot_name, self.region.length);
+        let mut chunks = Vec::new();
+        let pb = new_progress_bar(parts.iter().map(|x| x.length).sum());
+        let mut buffer = vec![0; CHUNK_SIZE as usize];
+        let mut new_chunks = 0;
+        for part in parts {
+            let mut source = part.open_source()?;
+            let mut entry = BackupIndexEntry::from_part(part, 0);
+            entry.encoding = ImageEncoding::Chunked;
+            let mut hasher = Sha256::new();
+            let mut ids = Vec::new();
+            let mut done = 0;
+            while done < part.length {
+                let size = (part.length - done).min(CHUNK_SIZE) as usize;
+                source
+                    .read_exact(&mut buffer[..size])
+                    .map_err(|e| RvabError::io(&part.driver, e))?;
+                hasher.update(&buffer[..size]);
+                done += size as u64;
+                pb.inc(size as u64);
+                if buffer[..size].iter().all(|&x| x == 0) {
+                    ids.push(CHUNK_ZERO);
+                    continue;
+                };
+                entry.stored_length += size as u64;
+                let sha256: [u8; 32] = Sha256::digest(&buffer[..size]).into();
+                if let Some(id) = known.get(&sha256) {
+                    ids.push(*id);
+                    continue;
+                };
+                let id = free.pop().ok_or("Error: chunk store is full")?;
+                tfile
+                    .write_all_at(&buffer[..size], self.chunk_offset(id))
+                    .map_err(|e| RvabError::io(&self.region.path, e))?;
+                self.chunks[id as usize] = ChunkRecord {
+                    sha256,
+                    length: size as u32,
+                    refcount: 0,
+                };
+                known.insert(sha256, id);
+                ids.push(id);
+                new_chunks += 1;
+            }
+            entry.sha256 = hasher.finalize().into();
+            entry.packed_length = entry.stored_length;
+            index.entries.push(entry);
+            chunks.push(ids);
+        }
+        tfile
+            .sync_all()
+            .map_err(|e| RvabError::io(&self.region.path, e))?;
+
+        //the gpt snapshot stays until the next gpt backup
+        let gpt = self
+            .manifests
+            .remove(slot_name)
+            .map(|x| x.gpt)
+            .unwrap_or_default();
+        self.manifests.insert(
+            slot_name.to_string(),
+            SlotManifest {
+                index: index.clone(),
+                chunks,
+                gpt,
+            },
+        );
+        self.manifests
+            .retain(|name, _| metadata.slots.contains_key(name));
+        self.recount();
+        self.commit(&tfile)?;
+        pb.finish_with_message("backup finished");
+        let used = self.chunks.iter().filter(|x| x.refcount != 0).count() as u64;
+        println!(
+            "Chunk store: {} new chunks, {} existing, {} of {} used by {} slots",
+            new_chunks,
+            reused,
+            bytes2ieee(used * CHUNK_SIZE),
+            bytes2ieee(self.chunks.len() as u64 * CHUNK_SIZE),
+            self.manifests.len()
+        );
+        Ok(index)
+    }
+
+    /// expand a stored image to target if any, return sha256 of the image
+    fn expand_image(
+        &self,
+        file: &File,
+        entry: &BackupIndexEntry,
+        ids: &[u32],
+        mut target: Option<&mut dyn Write>,
+        pb: Option<&ProgressBar>,
+    ) -> Result<[u8; 32], RvabError> {
+        if ids.len() as u64 != entry.length.div_ceil(CHUNK_SIZE) {
+            return Err(RvabError::integrity("invalid manifest in chunk store"));
+        };
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; CHUNK_SIZE as usize];
+        for (i, id) in ids.iter().enumerate() {
+            let offset = i as u64 * CHUNK_SIZE;
+            let size = (entry.length - offset).min(CHUNK_SIZE) as usize;
+            if *id == CHUNK_ZERO {
+                buffer[..size].fill(0);
+            } else {
+                let record = self
+                    .chunks
+                    .get(*id as usize)
+                    .ok_or("Error: invalid manifest in chunk store")?;
+                if record.length as usize != size {
+                    return Err(RvabError::integrity("invalid manifest in chunk store"));
+                };
+                file.read_exact_at(&mut buffer[..size], self.chunk_offset(*id))
+                    .map_err(|e| RvabError::io(&self.region.path, e))?;
+            };
+            hasher.update(&buffer[..size]);
+            if let Some(target) = &mut target {
+                target
+                    .write_all(&buffer[..size])
+                    .map_err(|_| "Error: write target disk failed")?;
+            };
+            if let Some(pb) = pb {
+                pb.inc(size as u64);
+            };
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// restore all stored images of the slot to the live partitions with the same name
+    /// all images are checked before anything is written
+    /// return false if the slot has nothing stored
+    pub fn restore_firmware(&self, slot_name: &str) -> Result<bool, RvabError> {
+        let manifest = match self.manifests.get(slot_name) {
+            Some(manifest) => manifest,
+            None => return Ok(false),
+        };
+        let sfile =
+            File::open(&self.region.path).map_err(|e| RvabError::io(&self.region.path, e))?;
+        let mut targets = Vec::new();
+        for (entry, ids) in manifest.index.entries.iter().zip(manifest.chunks.iter()) {
+            let (driver, _, first_lba, last_lba, sector_size) =
+                get_part_accelerate_location(&entry.name)?;
+            if (last_lba - first_lba + 1) * sector_size != entry.length {
+                eprintln!("Error: size of partition {} changed", entry.name);
+                return Err(RvabError::Backend("Error: firmware partition size changed"));
+            };
+            warn_part_type_changed(entry);
+            if self.expand_image(&sfile, entry, ids, None, None)? != entry.sha256 {
+                eprintln!("Error: backup of partition {} is broken", entry.name);
+                return Err(RvabError::integrity("backup image sha256 not match"));
+            };
+            targets.push((driver, first_lba * sector_size));
+        }
+        let pb = new_progress_bar(manifest.index.entries.iter().map(|x| x.length).sum());
+        for ((entry, ids), (driver, offset)) in manifest
+            .index
+            .entries
+            .iter()
+            .zip(manifest.chunks.iter())
+            .zip(targets)
+        {
+            let tfile = OpenOptions::new()
+                .write(true)
+                .open(&driver)
+                .map_err(|e| RvabError::io(&driver, e))?;
+            let mut writer = SegmentWriter::new(&tfile, offset, entry.length);
+            if self.expand_image(&sfile, entry, ids, Some(&mut writer), Some(&pb))? != entry.sha256
+            {
+                eprintln!(
+                    "Terrible!!!: partition {} changed in chunk store during restore",
+                    entry.name
+                );
+                return Err(RvabError::integrity("backup image sha256 not match"));
+            };
+            tfile.sync_all().map_err(|e| RvabError::io(&driver, e))?;
+        }
+        pb.finish_with_message("restore finished");
+        Ok(true)
+    }
+
+    /// expand all stored images of the slot into sink
+    /// return false if the slot has nothing stored
+    pub fn export_firmware(
+        &self,
+        slot_name: &str,
+        sink: &mut dyn ImageSink,
+    ) -> Result<bool, RvabError> {
+        let manifest = match self.manifests.get(slot_name) {
+            Some(manifest) => manifest,
+            None => return Ok(false),
+        };
+        let sfile =
+            File::open(&self.region.path).map_err(|e| RvabError::io(&self.region.path, e))?;
+        let pb = new_progress_bar(manifest.index.entries.iter().map(|x| x.length).sum());
+        for (entry, ids) in manifest.index.entries.iter().zip(manifest.chunks.iter()) {
+            let writer = sink.begin_image(entry)?;
+            if self.expand_image(&sfile, entry, ids, Some(writer), Some(&pb))? != entry.sha256 {
+                eprintln!("Error: backup of partition {} is broken", entry.name);
+                return Err(RvabError::integrity("backup image sha256 not match"));
+            };
+            sink.end_image(entry)?;
+        }
+        pb.finish_with_message("export finished");
+        Ok(true)
+    }
+
+    /// verify stored images of the slot against live partitions
+    /// return None if the slot has nothing stored
+    pub fn verify_firmware(&self, slot: &Slot) -> Result<Option<VerifyReport>, RvabError> {
+        let manifest = match self.manifests.get(&slot.slot_name) {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        let sfile =
+            File::open(&self.region.path).map_err(|e| RvabError::io(&self.region.path, e))?;
+        let report = VerifyReport::from_index(slot, &manifest.index, &mut |entry| {
+            let position = manifest
+                .index
+                .entries
+                .iter()
+                .position(|x| x.name == entry.name)
+                .ok_or("Error: invalid manifest in chunk store")?;
+            self.expand_image(&sfile, entry, &manifest.chunks[position], None, None)
+        })?;
+        Ok(Some(report))
+    }
+
+    /// keep the gpt snapshot beside the firmware manifest of the slot
+    pub fn store_gpt(&mut self, slot_name: &str, gpt: &SlotGpt) -> Result<(), RvabError> {
+        let manifest = self
+            .manifests
+            .get_mut(slot_name)
+            .ok_or("Error: no firmware backup found")?;
+        manifest.gpt = gpt.to_bytes()?;
+        let tfile = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.region.path)
+            .map_err(|e| RvabError::io(&self.region.path, e))?;
+        self.commit(&tfile)
+    }
+
+    /// gpt snapshot of the slot, None if nothing is stored
+    pub fn read_gpt(&self, slot_name: &str) -> Result<Option<SlotGpt>, RvabError> {
+        match self.manifests.get(slot_name) {
+            Some(manifest) if !manifest.gpt.is_empty() => Ok(Some(
+                SlotGpt::from_bytes(&manifest.gpt)?
+                    .ok_or("Error: invalid gpt backup in chunk store")?,
+            )),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// bytes of a chunk store holding the firmware (all partitions not in ex_back_list) of two slots,
+/// identical chunks count once,the other slot may run a different firmware so it counts twice
+pub fn measure_chunk_store_size(ex_back_list: &HashSet<String>) -> Result<u64, RvabError> {
+    let mut unique = HashSet::new();
+    let mut buffer = vec![0; CHUNK_SIZE as usize];
+    for (name, _) in list_firmware_partitions(ex_back_list)? {
+        let (driver, _, first_lba, last_lba, sector_size) = get_part_accelerate_location(&name)?;
+        let file = File::open(&driver).map_err(|e| RvabError::io(&driver, e))?;
+        let length = (last_lba - first_lba + 1) * sector_size;
+        let mut done = 0;
+        while done < length {
+            let size = (length - done).min(CHUNK_SIZE) as usize;
+            file.read_exact_at(&mut buffer[..size], first_lba * sector_size + done)
+                .map_err(|e| RvabError::io(&driver, e))?;
+            done += size as u64;
+            if buffer[..size].iter().any(|&x| x != 0) {
+                unique.insert(<[u8; 32]>::from(Sha256::digest(&buffer[..size])));
+            };
+        }
+    }
+    let total = CHUNK_DATA_OFFSET + 2 * unique.len() as u64 * CHUNK_SIZE;
+    println!(
+        "chunk store size:{} , {} ({} unique chunks)",
+        total,
+        bytes2ieee(total),
+        unique.len()
+    );
+    Ok(total)
+}
+
+/// cursor over a table payload
+struct PayloadReader<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], RvabError> {
+        if length > self.buffer.len() {
+            return Err(RvabError::Backend("Error: chunk store table truncated"));
+        };
+        let (head, rest) = self.buffer.split_at(length);
+        self.buffer = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, RvabError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    const REGION_OFFSET: u64 = 4096;
+    const REGION_CHUNKS: u64 = 4;
+
+    /// firmware source and backup region files of a test, removed on drop
+    struct Fixture {
+        source: PathBuf,
+        region: BackupRegion,
+    }
+
+    impl Fixture {
+        /// source holds one chunk per pattern, 0 gives an all-zero chunk
+        fn new(name: &str, patterns: &[u8]) -> Self {
+            let dir = std::env::temp_dir();
+            let prefix = format!("rvab_chunk_{}_{}", name, std::process::id());
+            let source = dir.join(format!("{}.src", prefix));
+            let mut data = Vec::new();
+            for pattern in patterns {
+                data.extend(vec![*pattern; CHUNK_SIZE as usize]);
+            }
+            std::fs::write(&source, data).unwrap();
+            let region_path = dir.join(format!("{}.region", prefix));
+            let length = CHUNK_DATA_OFFSET + REGION_CHUNKS * CHUNK_SIZE;
+            File::create(&region_path)
+                .unwrap()
+                .set_len(REGION_OFFSET + length)
+                .unwrap();
+            Fixture {
+                source,
+                region: BackupRegion {
+                    path: region_path.display().to_string(),
+                    offset: REGION_OFFSET,
+                    length,
+                },
+            }
+        }
+
+        /// part of the source starting at chunk first, count chunks long
+        fn part(&self, name: &str, first: u64, count: u64) -> FirmwarePart {
+            FirmwarePart {
+                name: name.to_string(),
+                driver: self.source.display().to_string(),
+                offset: first * CHUNK_SIZE,
+                length: count * CHUNK_SIZE,
+                type_guid: Uuid::nil(),
+                flags: 0,
+                simg_length: None,
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.source);
+            let _ = std::fs::remove_file(&self.region.path);
+        }
+    }
+
+    fn metadata() -> Metadata {
+        let slot = |slot_name: &str| Slot {
+            slot_name: slot_name.to_string(),
+            backup_type_code: 0,
+            backup_target: String::new(),
+            backup_exclude_list: HashSet::new(),
+            backup_target_start: 0,
+            backup_target_end: 0,
+            backup_target_attr: "dedup".to_string(),
+            dyn_partition_set: HashMap::new(),
+            android_slot: String::new(),
+        };
+        Metadata::new(
+            "a".to_string(),
+            HashMap::from([("a".to_string(), slot("a")), ("b".to_string(), slot("b"))]),
+        )
+    }
+
+    fn sha256_of(pattern: u8) -> [u8; 32] {
+        Sha256::digest(vec![pattern; CHUNK_SIZE as usize]).into()
+    }
+
+    /// every stored image of the slot expands to its recorded sha256
+    fn assert_slot_intact(store: &ChunkStore, slot_name: &str) {
+        let file = File::open(&store.region.path).unwrap();
+        let manifest = &store.manifests[slot_name];
+        for (entry, ids) in manifest.index.entries.iter().zip(manifest.chunks.iter()) {
+            let sha256 = store.expand_image(&file, entry, ids, None, None).unwrap();
+            assert_eq!(sha256, entry.sha256, "{} of slot {}", entry.name, slot_name);
+        }
+    }
+
+    /// slot a: boot (0x22,0x11) and an all-zero modem, slot b: boot (0x11,0x33)
+    fn store_two_slots(fixture: &Fixture) -> ChunkStore {
+        let metadata = metadata();
+        let mut store = ChunkStore::open(&fixture.region).unwrap();
+        store
+            .store_firmware(
+                &metadata,
+                "a",
+                &[fixture.part("boot", 0, 2), fixture.part("modem", 3, 1)],
+            )
+            .unwrap();
+        store
+            .store_firmware(&metadata, "b", &[fixture.part("boot", 1, 2)])
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn shared_chunks_are_counted_once() {
+        let fixture = Fixture::new("shared", &[0x22, 0x11, 0x33, 0]);
+        store_two_slots(&fixture);
+        let store = ChunkStore::open(&fixture.region).unwrap();
+        let refcount = |pattern: u8| {
+            let record = store.chunks.iter().find(|x| x.sha256 == sha256_of(pattern));
+            record.map_or(0, |x| x.refcount)
+        };
+        assert_eq!(refcount(0x11), 2);
+        assert_eq!(refcount(0x22), 1);
+        assert_eq!(refcount(0x33), 1);
+        assert_eq!(store.chunks.iter().filter(|x| x.refcount != 0).count(), 3);
+        //nothing is stored for a zero chunk
+        assert_eq!(store.manifests["a"].chunks[1], vec![CHUNK_ZERO]);
+        assert_slot_intact(&store, "a");
+        assert_slot_intact(&store, "b");
+    }
+
+    #[test]
+    fn freed_chunks_are_reused() {
+        let fixture = Fixture::new("reuse", &[0x22, 0x11, 0x33, 0, 0x44, 0x55]);
+        let metadata = metadata();
+        let mut store = store_two_slots(&fixture);
+        let freed_id = store.manifests["b"].chunks[0][1];
+        assert_eq!(store.chunks[freed_id as usize].sha256, sha256_of(0x33));
+        //0x33 is only used by slot b, it is freed by the commit and not overwritten before
+        store
+            .store_firmware(&metadata, "b", &[fixture.part("boot", 4, 1)])
+            .unwrap();
+        assert_ne!(store.manifests["b"].chunks, vec![vec![freed_id]]);
+        assert_eq!(store.chunks[freed_id as usize].refcount, 0);
+        //the store is full but for the freed chunk, the next new chunk takes it
+        store
+            .store_firmware(&metadata, "a", &[fixture.part("boot", 5, 1)])
+            .unwrap();
+        let store = ChunkStore::open(&fixture.region).unwrap();
+        assert_eq!(store.manifests["a"].chunks, vec![vec![freed_id]]);
+        let record = store.chunks[freed_id as usize];
+        assert_eq!(record.sha256, sha256_of(0x55));
+        assert_eq!(record.refcount, 1);
+        assert_eq!(store.chunks.iter().filter(|x| x.refcount != 0).count(), 2);
+        assert_slot_intact(&store, "a");
+        assert_slot_intact(&store, "b");
+    }
+
+    #[test]
+    fn broken_newest_table_falls_back() {
+        let fixture = Fixture::new("fallback", &[0x22, 0x11, 0x33, 0]);
+        let store = store_two_slots(&fixture);
+        assert_eq!(store.sequence, 2);
+        //break the payload of the newest copy
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&fixture.region.path)
+            .unwrap();
+        let offset = fixture.region.offset
+            + store.copy * CHUNK_TABLE_COPY_SIZE
+            + CHUNK_TABLE_HEAD_LEN as u64;
+        file.write_all_at(&[0xff; 4], offset).unwrap();
+        let store = ChunkStore::open(&fixture.region).unwrap();
+        assert_eq!(store.sequence, 1);
+        assert!(store.manifests.contains_key("a"));
+        assert!(!store.manifests.contains_key("b"));
+        assert_slot_intact(&store, "a");
+    }
+
+    #[test]
+    fn chunk_id_out_of_range() {
+        let fixture = Fixture::new("range", &[0x22, 0x11, 0x33, 0]);
+        let mut store = store_two_slots(&fixture);
+        let manifest = store.manifests.get_mut("b").unwrap();
+        manifest.chunks[0][0] = REGION_CHUNKS as u32;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&fixture.region.path)
+            .unwrap();
+        store.commit(&file).unwrap();
+        assert!(matches!(
+            ChunkStore::open(&fixture.region),
+            Err(RvabError::Integrity(_))
+        ));
+    }
+}
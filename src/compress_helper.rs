@@ -0,0 +1,255 @@
+///compress helper module
+/// Stored image data may be compressed, selected by `compression=zstd[:level]|lz4|none` in
+/// `Slot::backup_target_attr`.
+/// Packed data is a list of blocks: raw length (u32),packed length (u32),packed bytes.
+/// Every block holds up to COMPRESS_BLOCK_SIZE bytes and is compressed on its own,
+/// so both the writer and the reader side only ever buffer a single block.
+/// Uncompressed data is stored as is, without blocks.
+use crate::config_helper::BackupTargetAttr;
+use std::io::Write;
+
+/// raw bytes per compressed block
+pub const COMPRESS_BLOCK_SIZE: usize = 1024 * 1024;
+const COMPRESS_BLOCK_HEAD_LEN: usize = 8;
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+/// Compression of stored image data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+    Lz4,
+}
+impl Compression {
+    pub fn code2compression(code: u32) -> Result<Compression, &'static str> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            _ => Err("Error: unknown compression in backup index"),
+        }
+    }
+    pub fn compression2code(compression: Compression) -> u32 {
+        match compression {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+}
+
+/// Compression selected for a slot, level only matters for zstd
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionSetting {
+    pub compression: Compression,
+    pub level: i32,
+}
+impl CompressionSetting {
+    pub fn none() -> Self {
+        CompressionSetting {
+            compression: Compression::None,
+            level: 0,
+        }
+    }
+
+    /// parse "none","lz4","zstd" or "zstd:<level>"
+    pub fn parse(value: &str) -> Result<Self, &'static str> {
+        let value = value.trim().to_lowercase();
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (value.as_str(), None),
+        };
+        let setting = match (name, level) {
+            ("none", None) => CompressionSetting::none(),
+            ("lz4", None) => CompressionSetting {
+                compression: Compression::Lz4,
+                level: 0,
+            },
+            ("zstd", level) => {
+                let level = match level {
+                    Some(level) => level
+                        .parse()
+                        .map_err(|_| "Error: invalid zstd compression level")?,
+                    None => ZSTD_DEFAULT_LEVEL,
+                };
+                if !zstd::compression_level_range().contains(&level) {
+                    return Err("Error: invalid zstd compression level");
+                };
+                CompressionSetting {
+                    compression: Compression::Zstd,
+                    level,
+                }
+            }
+            _ => return Err("Error: unknown compression in backup target attr"),
+        };
+        Ok(setting)
+    }
+
+    /// compression of a slot from its backup_target_attr, none if not set
+    pub fn from_attr(attr: &str) -> Result<Self, &'static str> {
+        match BackupTargetAttr::parse(attr).get("compression") {
+            Some(value) => CompressionSetting::parse(value),
+            None => Ok(CompressionSetting::none()),
+        }
+    }
+
+    /// attr item selecting this compression
+    pub fn attr_item(&self) -> String {
+        match self.compression {
+            Compression::None => "compression=none".to_string(),
+            Compression::Zstd => format!("compression=zstd:{}", self.level),
+            Compression::Lz4 => "compression=lz4".to_string(),
+        }
+    }
+}
+
+/// Packs a byte stream into blocks, feed with `push`, drain with `read_output`
+pub struct BlockPacker {
+    setting: CompressionSetting,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+}
+
+impl BlockPacker {
+    pub fn new(setting: CompressionSetting) -> Self {
+        BlockPacker {
+            setting,
+            input: Vec::with_capacity(COMPRESS_BLOCK_SIZE),
+            output: Vec::new(),
+            output_pos: 0,
+        }
+    }
+
+    /// take bytes of data until the block is full, return bytes taken
+    /// only call when there is no pending output
+    pub fn push(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let size = (COMPRESS_BLOCK_SIZE - self.input.len()).min(data.len());
+        self.input.extend_from_slice(&data[..size]);
+        if self.input.len() == COMPRESS_BLOCK_SIZE {
+            self.pack_block()?;
+        };
+        Ok(size)
+    }
+
+    /// pack the last (partial) block
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.pack_block()
+    }
+
+    pub fn has_output(&self) -> bool {
+        self.output_pos < self.output.len()
+    }
+
+    /// copy pending output into buf, return bytes copied
+    pub fn read_output(&mut self, buf: &mut [u8]) -> usize {
+        let size = (self.output.len() - self.output_pos).min(buf.len());
+        buf[..size].copy_from_slice(&self.output[self.output_pos..self.output_pos + size]);
+        self.output_pos += size;
+        size
+    }
+
+    fn pack_block(&mut self) -> std::io::Result<()> {
+        if self.input.is_empty() {
+            return Ok(());
+        };
+        self.output.clear();
+        self.output_pos = 0;
+        let packed = match self.setting.compression {
+            Compression::None => {
+                std::mem::swap(&mut self.output, &mut self.input);
+                return Ok(());
+            }
+            Compression::Zstd => zstd::bulk::compress(&self.input, self.setting.level)?,
+            Compression::Lz4 => lz4_flex::block::compress(&self.input),
+        };
+        self.output
+            .extend_from_slice(&(self.input.len() as u32).to_le_bytes());
+        self.output
+            .extend_from_slice(&(packed.len() as u32).to_le_bytes());
+        self.output.extend_from_slice(&packed);
+        self.input.clear();
+        Ok(())
+    }
+}
+
+/// Takes packed data and writes the unpacked bytes to inner,
+/// bytes behind packed_length (e.g. an extent table) are ignored
+pub struct BlockUnpacker<W: Write> {
+    inner: W,
+    compression: Compression,
+    remain: u64,
+    block: Vec<u8>,
+}
+
+impl<W: Write> BlockUnpacker<W> {
+    pub fn new(inner: W, compression: Compression, packed_length: u64) -> Self {
+        BlockUnpacker {
+            inner,
+            compression,
+            remain: packed_length,
+            block: Vec::new(),
+        }
+    }
+
+    /// return inner, fail if the packed data ended inside a block
+    pub fn finish(self) -> Result<W, &'static str> {
+        if self.remain != 0 || !self.block.is_empty() {
+            return Err("Error: compressed image truncated");
+        };
+        Ok(self.inner)
+    }
+
+    /// bytes still missing to complete the current block head or block
+    fn missing(&self) -> usize {
+        if self.block.len() < COMPRESS_BLOCK_HEAD_LEN {
+            return COMPRESS_BLOCK_HEAD_LEN - self.block.len();
+        };
+        let packed_length = u32::from_le_bytes(self.block[4..8].try_into().unwrap()) as usize;
+        COMPRESS_BLOCK_HEAD_LEN + packed_length - self.block.len()
+    }
+
+    fn unpack_block(&mut self) -> std::io::Result<()> {
+        let raw_length = u32::from_le_bytes(self.block[..4].try_into().unwrap()) as usize;
+        let packed = &self.block[COMPRESS_BLOCK_HEAD_LEN..];
+        if raw_length > COMPRESS_BLOCK_SIZE {
+            return Err(std::io::Error::other("invalid compressed block"));
+        };
+        let raw = match self.compression {
+            Compression::None => return Err(std::io::Error::other("invalid compressed block")),
+            Compression::Zstd => zstd::bulk::decompress(packed, raw_length)?,
+            Compression::Lz4 => lz4_flex::block::decompress(packed, raw_length)
+                .map_err(|_| std::io::Error::other("invalid compressed block"))?,
+        };
+        if raw.len() != raw_length {
+            return Err(std::io::Error::other("invalid compressed block"));
+        };
+        self.inner.write_all(&raw)?;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BlockUnpacker<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let usable = self.remain.min(buf.len() as u64) as usize;
+        let mut data = &buf[..usable];
+        if self.compression == Compression::None {
+            self.inner.write_all(data)?;
+            data = &[];
+        };
+        while !data.is_empty() {
+            let size = self.missing().min(data.len());
+            self.block.extend_from_slice(&data[..size]);
+            data = &data[size..];
+            if self.block.len() >= COMPRESS_BLOCK_HEAD_LEN && self.missing() == 0 {
+                self.unpack_block()?;
+            };
+        }
+        self.remain -= usable as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
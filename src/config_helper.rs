@@ -1 +1,43 @@
+///config helper module
+use std::collections::HashMap;
 
+/// Parsed `Slot::backup_target_attr`
+/// The attr is a list of `key=value` pairs separated by ',' , a key without value means "true"
+/// e.g. "user=lab,password=secret,passive=false"
+#[derive(Debug, Clone, Default)]
+pub struct BackupTargetAttr {
+    items: HashMap<String, String>,
+}
+
+impl BackupTargetAttr {
+    pub fn parse(attr: &str) -> Self {
+        let mut items = HashMap::new();
+        for item in attr.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match item.split_once('=') {
+                Some((key, value)) => {
+                    items.insert(key.trim().to_lowercase(), value.trim().to_string());
+                }
+                None => {
+                    items.insert(item.to_lowercase(), "true".to_string());
+                }
+            }
+        }
+        BackupTargetAttr { items }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.items.get(key).map(|x| x.as_str())
+    }
+
+    /// get a bool item, accept true/false,yes/no,on/off,1/0
+    pub fn get_bool(&self, key: &str, default: bool) -> Result<bool, &'static str> {
+        match self.get(key) {
+            None => Ok(default),
+            Some(value) => match value.to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(true),
+                "false" | "no" | "off" | "0" => Ok(false),
+                _ => Err("Error: invalid bool value in backup target attr"),
+            },
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use std::fs;
+use crate::device_env::device_env;
+use crate::error::RvabError;
 
 // pub const CONFIG_INIT_SLOT_NAME: &str = "slot_name";
 // pub const CONFIG_INIT_BACKUP_TYPE: &str = "backup_type";
@@ -24,26 +25,25 @@ pub const BLOCK_DEV_NAME_MAPPER: &str = "/dev/block/by-name/";
 pub const BLOCK_DEV_NAME_BOOT: &str = "/dev/block/bootdevice/by-name/";
 pub const BLOCK_DEV_NAME_PLATFORM: &str = "/dev/block/platform/soc/*/by-name/";
 pub const BLOCK_DEV_DIR: &str = "/dev/block/";
+/// androidboot.* parameters of the running boot, bootconfig replaces the cmdline since android 12
+pub const PROC_CMDLINE_PATH: &str = "/proc/cmdline";
+pub const PROC_BOOTCONFIG_PATH: &str = "/proc/bootconfig";
 
-pub const BACK_EXCLUDE_LIST: [&'static str; 1] = ["userdata"];
+/// matched by substring, "rvab_" covers metadata and backup partitions created by rvab
+pub const BACK_EXCLUDE_LIST: [&'static str; 2] = ["userdata", "rvab_"];
 pub const METADATA_PARTITION_NAME: &str = "rvab_metadata";
+/// name of backup partition is prefix + slot name
+pub const BACKUP_PARTITION_PREFIX: &str = "rvab_backup_";
+/// backup partition shared by all slots with dedup enabled
+pub const CHUNK_STORE_PARTITION_NAME: &str = "rvab_chunk_store";
+/// v1 metadata: toml between head and tail magic lines, crc32 at the segment tail
 pub const METADATA_HEAD_MAGIC: &'static str = "RVAB_HEAD_MAGIC";
 pub const METADATA_TAIL_MAGIC: &'static str = "RVAB_TAIL_MAGIC";
+/// v2 metadata: binary header starting with this magic, see `MetadataHeader`
+pub const METADATA_V2_MAGIC: &'static str = "RVAB_MD2";
+pub const JOURNAL_HEAD_MAGIC: &'static str = "RVAB_JOURNAL_MAGIC";
 
-/// Get the block device name mapper dir path
-/// panic if not found
-pub fn get_block_dev_dir() -> String {
-    let block_dev_dir = BLOCK_DEV_NAME_MAPPER;
-    if fs::metadata(block_dev_dir).is_ok() {
-        return block_dev_dir.to_string();
-    }
-    let block_dev_dir = BLOCK_DEV_NAME_BOOT;
-    if fs::metadata(block_dev_dir).is_ok() {
-        return block_dev_dir.to_string();
-    }
-    let block_dev_dir = BLOCK_DEV_NAME_PLATFORM;
-    if fs::metadata(block_dev_dir).is_ok() {
-        return block_dev_dir.to_string();
-    }
-    panic!("Error: android block dev name mapper dir not found");
+/// Get the block device name mapper dir path of the device env
+pub fn get_block_dev_dir() -> Result<String, RvabError> {
+    device_env().block_dev_dir()
 }
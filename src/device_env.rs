@@ -0,0 +1,364 @@
+///device environment module
+/// Everything rvab knows about block devices comes from a DeviceEnv:
+/// where the by-name partition links live, which disk holds a partition, sizes and sector sizes.
+/// AndroidEnv reads /dev/block and sysfs, ImageDirEnv serves a directory of raw gpt disk images
+/// so init/install/switch can be rehearsed off device.
+use crate::constants::*;
+use crate::error::RvabError;
+use gpt::{disk, GptConfig, GptDisk};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// description file of an image dir env
+pub const IMAGE_ENV_CONFIG_NAME: &str = "env.toml";
+
+pub trait DeviceEnv: Send + Sync {
+    /// dir of raw block device nodes, ends with '/'
+    fn block_dev_root(&self) -> String;
+    /// dir of by-name partition links, ends with '/'
+    fn block_dev_dir(&self) -> Result<String, RvabError>;
+    /// disk holding userdata
+    fn userdata_driver(&self) -> Result<String, RvabError>;
+    /// disk holding a partition, path can be link or device node
+    fn partition_main_driver(&self, path: &str) -> Result<String, RvabError>;
+    /// bytes of a disk or partition node
+    fn block_dev_size(&self, dev_path: &Path) -> Result<u64, RvabError>;
+    /// logical sector size of a disk
+    fn logical_block_size(&self, disk: &str) -> Result<u64, RvabError>;
+    /// physical sector size of a disk
+    fn physical_block_size(&self, disk: &str) -> Result<u64, RvabError>;
+    /// kernel cmdline and bootconfig of the running boot, missing ones are empty
+    fn boot_params(&self) -> String;
+}
+
+static DEVICE_ENV: OnceLock<Box<dyn DeviceEnv>> = OnceLock::new();
+
+/// Use env instead of the android device, must be set before any disk access
+pub fn set_device_env(env: Box<dyn DeviceEnv>) -> Result<(), RvabError> {
+    DEVICE_ENV
+        .set(env)
+        .map_err(|_| RvabError::config("device env already in use"))
+}
+
+/// Current device env, AndroidEnv unless another one was set
+pub fn device_env() -> &'static dyn DeviceEnv {
+    DEVICE_ENV.get_or_init(|| Box::new(AndroidEnv)).as_ref()
+}
+
+/// The real device, /dev/block and /sys/class/block
+pub struct AndroidEnv;
+
+impl AndroidEnv {
+    /// sysfs attribute of a block device
+    fn read_sysfs(dev_path: &Path, attr: &str) -> Result<u64, RvabError> {
+        let dev_name = dev_path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or("Error: invalid block dev path")?;
+        let path = format!("/sys/class/block/{}/{}", dev_name, attr);
+        let value = fs::read_to_string(&path).map_err(|e| RvabError::io(&path, e))?;
+        value
+            .trim()
+            .parse()
+            .map_err(|_| RvabError::Integrity(format!("invalid value in {}", path)))
+    }
+
+    /// parent disk node of a partition node, a whole disk node is its own parent
+    /// sysfs keeps partitions as /sys/devices/.../block/<disk>/<part>, each with a partition file
+    fn parent_disk(node: &Path) -> Result<PathBuf, RvabError> {
+        let name = node
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or("Error: invalid block dev path")?;
+        let sys_path = Path::new("/sys/class/block").join(name);
+        if !sys_path.join("partition").exists() {
+            if !sys_path.exists() {
+                return Err(RvabError::Backend("Error: block dev not found in sysfs"));
+            };
+            return Ok(node.to_path_buf());
+        };
+        let sys_path = fs::canonicalize(&sys_path)
+            .map_err(|e| RvabError::io(&sys_path.to_string_lossy(), e))?;
+        let disk_name = sys_path
+            .parent()
+            .and_then(|x| x.file_name())
+            .ok_or("Error: partition driver not found")?;
+        Ok(node.with_file_name(disk_name))
+    }
+}
+
+impl DeviceEnv for AndroidEnv {
+    fn block_dev_root(&self) -> String {
+        BLOCK_DEV_DIR.to_string()
+    }
+
+    fn block_dev_dir(&self) -> Result<String, RvabError> {
+        [
+            BLOCK_DEV_NAME_MAPPER,
+            BLOCK_DEV_NAME_BOOT,
+            BLOCK_DEV_NAME_PLATFORM,
+        ]
+        .iter()
+        .find(|x| fs::metadata(x).is_ok())
+        .map(|x| x.to_string())
+        .ok_or(RvabError::Backend(
+            "Error: android block dev name mapper dir not found",
+        ))
+    }
+
+    fn userdata_driver(&self) -> Result<String, RvabError> {
+        let userdata_link_path = [
+            USERDATA_LINK_PATH,
+            USERDATA_LINK_PATH_BOOT,
+            USERDATA_LINK_PATH_PLATFORM,
+        ]
+        .into_iter()
+        .find(|x| fs::metadata(x).is_ok())
+        .ok_or("Error: userdata driver not found")?;
+        self.partition_main_driver(userdata_link_path)
+    }
+
+    fn partition_main_driver(&self, path: &str) -> Result<String, RvabError> {
+        //by-name links may point to other links
+        let node = fs::canonicalize(path).map_err(|e| RvabError::io(path, e))?;
+        Ok(Self::parent_disk(&node)?.to_string_lossy().to_string())
+    }
+
+    fn block_dev_size(&self, dev_path: &Path) -> Result<u64, RvabError> {
+        Ok(Self::read_sysfs(dev_path, "size")? * 512)
+    }
+
+    fn logical_block_size(&self, disk: &str) -> Result<u64, RvabError> {
+        Self::read_sysfs(Path::new(disk), "queue/logical_block_size")
+    }
+
+    fn physical_block_size(&self, disk: &str) -> Result<u64, RvabError> {
+        Self::read_sysfs(Path::new(disk), "queue/physical_block_size")
+    }
+
+    fn boot_params(&self) -> String {
+        [PROC_CMDLINE_PATH, PROC_BOOTCONFIG_PATH]
+            .iter()
+            .filter_map(|x| fs::read_to_string(x).ok())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// A disk image of an image dir env, stored as <root>/block/<name>
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageLun {
+    /// device name,like sda or mmcblk0
+    pub name: String,
+    pub sector_size: u64,
+    /// defaults to sector_size
+    #[serde(default)]
+    pub physical_block_size: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageEnvConfig {
+    lun: Vec<ImageLun>,
+}
+
+/// A directory of raw gpt disk images standing in for the device.
+/// <root>/env.toml lists the luns,each one is the image <root>/block/<name>.
+/// Partition nodes (<root>/block/sda12,<root>/block/mmcblk0p12) are empty placeholder files,
+/// by-name links point to them, see `open` for when they are rebuilt.
+/// <root>/proc/cmdline and <root>/proc/bootconfig, if present, stand in for the running boot.
+pub struct ImageDirEnv {
+    root: PathBuf,
+    luns: Vec<ImageLun>,
+}
+
+impl ImageDirEnv {
+    /// Open the env and rebuild its nodes from the gpt tables like a reboot does:
+    /// <root>/block/by-name/ is deleted with everything in it and recreated,
+    /// partition nodes of old tables are removed.
+    /// Gpt changes made after opening are only seen by name once the env is opened again.
+    pub fn open(root: &str) -> Result<Self, RvabError> {
+        let root = PathBuf::from(root);
+        let config_path = root.join(IMAGE_ENV_CONFIG_NAME).display().to_string();
+        let config = fs::read_to_string(&config_path)
+            .map_err(|e| RvabError::Config(format!("unable to read {}: {}", config_path, e)))?;
+        let config: ImageEnvConfig = toml::from_str(&config)
+            .map_err(|e| RvabError::Config(format!("unable to parse {}: {}", config_path, e)))?;
+        if config.lun.is_empty() {
+            return Err(RvabError::Config(format!("no lun in {}", config_path)));
+        };
+        let env = ImageDirEnv {
+            root,
+            luns: config.lun,
+        };
+        for lun in &env.luns {
+            if lun.sector_size != 512 && lun.sector_size != 4096 {
+                return Err(RvabError::Config(format!(
+                    "unsupported sector size {} of lun {}",
+                    lun.sector_size, lun.name
+                )));
+            };
+            let lun_path = env.lun_path(lun).display().to_string();
+            let size = fs::metadata(&lun_path)
+                .map_err(|e| RvabError::io(&lun_path, e))?
+                .len();
+            if size == 0 || !size.is_multiple_of(lun.sector_size) {
+                return Err(RvabError::Config(format!(
+                    "size of {} is not a multiple of sector size",
+                    lun_path
+                )));
+            };
+        }
+        env.refresh_nodes()?;
+        Ok(env)
+    }
+
+    fn lun_path(&self, lun: &ImageLun) -> PathBuf {
+        self.root.join("block").join(&lun.name)
+    }
+
+    /// partition node name, a "p" separates lun names ending with a digit
+    fn node_name(lun: &ImageLun, id: u32) -> String {
+        if lun.name.ends_with(|x: char| x.is_ascii_digit()) {
+            format!("{}p{}", lun.name, id)
+        } else {
+            format!("{}{}", lun.name, id)
+        }
+    }
+
+    /// lun and partition id (None for the lun itself) of a node name
+    fn parse_node(&self, node: &str) -> Option<(&ImageLun, Option<u32>)> {
+        self.luns.iter().find_map(|lun| {
+            let rest = node.strip_prefix(lun.name.as_str())?;
+            if rest.is_empty() {
+                return Some((lun, None));
+            };
+            let digits = match lun.name.ends_with(|x: char| x.is_ascii_digit()) {
+                true => rest.strip_prefix('p')?,
+                false => rest,
+            };
+            Some((lun, Some(digits.parse().ok()?)))
+        })
+    }
+
+    /// lun of a disk path
+    fn find_lun(&self, disk: &str) -> Result<&ImageLun, RvabError> {
+        let name = Path::new(disk)
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or("Error: invalid block dev path")?;
+        match self.parse_node(name) {
+            Some((lun, None)) => Ok(lun),
+            _ => Err(RvabError::Backend("Error: disk not found in image env")),
+        }
+    }
+
+    fn open_lun_gpt(&self, lun: &ImageLun) -> Result<GptDisk<fs::File>, RvabError> {
+        let sector = match lun.sector_size {
+            4096 => disk::LogicalBlockSize::Lb4096,
+            _ => disk::LogicalBlockSize::Lb512,
+        };
+        let path = self.lun_path(lun);
+        GptConfig::new()
+            .writable(false)
+            .logical_block_size(sector)
+            .open(&path)
+            .map_err(|e| RvabError::Gpt(format!("open {} failed: {}", path.display(), e)))
+    }
+
+    /// recreate partition placeholder nodes and by-name links from the gpt tables
+    fn refresh_nodes(&self) -> Result<(), RvabError> {
+        let block_dir = self.root.join("block");
+        let by_name = block_dir.join("by-name");
+        let _ = fs::remove_dir_all(&by_name);
+        fs::create_dir_all(&by_name).map_err(|e| RvabError::io(&by_name.to_string_lossy(), e))?;
+        //drop placeholders of old partition tables
+        let entries =
+            fs::read_dir(&block_dir).map_err(|e| RvabError::io(&block_dir.to_string_lossy(), e))?;
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some((_, Some(_))) = self.parse_node(&name) {
+                fs::remove_file(entry.path())
+                    .map_err(|e| RvabError::io(&entry.path().to_string_lossy(), e))?;
+            };
+        }
+        for lun in &self.luns {
+            let disk = self.open_lun_gpt(lun)?;
+            for (id, part) in disk.partitions() {
+                let node = block_dir.join(Self::node_name(lun, *id));
+                fs::File::create(&node).map_err(|e| RvabError::io(&node.to_string_lossy(), e))?;
+                let link = by_name.join(&part.name);
+                std::os::unix::fs::symlink(&node, &link)
+                    .map_err(|e| RvabError::io(&link.to_string_lossy(), e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DeviceEnv for ImageDirEnv {
+    fn block_dev_root(&self) -> String {
+        format!("{}/", self.root.join("block").display())
+    }
+
+    fn block_dev_dir(&self) -> Result<String, RvabError> {
+        Ok(format!("{}/", self.root.join("block/by-name").display()))
+    }
+
+    fn userdata_driver(&self) -> Result<String, RvabError> {
+        self.partition_main_driver(&format!("{}{}", self.block_dev_dir()?, USERDATA_NAME))
+    }
+
+    fn partition_main_driver(&self, path: &str) -> Result<String, RvabError> {
+        fs::metadata(path).map_err(|e| RvabError::io(path, e))?;
+        let node = fs::read_link(path).unwrap_or_else(|_| PathBuf::from(path));
+        let name = node
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or("Error: partition driver not found")?;
+        let (lun, _) = self
+            .parse_node(name)
+            .ok_or("Error: partition driver not found")?;
+        Ok(self.lun_path(lun).to_string_lossy().to_string())
+    }
+
+    fn block_dev_size(&self, dev_path: &Path) -> Result<u64, RvabError> {
+        let name = dev_path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or("Error: invalid block dev path")?;
+        let (lun, id) = self
+            .parse_node(name)
+            .ok_or("Error: block dev not found in image env")?;
+        let Some(id) = id else {
+            let lun_path = self.lun_path(lun).display().to_string();
+            return fs::metadata(&lun_path)
+                .map(|x| x.len())
+                .map_err(|e| RvabError::io(&lun_path, e));
+        };
+        let disk = self.open_lun_gpt(lun)?;
+        let part = disk
+            .partitions()
+            .get(&id)
+            .ok_or("Error: partition not found")?;
+        Ok((part.last_lba - part.first_lba + 1) * lun.sector_size)
+    }
+
+    fn logical_block_size(&self, disk: &str) -> Result<u64, RvabError> {
+        Ok(self.find_lun(disk)?.sector_size)
+    }
+
+    fn physical_block_size(&self, disk: &str) -> Result<u64, RvabError> {
+        let lun = self.find_lun(disk)?;
+        Ok(lun.physical_block_size.unwrap_or(lun.sector_size))
+    }
+
+    fn boot_params(&self) -> String {
+        ["proc/cmdline", "proc/bootconfig"]
+            .iter()
+            .filter_map(|x| fs::read_to_string(self.root.join(x)).ok())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
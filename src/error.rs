@@ -0,0 +1,75 @@
+///error module
+/// Public functions of lib.rs, gpt_helper.rs and metadata.rs return RvabError instead of panicking.
+/// Backup backends, chunk store and journal return RvabError too,
+/// a failed open/read/write/sync is reported as RvabError::Io with the path it failed on,
+/// their remaining static messages become RvabError::Backend.
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum RvabError {
+    /// i/o failure on a file, block device or ftp server path
+    Io { path: String, source: io::Error },
+    /// gpt table can not be read, changed or written
+    Gpt(String),
+    /// config file, metadata or device env is invalid
+    Config(String),
+    /// partitions or regions overlap, or do not fit on the disk
+    Layout(String),
+    /// the user refused to continue
+    Cancelled,
+    /// data does not match its magic, checksum or recorded size
+    Integrity(String),
+    /// failure reported by a backup backend or another internal module
+    Backend(&'static str),
+}
+
+impl RvabError {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        RvabError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+    pub fn gpt(msg: &str) -> Self {
+        RvabError::Gpt(msg.to_string())
+    }
+    pub fn config(msg: &str) -> Self {
+        RvabError::Config(msg.to_string())
+    }
+    pub fn layout(msg: &str) -> Self {
+        RvabError::Layout(msg.to_string())
+    }
+    pub fn integrity(msg: &str) -> Self {
+        RvabError::Integrity(msg.to_string())
+    }
+}
+
+impl fmt::Display for RvabError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RvabError::Io { path, source } => write!(f, "Error: {}: {}", path, source),
+            RvabError::Gpt(msg) => write!(f, "Error: gpt: {}", msg),
+            RvabError::Config(msg) => write!(f, "Error: config: {}", msg),
+            RvabError::Layout(msg) => write!(f, "Error: layout: {}", msg),
+            RvabError::Cancelled => write!(f, "Error: cancelled by user"),
+            RvabError::Integrity(msg) => write!(f, "Error: integrity: {}", msg),
+            RvabError::Backend(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for RvabError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RvabError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<&'static str> for RvabError {
+    fn from(msg: &'static str) -> Self {
+        RvabError::Backend(msg)
+    }
+}
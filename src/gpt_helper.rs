@@ -1,52 +1,24 @@
-use crate::backup_factory::{BackupTrait, BackupType};
+use crate::backup_factory::{measure_firmware_stored_size, BackupTrait, BackupType};
+use crate::chunk_store::measure_chunk_store_size;
+use crate::compress_helper::{Compression, CompressionSetting};
 use crate::constants::*;
+use crate::device_env::device_env;
+use crate::error::RvabError;
 use crate::math_support::*;
 use crate::metadata::*;
 use gpt::GptConfig;
 use gpt::{disk, GptDisk};
 use log::debug;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{Read, Seek, Write};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 use uuid::Uuid;
 
 /// Get the userdata driver path
-/// ## panic if not found
-pub fn get_userdata_driver() -> String {
-    let emmc_path = EMMC_TRAIT_FILE;
-    if fs::metadata(emmc_path).is_ok() {
-        //emmc
-        return emmc_path.to_string();
-    }
-    let mut userdata_link_path = USERDATA_LINK_PATH;
-    if fs::metadata(userdata_link_path).is_ok() == false {
-        //
-        userdata_link_path = USERDATA_LINK_PATH_BOOT;
-    }
-    if fs::metadata(userdata_link_path).is_ok() == false {
-        //
-        userdata_link_path = USERDATA_LINK_PATH_PLATFORM;
-    }
-    if fs::metadata(userdata_link_path).is_ok() == false {
-        panic!("Error: userdata driver not found");
-    }
-    // Get the target of the symbolic link
-    let target_path = match fs::read_link(userdata_link_path) {
-        Ok(target) => target.to_str().unwrap().to_string(),
-        Err(e) => {
-            panic!("Error reading symbolic link: {:?}", e);
-        }
-    };
-    // Iterate from sda to sdz
-    for c in 'a'..='z' {
-        let potential_path = format!("/dev/block/sd{}", c);
-
-        if target_path.contains(&potential_path) {
-            return potential_path.to_string();
-        }
-    }
-    panic!("Error: userdata driver not found");
+pub fn get_userdata_driver() -> Result<String, RvabError> {
+    device_env().userdata_driver()
 }
 
 /// Find the largest free space tuple,args (start_lba,length_lba) return (start_lba, END_lba) in the disk
@@ -64,13 +36,13 @@ pub fn find_max_free_tuple(tuples: &Vec<(u64, u64)>) -> (u64, u64) {
     (max_start, max_end)
 }
 
-/// Auto layout for two slot
+/// Auto layout for two slot, return (slot1,slot2,backup target min size in sectors)
 /// Default space strategy: peace split
 /// Default backuptype: test order: losetup,partition,binaryspace
 /// Default backup_target: follow the userdata
-/// ## panic if no backup type available or no enough space
-/// ## panic if get part accelerate location failed
-/// ## panic if get dual part info failed
+/// With dedup both slots share one backup region behind the two halves
+/// Dyn partitions on other luns stay on their own lun, see `plan_lun_dyn_partitions`
+/// Also return sectors used by the layout on every lun
 pub fn auto_layout_freespace_example(
     target_disk: &str,
     start_lba: u64,
@@ -78,31 +50,18 @@ pub fn auto_layout_freespace_example(
     sector: u64,
     ex_back_fpath: &Option<String>,
     dual_list: &Option<String>,
-    _back_min_size_sector: &mut u64,
-) -> (Slot, Slot) {
-    //half split
-    let mut end_lba = end_lba;
-    let mut start_lba = start_lba;
-    let p1_size = (end_lba - start_lba + 1) / 2;
-    let p2_size = (end_lba - start_lba + 1) - p1_size;
-
-    let mut p1_end = start_lba + p1_size - 1;
-
-    let part_alignment = compute_alignment(target_disk);
+    compression: CompressionSetting,
+    dedup: bool,
+) -> Result<(Slot, Slot, u64, Vec<LunUsage>), RvabError> {
+    let (region_start, region_end) = (start_lba, end_lba);
+    let part_alignment = compute_alignment(target_disk)?;
     println!("part_alignment:{}", part_alignment);
-    alignment_partition(&mut start_lba, &mut p1_end, part_alignment, true);
-
-    let mut p2_start = p1_end + 1;
-    alignment_partition(&mut p2_start, &mut end_lba, part_alignment, true);
-
-    let mut p1_used_pointer = start_lba;
-    let mut p2_used_pointer = p2_start;
     // exclude file list
     let mut exclude_files = HashSet::new();
     let mut dual_files = HashSet::new();
     if let Some(path) = ex_back_fpath {
         //read exclude file and add into exclude list
-        let file = fs::read_to_string(path).expect("Error reading exclude list file");
+        let file = fs::read_to_string(path).map_err(|e| RvabError::io(path, e))?;
         let files: Vec<&str> = file
             .lines()
             .map(|line| line.trim())
@@ -114,7 +73,7 @@ pub fn auto_layout_freespace_example(
     };
     if let Some(path) = dual_list {
         //read exclude file and add into exclude list
-        let file = fs::read_to_string(path).expect("Error reading dual list file");
+        let file = fs::read_to_string(path).map_err(|e| RvabError::io(path, e))?;
         let files: Vec<&str> = file
             .lines()
             .map(|line| line.trim())
@@ -125,7 +84,19 @@ pub fn auto_layout_freespace_example(
             dual_files.insert(item.to_string());
         }
     }
-    let (_fw_num, fw_size) = calculate_firmware_size(&exclude_files);
+    calculate_firmware_size(&exclude_files)?;
+    //images are stored sparse and compressed, size the backup target by the measured size
+    //dedup slots keep the unique chunks of both slots in one chunk store
+    let fw_size = if dedup {
+        if compression.compression != Compression::None {
+            return Err(RvabError::config(
+                "dedup keeps chunks uncompressed, do not combine it with compression",
+            ));
+        };
+        measure_chunk_store_size(&exclude_files)?
+    } else {
+        measure_firmware_stored_size(&exclude_files, compression)?
+    };
 
     //test partition backup
     let mut backup_type = BackupType::Losetup;
@@ -136,11 +107,46 @@ pub fn auto_layout_freespace_example(
         backup_type = BackupType::BinarySpace;
     }
     if let Err(_) = backup_type.test(None) {
-        panic!("Error: no backup type available");
+        return Err(RvabError::config("no backup type available"));
     }
     let back_min_size_sector =
         backup_type.guess_backup_target_partition_size_sector(fw_size, sector);
-    *_back_min_size_sector = back_min_size_sector;
+
+    //dedup slots share one backup region behind both halves,it is the same in either gpt
+    let mut end_lba = end_lba;
+    let mut shared_region = None;
+    if dedup {
+        let shared_start =
+            (end_lba + 1).saturating_sub(back_min_size_sector) / part_alignment * part_alignment;
+        if shared_start <= start_lba {
+            return Err(RvabError::Layout(format!(
+                "no enough space for shared backup target {}",
+                bytes2ieee(back_min_size_sector * sector)
+            )));
+        };
+        shared_region = Some((shared_start, end_lba));
+        end_lba = shared_start - 1;
+    };
+    //backup sectors taken from the end of each half
+    let slot_backup_sectors = match shared_region {
+        Some(_) => 0,
+        None => back_min_size_sector,
+    };
+
+    //half split
+    let mut start_lba = start_lba;
+    let p1_size = (end_lba - start_lba + 1) / 2;
+    let p2_size = (end_lba - start_lba + 1) - p1_size;
+
+    let mut p1_end = start_lba + p1_size - 1;
+
+    alignment_partition(&mut start_lba, &mut p1_end, part_alignment, true);
+
+    let mut p2_start = p1_end + 1;
+    alignment_partition(&mut p2_start, &mut end_lba, part_alignment, true);
+
+    let mut p1_used_pointer = start_lba;
+    let mut p2_used_pointer = p2_start;
 
     //construct slots
 
@@ -148,9 +154,16 @@ pub fn auto_layout_freespace_example(
     let mut map1 = HashMap::new();
     let mut map2 = HashMap::new();
 
+    let mut dual_files: Vec<String> = dual_files.into_iter().collect();
+    dual_files.sort();
+    let mut lun_parts: BTreeMap<String, Vec<String>> = BTreeMap::new();
     for part_name in dual_files {
-        let (_driver, _id, first_lba, last_lba, sector_size) =
-            get_part_accelerate_location(&part_name).unwrap();
+        let (driver, _id, first_lba, last_lba, sector_size) =
+            get_part_accelerate_location(&part_name)?;
+        if driver != target_disk {
+            lun_parts.entry(driver).or_default().push(part_name);
+            continue;
+        };
 
         let length_bytes = (last_lba - first_lba + 1) * sector_size;
         let mut length_lba = length_bytes / sector;
@@ -162,7 +175,7 @@ pub fn auto_layout_freespace_example(
         let mut dyn_end_lba = dyn_start_lba + length_lba - 1;
         alignment_partition(&mut dyn_start_lba, &mut dyn_end_lba, part_alignment, true);
         p1_used_pointer = dyn_end_lba + 1;
-        let (type_guid, flags) = get_part_info(&part_name).unwrap();
+        let (type_guid, flags) = get_part_info(&part_name)?;
         let part = PartitionRawTarget {
             part_name: part_name.clone(),
             driver: target_disk.to_string(),
@@ -200,28 +213,33 @@ pub fn auto_layout_freespace_example(
     p2_used_pointer = userdata2_start_lba;
 
     let backup_target = target_disk.to_string();
+    let backup_target_attr = match compression.compression {
+        Compression::None if dedup => "dedup=true".to_string(),
+        Compression::None => "".to_string(),
+        _ => compression.attr_item(),
+    };
     //test space layout is correct for partition backup and binaryspace backup and losetup backup
-    if back_min_size_sector > p2_size {
-        panic!(
-            "Error: no enough space for backup target {}",
+    if slot_backup_sectors > p2_size {
+        return Err(RvabError::Layout(format!(
+            "no enough space for backup target {}",
             bytes2ieee(back_min_size_sector * sector)
-        );
+        )));
     }
-    if (userdata1_start_lba + (USERDATA_MIN_SIZE / sector) + back_min_size_sector) > p1_end {
-        panic!("Error: no enough space for userdata1");
+    if (userdata1_start_lba + (USERDATA_MIN_SIZE / sector) + slot_backup_sectors) > p1_end {
+        return Err(RvabError::layout("no enough space for userdata1"));
     };
-    if (userdata2_start_lba + (USERDATA_MIN_SIZE / sector) + back_min_size_sector) > end_lba {
-        panic!("Error: no enough space for userdata2");
+    if (userdata2_start_lba + (USERDATA_MIN_SIZE / sector) + slot_backup_sectors) > end_lba {
+        return Err(RvabError::layout("no enough space for userdata2"));
     };
     //userdata1
-    let mut userdata1_end = p1_end - back_min_size_sector;
+    let mut userdata1_end = p1_end - slot_backup_sectors;
     alignment_partition(
         &mut userdata1_start_lba,
         &mut userdata1_end,
         part_alignment,
         true,
     );
-    let (type_guid, flags) = get_part_info(&USERDATA_NAME.to_string()).unwrap();
+    let (type_guid, flags) = get_part_info(&USERDATA_NAME.to_string())?;
     let userdata1 = PartitionRawTarget {
         part_name: USERDATA_NAME.to_string(),
         driver: target_disk.to_string(),
@@ -230,20 +248,21 @@ pub fn auto_layout_freespace_example(
         type_guid: type_guid.clone(),
         flags,
     };
-    let mut backup1_start = userdata1_end + 1;
+    let (backup1_start, backup1_end) = shared_region.unwrap_or((userdata1_end + 1, p1_end));
     map1.insert(USERDATA_NAME.to_string(), userdata1);
-    let slot1: Slot = Slot {
+    let mut slot1: Slot = Slot {
         slot_name: "a".to_string(),
         backup_type_code: BackupType::type2code(backup_type.clone()),
         backup_target: backup_target.clone(),
         backup_exclude_list: exclude_files.clone(),
         backup_target_start: backup1_start,
-        backup_target_end: p1_end,
-        backup_target_attr: "".to_string(),
+        backup_target_end: backup1_end,
+        backup_target_attr: backup_target_attr.clone(),
         dyn_partition_set: map1,
+        android_slot: String::new(),
     };
     //userdata2
-    let mut userdata2_end = end_lba - back_min_size_sector;
+    let mut userdata2_end = end_lba - slot_backup_sectors;
     alignment_partition(
         &mut userdata2_start_lba,
         &mut userdata2_end,
@@ -259,30 +278,153 @@ pub fn auto_layout_freespace_example(
 
         flags,
     };
-    let mut backup2_start = userdata2_end + 1;
+    let (backup2_start, backup2_end) = shared_region.unwrap_or((userdata2_end + 1, end_lba));
     map2.insert(USERDATA_NAME.to_string(), userdata2);
-    let slot2: Slot = Slot {
+    let mut slot2: Slot = Slot {
         slot_name: "b".to_string(),
         backup_type_code: BackupType::type2code(backup_type),
         backup_target,
         backup_exclude_list: exclude_files,
         backup_target_start: backup2_start,
-        backup_target_end: end_lba,
-        backup_target_attr: "".to_string(),
+        backup_target_end: backup2_end,
+        backup_target_attr: backup_target_attr.clone(),
         dyn_partition_set: map2,
+        android_slot: String::new(),
     };
-    (slot1, slot2)
+    let mut lun_usage = vec![LunUsage {
+        driver: target_disk.to_string(),
+        sector_size: sector,
+        used: [p1_end - start_lba + 1, end_lba - p2_start + 1],
+        shared: shared_region.map_or(0, |(start, end)| end - start + 1),
+        free: disk_free_sectors(target_disk, |&(first, length)| {
+            first + length <= region_start || first > region_end
+        })?,
+    }];
+    for (driver, parts) in lun_parts {
+        lun_usage.push(plan_lun_dyn_partitions(
+            &driver,
+            &parts,
+            &mut slot1.dyn_partition_set,
+            &mut slot2.dyn_partition_set,
+        )?);
+    }
+    Ok((slot1, slot2, back_min_size_sector, lun_usage))
+}
+
+/// Sectors used by a planned layout on a lun
+#[derive(Debug, Clone)]
+pub struct LunUsage {
+    pub driver: String,
+    pub sector_size: u64,
+    /// sectors used by slot a and slot b
+    pub used: [u64; 2],
+    /// sectors of the backup region shared by dedup slots
+    pub shared: u64,
+    /// free sectors left untouched by the layout
+    pub free: u64,
+}
+
+/// free sectors of a disk, only count free segments (first_lba,length) accepted by filter
+fn disk_free_sectors(disk: &str, filter: impl Fn(&(u64, u64)) -> bool) -> Result<u64, RvabError> {
+    let disk = get_gpt_disk(disk, false)?;
+    Ok(disk
+        .find_free_sectors()
+        .iter()
+        .filter(|x| filter(x))
+        .map(|(_, length)| length)
+        .sum())
+}
+
+/// Plan dyn partitions on a lun other than the userdata lun,
+/// the bootloader may look for them on this lun so they never leave it:
+/// slot a keeps each partition in place, slot b gets a copy in free space of the same lun
+fn plan_lun_dyn_partitions(
+    driver: &str,
+    parts: &[String],
+    map1: &mut HashMap<String, PartitionRawTarget>,
+    map2: &mut HashMap<String, PartitionRawTarget>,
+) -> Result<LunUsage, RvabError> {
+    let disk = get_gpt_disk(driver, false)?;
+    let alignment = compute_alignment(driver)?;
+    let mut free_segments = disk.find_free_sectors();
+    let mut usage = LunUsage {
+        driver: driver.to_string(),
+        sector_size: disk.logical_block_size().as_u64(),
+        used: [0, 0],
+        shared: 0,
+        free: 0,
+    };
+    for part_name in parts {
+        let (_, _, first_lba, last_lba, _) = get_part_accelerate_location(part_name)?;
+        let (type_guid, flags) = get_part_info(part_name)?;
+        let length_lba = last_lba - first_lba + 1;
+        //first fit into an aligned free segment
+        let (i, start_lba) = free_segments
+            .iter()
+            .enumerate()
+            .find_map(|(i, (first, length))| {
+                let start_lba = first.next_multiple_of(alignment);
+                (start_lba + length_lba <= first + length).then_some((i, start_lba))
+            })
+            .ok_or_else(|| {
+                RvabError::Layout(format!("no enough space on {} for {}", driver, part_name))
+            })?;
+        let (first, length) = free_segments[i];
+        free_segments[i] = (
+            start_lba + length_lba,
+            first + length - start_lba - length_lba,
+        );
+        if start_lba > first {
+            free_segments.push((first, start_lba - first));
+        };
+        let target = |start_lba: u64| PartitionRawTarget {
+            part_name: part_name.clone(),
+            driver: driver.to_string(),
+            start_lba,
+            end_lba: start_lba + length_lba - 1,
+            type_guid: type_guid.clone(),
+            flags,
+        };
+        map1.insert(part_name.clone(), target(first_lba));
+        map2.insert(part_name.clone(), target(start_lba));
+        usage.used[0] += length_lba;
+        usage.used[1] += length_lba;
+    }
+    usage.free = free_segments.iter().map(|(_, length)| length).sum();
+    Ok(usage)
 }
 
 /// Calculate the size of the firmwares,return in (total_num,total_bytes)
 /// include all physical partitions under block/by-name/ except userdata
-/// ## panic if block device dir access error occurs or total size=0
-pub fn calculate_firmware_size(ex_back_list: &HashSet<String>) -> (u64, u64) {
+pub fn calculate_firmware_size(ex_back_list: &HashSet<String>) -> Result<(u64, u64), RvabError> {
     let mut firmware_size: u64 = 0;
-    let dev_dir = get_block_dev_dir();
     let mut total_num = 0;
-    let files = fs::read_dir(&dev_dir).unwrap();
+    let firmwares = list_firmware_partitions(ex_back_list)?;
+    for (_, target_file) in firmwares {
+        firmware_size += read_block_dev_size(&target_file)?;
+        total_num += 1;
+        //println!("{} firmware_size:{}", target_file.to_str().unwrap(), bytes2ieee(&firmware_size));
+    }
+    if firmware_size == 0 {
+        return Err(RvabError::layout("firmware size=0"));
+    }
+    println!(
+        "firmware_size:{} , {}",
+        firmware_size,
+        bytes2ieee(firmware_size)
+    );
+    Ok((total_num, firmware_size))
+}
+
+/// List the firmware partitions,return vec of (part_name,block_dev_node)
+/// include all physical partitions under block/by-name/ except userdata and ex_back_list
+pub fn list_firmware_partitions(
+    ex_back_list: &HashSet<String>,
+) -> Result<Vec<(String, PathBuf)>, RvabError> {
+    let dev_dir = get_block_dev_dir()?;
+    let files = fs::read_dir(&dev_dir).map_err(|e| RvabError::io(&dev_dir, e))?;
     let mut exclude_files = get_block_dev_filenames();
+    let mut firmwares = Vec::new();
     // merge ex_back_list into exclude_files
 
     for item in ex_back_list {
@@ -290,82 +432,44 @@ pub fn calculate_firmware_size(ex_back_list: &HashSet<String>) -> (u64, u64) {
     }
 
     for file in files {
-        let path = file.unwrap().path();
-        let path_str = path.to_str().unwrap();
-        let filename = &path.file_name().unwrap().to_str().unwrap().to_string();
+        let path = file.map_err(|e| RvabError::io(&dev_dir, e))?.path();
+        let path_str = path.to_str().unwrap_or_default();
+        let filename = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default()
+            .to_string();
         let global_exclude_files = &BACK_EXCLUDE_LIST;
         // Skip if the file is in the exclude list
-        if exclude_files.contains(filename)
+        if exclude_files.contains(&filename)
             || global_exclude_files.iter().any(|&x| filename.contains(x))
         {
             println!("skip excluded file {}", &path_str);
             continue;
         };
-        let metadata = fs::metadata(&path).unwrap();
+        let metadata = fs::metadata(&path).map_err(|e| RvabError::io(path_str, e))?;
         //TODO guess skip subdir, usually no subdir
         if metadata.is_dir() {
             //println!("skip subdir {}", &path_str);
             continue;
         }
         //TODO skip non-symbloic link , std not work
-        let target_file = fs::read_link(&path).unwrap();
-
-        firmware_size += read_block_dev_size(&target_file);
-        total_num += 1;
-        //println!("{} firmware_size:{}", target_file.to_str().unwrap(), bytes2ieee(&firmware_size));
-    }
-    if firmware_size == 0 {
-        panic!("Error: firmware size=0");
+        let target_file = fs::read_link(&path).map_err(|e| RvabError::io(path_str, e))?;
+        firmwares.push((filename, target_file));
     }
-    println!(
-        "firmware_size:{} , {}",
-        firmware_size,
-        bytes2ieee(firmware_size)
-    );
-    (total_num, firmware_size)
+    firmwares.sort();
+    Ok(firmwares)
 }
 
-/// read block device size via linux kernel, return bytes
-/// by reading /sys/class/block/dev_node/size
-pub fn read_block_dev_size(dev_path: &PathBuf) -> u64 {
-    let dev_name = dev_path.file_name().unwrap().to_str().unwrap();
-    //read size
-    let size_path = format!("/sys/class/block/{}/size", dev_name);
-    let size_str = fs::read_to_string(size_path).expect("Error reading size file");
-    let size: u64 = size_str.trim().parse().expect("Error parsing size");
-    size * 512
+/// read block device size via the device env, return bytes
+/// on device by reading /sys/class/block/dev_node/size
+pub fn read_block_dev_size(dev_path: &PathBuf) -> Result<u64, RvabError> {
+    device_env().block_dev_size(dev_path)
 }
 
 /// get partition main driver, path can be link or real device
-pub fn get_partition_main_driver(spath: &str) -> Result<String, &'static str> {
-    let emmc_path = EMMC_TRAIT_FILE;
-    if fs::metadata(emmc_path).is_ok() {
-        //emmc
-        return Ok(emmc_path.to_string());
-    }
-
-    let path = Path::new(spath);
-    if fs::metadata(path).is_ok() == false {
-        return Err("Error: no such partition file");
-    }
-    let mut dev_node;
-    match nix::fcntl::readlink(path) {
-        Ok(node) => {
-            dev_node = node.to_str().unwrap().to_string();
-        }
-        Err(_) => {
-            dev_node = spath.to_string();
-        }
-    }
-    // Iterate from sda to sdz
-    for c in 'a'..='z' {
-        let potential_path = format!("/dev/block/sd{}", c);
-
-        if dev_node.contains(&potential_path) {
-            return Ok(potential_path.to_string());
-        }
-    }
-    return Err("Error: partition driver not found");
+pub fn get_partition_main_driver(spath: &str) -> Result<String, RvabError> {
+    device_env().partition_main_driver(spath)
 }
 
 ///Takes a size and converts this to a size in IEEE-1541-2002 units (KiB, MiB, GiB, TiB, PiB, or EiB),precision 1
@@ -382,7 +486,7 @@ pub fn bytes2ieee(size: u64) -> String {
 
 /// get  all block dev filenam list
 pub fn get_block_dev_filenames() -> HashSet<String> {
-    let path = BLOCK_DEV_DIR;
+    let path = device_env().block_dev_root();
     let mut exclude_files = HashSet::new();
     if let Ok(entries) = fs::read_dir(path) {
         for entry in entries {
@@ -405,114 +509,65 @@ pub fn get_block_dev_filenames() -> HashSet<String> {
 /// get part accelerate location via gpt table,return (main_driver,id_num,first_lba,last_lba,sector_size)
 pub fn get_part_accelerate_location(
     part_name: &str,
-) -> Result<(String, u32, u64, u64, u64), &'static str> {
-    let path = format!("{}{}", get_block_dev_dir(), part_name);
+) -> Result<(String, u32, u64, u64, u64), RvabError> {
+    let path = format!("{}{}", get_block_dev_dir()?, part_name);
     let disk_path = get_partition_main_driver(&path)?;
-    let sector_size = get_disk_sector_size(&disk_path);
-    let mut sector = disk::LogicalBlockSize::Lb512;
-    if sector_size == 4096 {
-        sector = disk::LogicalBlockSize::Lb4096;
-    } else if sector_size == 512 {
-    } else {
-        return Err("Error: unsupported sector size !!!");
-    };
-    let gptcfg = GptConfig::new()
-        .writable(false)
-        .logical_block_size(sector.clone());
-    let mut disk = gptcfg.open(&disk_path);
-    if !(disk.is_ok()) {
-        return Err("Error: open disk failed");
-    }
-    let binding_disk = disk.unwrap();
-    let (id, part) = binding_disk
+    let sector_size = get_disk_sector_size(&disk_path)?;
+    let disk = get_gpt_disk(&disk_path, false)?;
+    let (id, part) = disk
         .partitions()
         .iter()
         .find(|(_, partition)| partition.name == part_name)
-        .ok_or("Error: partition not found")?;
+        .ok_or_else(|| RvabError::Gpt(format!("partition {} not found", part_name)))?;
     Ok((disk_path, *id, part.first_lba, part.last_lba, sector_size))
 }
 
 /// get part info ,return (type_guid_str,flags)
-/// ## panic if unsupported sector size
-pub fn get_part_info(part_name: &String) -> Option<(String, u64)> {
-    let path = format!("{}{}", get_block_dev_dir(), part_name);
-    let disk_path = get_partition_main_driver(&path).ok()?;
-    let mut sector = try_get_disk_lba(&disk_path);
-    let gptcfg = GptConfig::new()
-        .writable(false)
-        .logical_block_size(sector.clone());
-    let mut disk = gptcfg.open(&disk_path);
-    if !(disk.is_ok()) {
-        return None;
-    }
-    let binding_disk = disk.unwrap();
-    let ret = binding_disk
+pub fn get_part_info(part_name: &String) -> Result<(String, u64), RvabError> {
+    let path = format!("{}{}", get_block_dev_dir()?, part_name);
+    let disk_path = get_partition_main_driver(&path)?;
+    let disk = get_gpt_disk(&disk_path, false)?;
+    let (_, part) = disk
         .partitions()
         .iter()
-        .find(|(_, partition)| partition.name == *part_name);
-    if ret.is_none() {
-        return None;
-    }
-    let (id, part) = ret.unwrap();
-    Some((part.part_type_guid.guid.to_string(), part.flags))
+        .find(|(_, partition)| partition.name == *part_name)
+        .ok_or_else(|| RvabError::Gpt(format!("partition {} not found", part_name)))?;
+    Ok((part.part_type_guid.guid.to_string(), part.flags))
 }
 
 /// get disk sector size
-/// ## panic if any error occurs
-pub fn get_disk_sector_size(disk: &str) -> u64 {
-    let path = Path::new(disk);
-    let disk_name = path.file_name().unwrap().to_str().unwrap();
-    let path = format!("/sys/class/block/{}/queue/logical_block_size", disk_name);
-    let size_str = fs::read_to_string(path).expect("Error reading size file");
-    let size: u64 = size_str.trim().parse().expect("Error parsing size");
-    size
+pub fn get_disk_sector_size(disk: &str) -> Result<u64, RvabError> {
+    device_env().logical_block_size(disk)
 }
 
 /// get disk gpt table
-pub fn get_gpt_disk(disk: &str, write_able: bool) -> Option<GptDisk<fs::File>> {
-    let sector = try_get_disk_lba(disk);
-    let gptcfg = GptConfig::new()
+pub fn get_gpt_disk(disk: &str, write_able: bool) -> Result<GptDisk<fs::File>, RvabError> {
+    let sector = try_get_disk_lba(disk)?;
+    GptConfig::new()
         .writable(write_able)
-        .logical_block_size(sector);
-    let disk = gptcfg.open(disk);
-    if disk.is_err() {
-        return None;
-    }
-    Some(disk.unwrap())
+        .logical_block_size(sector)
+        .open(disk)
+        .map_err(|e| RvabError::Gpt(format!("open {} failed: {}", disk, e)))
 }
 /// try get disk lba
-/// ## panic if unsupported sector size
-pub fn try_get_disk_lba(disk: &str) -> disk::LogicalBlockSize {
-    let sector_size = get_disk_sector_size(disk);
-    let mut sector = disk::LogicalBlockSize::Lb512;
-    if sector_size == 4096 {
-        sector = disk::LogicalBlockSize::Lb4096;
-    } else if sector_size == 512 {
-    } else {
-        panic!("Error: unsupported sector size !!!")
-    };
-    sector
+pub fn try_get_disk_lba(disk: &str) -> Result<disk::LogicalBlockSize, RvabError> {
+    match get_disk_sector_size(disk)? {
+        512 => Ok(disk::LogicalBlockSize::Lb512),
+        4096 => Ok(disk::LogicalBlockSize::Lb4096),
+        _ => Err(RvabError::Gpt(format!(
+            "unsupported sector size of {}",
+            disk
+        ))),
+    }
 }
 
 /// check if disk segment is used by table,return Option<Vec<(part_name,id)>>
-/// ## panic if any error occurs
 pub fn is_disk_segment_used(
     disk: &str,
     start_lba: u64,
     end_lba: u64,
-) -> Option<Vec<(String, u32)>> {
-    let sector_size = get_disk_sector_size(disk);
-    let mut sector = disk::LogicalBlockSize::Lb512;
-    if sector_size == 4096 {
-        sector = disk::LogicalBlockSize::Lb4096;
-    } else if sector_size == 512 {
-    } else {
-        panic!("Error: unsupported sector size !!!")
-    };
-    let gptcfg = GptConfig::new()
-        .writable(false)
-        .logical_block_size(sector.clone());
-    let mut disk = gptcfg.open(disk).expect("open disk failed");
+) -> Result<Option<Vec<(String, u32)>>, RvabError> {
+    let disk = get_gpt_disk(disk, false)?;
     let mut find_part_name = Vec::new();
     //check if given segment is used by some partitions,if any part of the segment is used,add it to the list
     for (id, partition) in disk.partitions().iter() {
@@ -525,9 +580,18 @@ pub fn is_disk_segment_used(
         }
     }
     if find_part_name.is_empty() {
-        return None;
+        return Ok(None);
     };
-    Some(find_part_name)
+    Ok(Some(find_part_name))
+}
+
+/// check if disk segment (inclusive) lies completely in free space of gpt
+pub fn is_disk_segment_free(disk: &str, start_lba: u64, end_lba: u64) -> Result<bool, RvabError> {
+    let disk = get_gpt_disk(disk, false)?;
+    Ok(disk
+        .find_free_sectors()
+        .iter()
+        .any(|(start, length)| start_lba >= *start && end_lba < *start + *length))
 }
 
 /// align partition lba (only shrink)
@@ -547,42 +611,24 @@ pub fn alignment_partition(
     };
 }
 
-/// get disk partitions alignment via sysfs
-/// ## panic if file read error or parse error , (rarely occurs)
-pub fn get_disk_part_boundary_alignment(disk: &str) -> u32 {
+/// get disk partitions alignment via the device env
+pub fn get_disk_part_boundary_alignment(disk: &str) -> Result<u32, RvabError> {
     // cal via physical block size / logical block size
-    let path = Path::new(disk);
-    let disk_name = path.file_name().unwrap().to_str().unwrap();
-    let path = format!("/sys/class/block/{}/queue/physical_block_size", disk_name);
-    let size_str = fs::read_to_string(path).expect("Error reading size file");
-    let phy_size: u32 = size_str.trim().parse().expect("Error parsing size");
-    let path = format!("/sys/class/block/{}/queue/logical_block_size", disk_name);
-    let size_str = fs::read_to_string(path).expect("Error reading size file");
-    let log_size: u32 = size_str.trim().parse().expect("Error parsing size");
-    phy_size / log_size
+    let env = device_env();
+    let phy_size = env.physical_block_size(disk)?;
+    let log_size = env.logical_block_size(disk)?;
+    Ok((phy_size / log_size) as u32)
 }
 
 /// delete partition by name
-/// ## panic if unsupported sector size
-pub fn delete_part_by_name(part_name: &str) -> Result<(), &'static str> {
-    let (main_driver, id, _, _, sector_bytes) = get_part_accelerate_location(part_name)?;
+pub fn delete_part_by_name(part_name: &str) -> Result<(), RvabError> {
+    let (main_driver, id, _, _, _) = get_part_accelerate_location(part_name)?;
     //delete partition
-    let mut sector = disk::LogicalBlockSize::Lb512;
-    if sector_bytes == 4096 {
-        sector = disk::LogicalBlockSize::Lb4096;
-    } else if sector_bytes == 512 {
-    } else {
-        panic!("Error: unsupported sector size !!!");
-    };
-    let gptcfg = GptConfig::new()
-        .writable(true)
-        .logical_block_size(sector.clone());
-    let mut disk = gptcfg
-        .open(&main_driver)
-        .map_err(|_| "Error: open disk failed")?;
+    let mut disk = get_gpt_disk(&main_driver, true)?;
     disk.remove_partition(id)
-        .ok_or_else(|| "Error: remove partition failed")?;
-    disk.write().map_err(|_| "Error: write disk failed")?;
+        .ok_or_else(|| RvabError::Gpt(format!("remove partition {} failed", part_name)))?;
+    disk.write()
+        .map_err(|e| RvabError::Gpt(format!("write {} failed: {}", main_driver, e)))?;
     Ok(())
 }
 
@@ -592,9 +638,6 @@ pub fn delete_part_by_name(part_name: &str) -> Result<(), &'static str> {
 /// a specific length_lba
 /// a specific part_type
 /// a specific flags
-/// ## Panics
-/// If length is empty panics
-/// If id zero panics
 pub fn new_partition(
     disk: &mut GptDisk<fs::File>,
     name: &str,
@@ -603,13 +646,15 @@ pub fn new_partition(
     length_lba: u64,
     part_type: gpt::partition_types::Type,
     flags: u64,
-) -> Result<u32, gpt::GptError> {
-    assert!(length_lba > 0, "length must be greater than zero");
-    assert!(id > 0, "id must be greater than zero");
+) -> Result<u32, RvabError> {
+    if length_lba == 0 || id == 0 {
+        return Err(RvabError::gpt("length and id must be greater than zero"));
+    };
     //check id
-    match disk.take_partitions().get(&id) {
-        // TODO err type
-        Some(p) if p.is_used() => return Err(gpt::GptError::OverflowPartitionCount),
+    match disk.partitions().get(&id) {
+        Some(p) if p.is_used() => {
+            return Err(RvabError::Gpt(format!("partition id {} is used", id)))
+        }
         // Allow unused ids , because we can allow to modify the part count
         None => {}
         _ => {
@@ -645,13 +690,17 @@ pub fn new_partition(
                 debug!("Replacing\n{}\nwith\n{}", p, part);
                 eprintln!("Partition overwrite !!!");
             }
-            disk.update_partitions(partitions)?;
+            disk.update_partitions(partitions)
+                .map_err(|e| RvabError::Gpt(format!("update partitions failed: {}", e)))?;
             return Ok(id);
         }
     }
 
     //given segment is illegal
-    Err(gpt::GptError::NotEnoughSpace)
+    Err(RvabError::Layout(format!(
+        "no free space for partition {} at lba {}",
+        name, first_lba
+    )))
 }
 
 /// clone disk segment , in bytes
@@ -664,45 +713,51 @@ pub fn clone_disk_segment(
     toffset: u64,
     tlength: u64,
     allow_override_part: bool,
-) -> Result<(), &'static str> {
+) -> Result<(), RvabError> {
     if slength != tlength {
-        return Err("Error: source and target length must be equal");
+        return Err(RvabError::layout("source and target length must be equal"));
     };
-    let tsector = get_disk_sector_size(tdisk);
+    let tsector = get_disk_sector_size(tdisk)?;
     let tstart_lba = toffset / tsector;
     let tend_lba = (toffset + tlength - 1 + tsector) / tsector;
-    let override_list = is_disk_segment_used(tdisk, tstart_lba, tend_lba);
-    if override_list.is_some() && !allow_override_part {
-        return Err("Error: target segment is used by some partitions");
+    let override_list = is_disk_segment_used(tdisk, tstart_lba, tend_lba)?;
+    if let Some(parts) = override_list {
+        if !allow_override_part {
+            return Err(RvabError::Layout(format!(
+                "target segment is used by {:?}",
+                parts
+            )));
+        };
     };
     let sfile = fs::OpenOptions::new()
         .read(true)
         .open(sdisk)
-        .map_err(|_| "Error: open source disk failed")?;
+        .map_err(|e| RvabError::io(sdisk, e))?;
     let tfile = fs::OpenOptions::new()
         .write(true)
         .open(tdisk)
-        .map_err(|_| "Error: open target disk failed")?;
+        .map_err(|e| RvabError::io(tdisk, e))?;
     let mut sfile = io::BufReader::new(sfile);
     let mut tfile = io::BufWriter::new(tfile);
     sfile
         .seek(io::SeekFrom::Start(soffset))
-        .map_err(|_| "Error: seek source disk failed")?;
+        .map_err(|e| RvabError::io(sdisk, e))?;
     tfile
         .seek(io::SeekFrom::Start(toffset))
-        .map_err(|_| "Error: seek target disk failed")?;
+        .map_err(|e| RvabError::io(tdisk, e))?;
     let mut buffer = vec![0; 4096];
     let mut remain = slength;
     while remain > 0 {
         let read_size = if remain > 4096 { 4096 } else { remain as usize };
         sfile
             .read_exact(&mut buffer[..read_size])
-            .map_err(|_| "Error: read source disk failed")?;
+            .map_err(|e| RvabError::io(sdisk, e))?;
         tfile
             .write_all(&buffer[..read_size])
-            .map_err(|_| "Error: write target disk failed")?;
+            .map_err(|e| RvabError::io(tdisk, e))?;
         remain -= read_size as u64;
     }
+    tfile.flush().map_err(|e| RvabError::io(tdisk, e))?;
     Ok(())
 }
 /// Compute sector alignment based on the current partitions (if any). Each
@@ -719,9 +774,8 @@ pub fn clone_disk_segment(
 /// is used on big disks (as safety for Advanced Format drives).
 /// Returns the computed alignment value.
 /// Ported from gptfdisk's gpt.cc
-/// ## panic if unable to calculate alignment
-pub fn compute_alignment(disk_path: &str) -> u64 {
-    let mut disk = get_gpt_disk(disk_path, false).expect("Error: open disk failed");
+pub fn compute_alignment(disk_path: &str) -> Result<u64, RvabError> {
+    let mut disk = get_gpt_disk(disk_path, false)?;
     const DEFAULT_ALIGNMENT: u64 = 2048;
     const MIN_AF_ALIGNMENT: u64 = 8;
     // Below constant corresponds to a ~279GiB (300GB) disk, since the
@@ -756,5 +810,54 @@ pub fn compute_alignment(disk_path: &str) -> u64 {
     if align < MIN_AF_ALIGNMENT && disk_fake_size_lba >= SMALLEST_ADVANCED_FORMAT {
         align = MIN_AF_ALIGNMENT;
     }
-    align
+    Ok(align)
+}
+
+/// Raw bytes of primary gpt (protective mbr,header,entries) and backup gpt (entries,header) of a disk
+#[derive(Debug, Clone)]
+pub struct RawGpt {
+    pub primary_offset: u64,
+    pub primary: Vec<u8>,
+    pub backup_offset: u64,
+    pub backup: Vec<u8>,
+}
+
+/// dump raw primary and backup gpt of a disk
+/// primary covers lba 0 to first_usable-1, backup covers last_usable+1 to backup_lba
+pub fn dump_raw_gpt(disk_path: &str) -> Result<RawGpt, RvabError> {
+    let disk = get_gpt_disk(disk_path, false)?;
+    let sector_size = disk.logical_block_size().as_u64();
+    let header = disk
+        .primary_header()
+        .map_err(|_| RvabError::Gpt(format!("no primary gpt header on {}", disk_path)))?;
+    let primary_length = header.first_usable * sector_size;
+    let backup_offset = (header.last_usable + 1) * sector_size;
+    let backup_length = (header.backup_lba + 1) * sector_size - backup_offset;
+    let file = fs::File::open(disk_path).map_err(|e| RvabError::io(disk_path, e))?;
+    let mut primary = vec![0; primary_length as usize];
+    file.read_exact_at(&mut primary, 0)
+        .map_err(|e| RvabError::io(disk_path, e))?;
+    let mut backup = vec![0; backup_length as usize];
+    file.read_exact_at(&mut backup, backup_offset)
+        .map_err(|e| RvabError::io(disk_path, e))?;
+    Ok(RawGpt {
+        primary_offset: 0,
+        primary,
+        backup_offset,
+        backup,
+    })
+}
+
+/// write raw primary and backup gpt back to a disk
+pub fn write_raw_gpt(disk_path: &str, raw_gpt: &RawGpt) -> Result<(), RvabError> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(disk_path)
+        .map_err(|e| RvabError::io(disk_path, e))?;
+    file.write_all_at(&raw_gpt.primary, raw_gpt.primary_offset)
+        .map_err(|e| RvabError::io(disk_path, e))?;
+    file.write_all_at(&raw_gpt.backup, raw_gpt.backup_offset)
+        .map_err(|e| RvabError::io(disk_path, e))?;
+    file.sync_all().map_err(|e| RvabError::io(disk_path, e))?;
+    Ok(())
 }
@@ -0,0 +1,522 @@
+use crate::constants::JOURNAL_HEAD_MAGIC;
+///switch journal module
+/// A write-ahead journal stored in the hidden metadata segment of every slot,
+/// next to the toml metadata blob. It records the phase of a running switch together with
+/// raw snapshots of every gpt the switch may touch, so that an interrupted switch can be
+/// finished or rolled back later by `rvab recover`.
+use crate::error::RvabError;
+use crate::gpt_helper::{dump_raw_gpt, write_raw_gpt, RawGpt};
+use crate::metadata::{Metadata, METADATA_JOURNAL_OFFSET, METADATA_JOURNAL_SIZE};
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+
+/// journal record (head magic,payload length,crc32,toml payload) lives in the first 1MiB
+/// raw gpt snapshots live behind it
+const JOURNAL_RECORD_SIZE: u64 = 1024 * 1024;
+
+/// phases of a switch, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum JournalPhase {
+    /// journal written, nothing changed yet
+    Started,
+    /// firmware of source slot is stored in its backup target
+    FirmwareBackedUp,
+    /// gpt tables are being rewritten, see `rewritten_luns`
+    GptRewriting,
+    /// all gpt tables rewritten to the target slot layout
+    GptRewritten,
+    /// firmware of target slot is restored
+    FirmwareRestored,
+    /// new current slot is committed to all metadata
+    MetadataCommitted,
+}
+impl fmt::Display for JournalPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                JournalPhase::Started => "started",
+                JournalPhase::FirmwareBackedUp => "firmware backed up",
+                JournalPhase::GptRewriting => "gpt rewriting",
+                JournalPhase::GptRewritten => "gpt rewritten",
+                JournalPhase::FirmwareRestored => "firmware restored",
+                JournalPhase::MetadataCommitted => "metadata committed",
+            }
+        )
+    }
+}
+
+/// location of a raw gpt snapshot inside the journal segment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GptSnapshot {
+    pub driver: String,
+    pub primary_offset: u64,
+    pub primary_length: u64,
+    pub backup_offset: u64,
+    pub backup_length: u64,
+    //offset relative to the snapshot area
+    pub data_offset: u64,
+    pub crc32: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwitchJournal {
+    //increased on every write, the newest valid record wins
+    pub sequence: u64,
+    pub from_slot: String,
+    pub to_slot: String,
+    pub phase: JournalPhase,
+    pub rewritten_luns: Vec<String>,
+    pub snapshots: Vec<GptSnapshot>,
+    #[serde(skip)]
+    raw_gpts: HashMap<String, RawGpt>,
+}
+impl fmt::Display for SwitchJournal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Switch: {} -> {}\nPhase: {}\nSequence: {}\nRewritten LUNs: {:?}\n",
+            self.from_slot, self.to_slot, self.phase, self.sequence, self.rewritten_luns
+        )
+    }
+}
+
+impl SwitchJournal {
+    pub fn new(from_slot: &str, to_slot: &str) -> Self {
+        SwitchJournal {
+            sequence: 0,
+            from_slot: from_slot.to_string(),
+            to_slot: to_slot.to_string(),
+            phase: JournalPhase::Started,
+            rewritten_luns: Vec::new(),
+            snapshots: Vec::new(),
+            raw_gpts: HashMap::new(),
+        }
+    }
+
+    /// take raw gpt snapshots of the given disks, they are written with the next record
+    pub fn snapshot_gpts<'a>(
+        &mut self,
+        drivers: impl Iterator<Item = &'a String>,
+    ) -> Result<(), RvabError> {
+        self.snapshots.clear();
+        self.raw_gpts.clear();
+        for driver in drivers {
+            self.push_snapshot(driver, dump_raw_gpt(driver)?)?;
+        }
+        Ok(())
+    }
+
+    /// add a raw gpt snapshot behind the last one
+    fn push_snapshot(&mut self, driver: &str, raw_gpt: RawGpt) -> Result<(), RvabError> {
+        let data_offset = self
+            .snapshots
+            .last()
+            .map_or(0, |x| x.data_offset + x.primary_length + x.backup_length);
+        let mut hasher = Hasher::new();
+        hasher.update(&raw_gpt.primary);
+        hasher.update(&raw_gpt.backup);
+        let snapshot = GptSnapshot {
+            driver: driver.to_string(),
+            primary_offset: raw_gpt.primary_offset,
+            primary_length: raw_gpt.primary.len() as u64,
+            backup_offset: raw_gpt.backup_offset,
+            backup_length: raw_gpt.backup.len() as u64,
+            data_offset,
+            crc32: hasher.finalize(),
+        };
+        if JOURNAL_RECORD_SIZE
+            + snapshot.data_offset
+            + snapshot.primary_length
+            + snapshot.backup_length
+            > METADATA_JOURNAL_SIZE
+        {
+            return Err(RvabError::Backend(
+                "Error: gpt snapshots overflow journal segment",
+            ));
+        };
+        self.snapshots.push(snapshot);
+        self.raw_gpts.insert(driver.to_string(), raw_gpt);
+        Ok(())
+    }
+
+    /// record a new phase and write journal to all metadata segments
+    pub fn commit_phase(
+        &mut self,
+        metadata: &Metadata,
+        phase: JournalPhase,
+    ) -> Result<(), RvabError> {
+        self.phase = phase;
+        self.write(metadata)
+    }
+
+    /// record a rewritten lun (gpt) and write journal to all metadata segments
+    pub fn commit_rewritten_lun(
+        &mut self,
+        metadata: &Metadata,
+        driver: &str,
+    ) -> Result<(), RvabError> {
+        if self.rewritten_luns.iter().any(|x| x == driver) {
+            return Ok(());
+        };
+        self.rewritten_luns.push(driver.to_string());
+        self.write(metadata)
+    }
+
+    /// (disk,byte offset) of the journal in every metadata segment
+    fn locations(metadata: &Metadata) -> Result<Vec<(String, u64)>, RvabError> {
+        Ok(Metadata::get_all_metadata_location(metadata)?
+            .into_iter()
+            .map(|(main_driver, _, start_lba, _, sector_size)| {
+                (
+                    main_driver,
+                    start_lba * sector_size + METADATA_JOURNAL_OFFSET,
+                )
+            })
+            .collect())
+    }
+
+    /// write journal record (and snapshots if any in ram) to all metadata segments
+    pub fn write(&mut self, metadata: &Metadata) -> Result<(), RvabError> {
+        self.write_to(&Self::locations(metadata)?)
+    }
+
+    fn write_to(&mut self, locations: &[(String, u64)]) -> Result<(), RvabError> {
+        self.sequence += 1;
+        let toml_str =
+            toml::to_string(&self).map_err(|_| "Error: Failed to convert journal to toml str")?;
+        let mut hasher = Hasher::new();
+        hasher.update(toml_str.as_bytes());
+        let checksum = hasher.finalize();
+        let mut record = Vec::new();
+        record.extend_from_slice(JOURNAL_HEAD_MAGIC.as_bytes());
+        record.extend_from_slice(&(toml_str.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum.to_le_bytes());
+        record.extend_from_slice(toml_str.as_bytes());
+        if record.len() as u64 > JOURNAL_RECORD_SIZE {
+            return Err(RvabError::Backend("Error: journal size overflow"));
+        };
+
+        for (main_driver, offset) in locations {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(main_driver)
+                .map_err(|e| RvabError::io(main_driver, e))?;
+            for snapshot in self.snapshots.iter() {
+                if let Some(raw_gpt) = self.raw_gpts.get(&snapshot.driver) {
+                    let data_offset = offset + JOURNAL_RECORD_SIZE + snapshot.data_offset;
+                    file.write_all_at(&raw_gpt.primary, data_offset)
+                        .map_err(|e| RvabError::io(main_driver, e))?;
+                    file.write_all_at(&raw_gpt.backup, data_offset + snapshot.primary_length)
+                        .map_err(|e| RvabError::io(main_driver, e))?;
+                };
+            }
+            file.write_all_at(&record, *offset)
+                .map_err(|e| RvabError::io(main_driver, e))?;
+            file.sync_all().map_err(|e| RvabError::io(main_driver, e))?;
+        }
+        //snapshots only need to be written once
+        self.raw_gpts.clear();
+        Ok(())
+    }
+
+    /// read the newest valid journal from all metadata segments
+    /// return None if there is no interrupted switch
+    pub fn read(metadata: &Metadata) -> Result<Option<Self>, RvabError> {
+        Self::read_from(&Self::locations(metadata)?)
+    }
+
+    fn read_from(locations: &[(String, u64)]) -> Result<Option<Self>, RvabError> {
+        let mut newest: Option<(SwitchJournal, &String, File, u64)> = None;
+        for (main_driver, offset) in locations {
+            let file = File::open(main_driver).map_err(|e| RvabError::io(main_driver, e))?;
+            let journal = match SwitchJournal::read_record(&file, *offset) {
+                Some(journal) => journal,
+                None => continue,
+            };
+            if newest
+                .as_ref()
+                .is_none_or(|(x, _, _, _)| x.sequence < journal.sequence)
+            {
+                newest = Some((journal, main_driver, file, *offset));
+            }
+        }
+        let (mut journal, main_driver, file, offset) = match newest {
+            Some(newest) => newest,
+            None => return Ok(None),
+        };
+        //load snapshots, a broken one is left out, see `has_all_snapshots`
+        for snapshot in journal.snapshots.iter() {
+            let data_offset = offset + JOURNAL_RECORD_SIZE + snapshot.data_offset;
+            let mut primary = vec![0; snapshot.primary_length as usize];
+            let mut backup = vec![0; snapshot.backup_length as usize];
+            file.read_exact_at(&mut primary, data_offset)
+                .map_err(|e| RvabError::io(main_driver, e))?;
+            file.read_exact_at(&mut backup, data_offset + snapshot.primary_length)
+                .map_err(|e| RvabError::io(main_driver, e))?;
+            let mut hasher = Hasher::new();
+            hasher.update(&primary);
+            hasher.update(&backup);
+            if hasher.finalize() != snapshot.crc32 {
+                println!(
+                    "Warning: gpt snapshot of {} in journal is broken, ignored",
+                    snapshot.driver
+                );
+                continue;
+            };
+            journal.raw_gpts.insert(
+                snapshot.driver.clone(),
+                RawGpt {
+                    primary_offset: snapshot.primary_offset,
+                    primary,
+                    backup_offset: snapshot.backup_offset,
+                    backup,
+                },
+            );
+        }
+        Ok(Some(journal))
+    }
+
+    fn read_record(file: &File, offset: u64) -> Option<Self> {
+        let magic_len = JOURNAL_HEAD_MAGIC.len();
+        let mut head = vec![0; magic_len + 8];
+        file.read_exact_at(&mut head, offset).ok()?;
+        if &head[..magic_len] != JOURNAL_HEAD_MAGIC.as_bytes() {
+            return None;
+        };
+        let length = u32::from_le_bytes(head[magic_len..magic_len + 4].try_into().ok()?);
+        let crc32 = u32::from_le_bytes(head[magic_len + 4..magic_len + 8].try_into().ok()?);
+        if length as u64 > JOURNAL_RECORD_SIZE {
+            return None;
+        };
+        let mut payload = vec![0; length as usize];
+        file.read_exact_at(&mut payload, offset + head.len() as u64)
+            .ok()?;
+        let mut hasher = Hasher::new();
+        hasher.update(&payload);
+        if hasher.finalize() != crc32 {
+            eprintln!("Warning: found journal record with crc32 mismatch, ignored");
+            return None;
+        };
+        toml::from_str(std::str::from_utf8(&payload).ok()?).ok()
+    }
+
+    /// true if the gpt snapshot of every recorded disk was read back
+    pub fn has_all_snapshots(&self) -> bool {
+        self.snapshots
+            .iter()
+            .all(|x| self.raw_gpts.contains_key(&x.driver))
+    }
+
+    /// write all gpt snapshots back to their disks
+    pub fn rollback_gpts(&self) -> Result<(), RvabError> {
+        let mut all_fine = true;
+        for (driver, raw_gpt) in self.raw_gpts.iter() {
+            if write_raw_gpt(driver, raw_gpt).is_err() {
+                println!("Terrible!!!: restore disk {} failed", driver);
+                all_fine = false;
+            };
+        }
+        if !all_fine {
+            return Err(RvabError::Backend("Error: rollback gpt failed"));
+        };
+        Ok(())
+    }
+
+    /// erase journal record from all metadata segments
+    pub fn clear(metadata: &Metadata) -> Result<(), RvabError> {
+        let blank = vec![0; JOURNAL_HEAD_MAGIC.len()];
+        for (main_driver, offset) in Self::locations(metadata)? {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&main_driver)
+                .map_err(|e| RvabError::io(&main_driver, e))?;
+            file.write_all_at(&blank, offset)
+                .map_err(|e| RvabError::io(&main_driver, e))?;
+            file.sync_all()
+                .map_err(|e| RvabError::io(&main_driver, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpt::{disk::LogicalBlockSize, mbr::ProtectiveMBR, partition_types, GptConfig};
+    use std::path::PathBuf;
+
+    /// image file with room for two journal segments, removed on drop
+    struct Segments {
+        path: PathBuf,
+    }
+
+    impl Segments {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rvab_journal_{}_{}", name, std::process::id()));
+            File::create(&path)
+                .unwrap()
+                .set_len(2 * METADATA_JOURNAL_SIZE)
+                .unwrap();
+            Segments { path }
+        }
+
+        fn locations(&self) -> Vec<(String, u64)> {
+            let path = self.path.display().to_string();
+            vec![(path.clone(), 0), (path, METADATA_JOURNAL_SIZE)]
+        }
+
+        fn flip_byte(&self, offset: u64) {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&self.path)
+                .unwrap();
+            let mut byte = [0];
+            file.read_exact_at(&mut byte, offset).unwrap();
+            file.write_all_at(&[!byte[0]], offset).unwrap();
+        }
+    }
+
+    impl Drop for Segments {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn raw_gpt(pattern: u8) -> RawGpt {
+        RawGpt {
+            primary_offset: 0,
+            primary: vec![pattern; 34 * 512],
+            backup_offset: 1024 * 1024,
+            backup: vec![!pattern; 33 * 512],
+        }
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let segments = Segments::new("round_trip");
+        let locations = segments.locations();
+        assert!(SwitchJournal::read_from(&locations).unwrap().is_none());
+        let mut journal = SwitchJournal::new("a", "b");
+        journal.write_to(&locations).unwrap();
+        journal.phase = JournalPhase::GptRewriting;
+        journal.rewritten_luns.push("/dev/block/sda".to_string());
+        journal.write_to(&locations).unwrap();
+        let read = SwitchJournal::read_from(&locations).unwrap().unwrap();
+        assert_eq!(read.sequence, 2);
+        assert_eq!((read.from_slot.as_str(), read.to_slot.as_str()), ("a", "b"));
+        assert_eq!(read.phase, JournalPhase::GptRewriting);
+        assert_eq!(read.rewritten_luns, vec!["/dev/block/sda".to_string()]);
+
+        //a newer record in one segment wins
+        journal.phase = JournalPhase::FirmwareRestored;
+        journal.write_to(&locations[1..]).unwrap();
+        let read = SwitchJournal::read_from(&locations).unwrap().unwrap();
+        assert_eq!(read.sequence, 3);
+        assert_eq!(read.phase, JournalPhase::FirmwareRestored);
+
+        //unless it is broken
+        segments.flip_byte(METADATA_JOURNAL_SIZE + JOURNAL_HEAD_MAGIC.len() as u64 + 8);
+        let read = SwitchJournal::read_from(&locations).unwrap().unwrap();
+        assert_eq!(read.sequence, 2);
+        assert_eq!(read.phase, JournalPhase::GptRewriting);
+    }
+
+    #[test]
+    fn broken_snapshot_is_skipped() {
+        let segments = Segments::new("snapshot");
+        let locations = segments.locations();
+        let mut journal = SwitchJournal::new("a", "b");
+        journal
+            .push_snapshot("/dev/block/sda", raw_gpt(0x11))
+            .unwrap();
+        journal
+            .push_snapshot("/dev/block/sdb", raw_gpt(0x22))
+            .unwrap();
+        journal.write_to(&locations[..1]).unwrap();
+        //snapshots are written once, later records only refer to them
+        journal.phase = JournalPhase::GptRewriting;
+        journal.write_to(&locations[..1]).unwrap();
+        let read = SwitchJournal::read_from(&locations).unwrap().unwrap();
+        assert!(read.has_all_snapshots());
+        assert_eq!(read.raw_gpts["/dev/block/sdb"].backup, raw_gpt(0x22).backup);
+
+        segments.flip_byte(JOURNAL_RECORD_SIZE + 100);
+        let read = SwitchJournal::read_from(&locations).unwrap().unwrap();
+        assert!(!read.has_all_snapshots());
+        assert!(!read.raw_gpts.contains_key("/dev/block/sda"));
+        assert_eq!(
+            read.raw_gpts["/dev/block/sdb"].primary,
+            raw_gpt(0x22).primary
+        );
+    }
+
+    #[test]
+    fn rollback_gpts_restores_table() {
+        let segments = Segments::new("rollback");
+        let disk_path =
+            std::env::temp_dir().join(format!("rvab_journal_rollback_disk_{}", std::process::id()));
+        let sectors = 8 * 1024 * 1024 / 512;
+        let mut file = File::create(&disk_path).unwrap();
+        file.set_len(sectors * 512).unwrap();
+        ProtectiveMBR::with_lb_size(sectors as u32 - 1)
+            .overwrite_lba0(&mut file)
+            .unwrap();
+        let config = || {
+            GptConfig::new()
+                .writable(true)
+                .logical_block_size(LogicalBlockSize::Lb512)
+        };
+        let mut disk = config().create(&disk_path).unwrap();
+        disk.add_partition("boot", 1024 * 1024, partition_types::LINUX_FS, 0, None)
+            .unwrap();
+        disk.write().unwrap();
+
+        //snapshot the table like `dump_raw_gpt`
+        let disk = config().open(&disk_path).unwrap();
+        let header = disk.primary_header().unwrap();
+        let backup_offset = (header.last_usable + 1) * 512;
+        let mut primary = vec![0; (header.first_usable * 512) as usize];
+        let mut backup = vec![0; ((header.backup_lba + 1) * 512 - backup_offset) as usize];
+        let file = File::open(&disk_path).unwrap();
+        file.read_exact_at(&mut primary, 0).unwrap();
+        file.read_exact_at(&mut backup, backup_offset).unwrap();
+        let driver = disk_path.display().to_string();
+        let mut journal = SwitchJournal::new("a", "b");
+        let raw_gpt = RawGpt {
+            primary_offset: 0,
+            primary,
+            backup_offset,
+            backup,
+        };
+        journal.push_snapshot(&driver, raw_gpt).unwrap();
+        journal.write_to(&segments.locations()).unwrap();
+
+        let mut disk = config().open(&disk_path).unwrap();
+        let id = *disk.partitions().keys().next().unwrap();
+        disk.remove_partition(id).unwrap();
+        disk.add_partition("modem", 2 * 1024 * 1024, partition_types::LINUX_FS, 0, None)
+            .unwrap();
+        disk.write().unwrap();
+
+        let journal = SwitchJournal::read_from(&segments.locations())
+            .unwrap()
+            .unwrap();
+        journal.rollback_gpts().unwrap();
+        let disk = config().writable(false).open(&disk_path).unwrap();
+        let names: Vec<&str> = disk
+            .partitions()
+            .values()
+            .map(|x| x.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["boot"]);
+        let _ = std::fs::remove_file(&disk_path);
+    }
+}
@@ -4,24 +4,42 @@ mod backup_factory;
 mod backup_ftp;
 mod backup_losetup;
 mod backup_partition;
-mod bootctrl;
+pub mod bootctrl;
+mod chunk_store;
+mod compress_helper;
 mod config_helper;
 pub mod constants;
+pub mod device_env;
+pub mod error;
 pub mod gpt_helper;
+mod journal;
 mod math_support;
 pub mod metadata;
+pub mod report;
+mod simg_helper;
+mod sparse_helper;
 
-use crate::backup_factory::BackupType;
+use crate::backup_factory::{
+    measure_firmware_stored_size, slot_uses_chunk_store, BackupTrait, BackupType, VerifyReport,
+};
+use crate::bootctrl::{change_active_android_slot, get_current_android_slot};
+use crate::chunk_store::measure_chunk_store_size;
+use crate::compress_helper::{Compression, CompressionSetting};
+use crate::error::RvabError;
 use crate::gpt_helper::{
     auto_layout_freespace_example, bytes2ieee, calculate_firmware_size, delete_part_by_name,
     get_disk_sector_size, get_gpt_disk, get_part_accelerate_location, is_disk_segment_used,
     try_get_disk_lba,
 };
+use crate::journal::{JournalPhase, SwitchJournal};
 use crate::math_support::Interval;
 use crate::metadata::{Metadata, Slot, SlotsTomlConfig};
+use crate::report::{
+    CheckReport, CloneReport, ClonedPart, FirmwareSizeCheck, SlotCheck, SlotListing,
+};
 use constants::*;
 use gpt::disk::LogicalBlockSize;
-use gpt::{partition, GptConfig};
+use gpt::{partition, GptConfig, GptDisk};
 use gpt_helper::get_userdata_driver;
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
 use log::debug;
@@ -35,28 +53,36 @@ use std::{fs, io, thread};
 extern crate gpt;
 
 /// Generate a template init config file
-/// panic if any error occurs
+/// compression (e.g. "zstd:3") is set for all slots and shrinks the suggested backup target
 pub fn generate_template_init_config_file(
     path: PathBuf,
     ex_back_fpath: Option<String>,
     dual_list: Option<String>,
-) -> std::io::Result<()> {
-    let userdata_driver = get_userdata_driver();
-    let mut back_min_size_sector = 0;
-    let sector_size = get_disk_sector_size(&userdata_driver);
+    compression: Option<String>,
+    dedup: bool,
+) -> Result<(), RvabError> {
+    let compression = match compression {
+        Some(value) => CompressionSetting::parse(&value)
+            .map_err(|e| RvabError::Config(format!("invalid compression {}: {}", value, e)))?,
+        None => CompressionSetting::none(),
+    };
+    let userdata_driver = get_userdata_driver()?;
+    let sector_size = get_disk_sector_size(&userdata_driver)?;
     let mut sector = LogicalBlockSize::Lb512;
     if sector_size == 4096 {
         sector = LogicalBlockSize::Lb4096;
-    } else if sector_size == 512 {
-    } else {
-        panic!("Error: unsupported sector size !!!")
+    } else if sector_size != 512 {
+        return Err(RvabError::Config(format!(
+            "unsupported sector size {}",
+            sector_size
+        )));
     };
     let mut gpt_cfg = gpt::GptConfig::new()
         .writable(false)
         .logical_block_size(sector.clone());
-    let mut disk_result = gpt_cfg.open(&userdata_driver);
-    ///panic if disk open failed
-    let mut disk = disk_result.expect("open disk failed");
+    let mut disk = gpt_cfg
+        .open(&userdata_driver)
+        .map_err(|e| RvabError::Gpt(format!("open {} failed: {}", userdata_driver, e)))?;
     let mut userdata_id = 0;
     for (id, partition) in disk.partitions().iter() {
         if partition.name == USERDATA_NAME {
@@ -64,10 +90,10 @@ pub fn generate_template_init_config_file(
         }
     }
     if userdata_id == 0 {
-        panic!("Error: no userdata partition found");
+        return Err(RvabError::config("no userdata partition found"));
     };
     disk.remove_partition(userdata_id)
-        .expect("remove partition failed");
+        .ok_or(RvabError::gpt("remove userdata partition failed"))?;
     //find the largest free space
     let (start_lba, end_lba) = gpt_helper::find_max_free_tuple(&disk.find_free_sectors());
     println!(
@@ -78,50 +104,128 @@ pub fn generate_template_init_config_file(
         sector.as_u64()
     );
 
-    let (slot1, slot2) = auto_layout_freespace_example(
+    let (slot1, slot2, back_min_size_sector, lun_usage) = auto_layout_freespace_example(
         &userdata_driver,
         start_lba,
         end_lba,
         sector.as_u64(),
         &ex_back_fpath,
         &dual_list,
-        &mut back_min_size_sector,
-    );
+        compression,
+        dedup,
+    )?;
     let config = SlotsTomlConfig {
         slot: vec![slot1, slot2],
     };
-    let toml = toml::to_string(&config).unwrap();
-
-    let mut file = std::fs::File::create(path)?;
-    file.write_all(b"# DO NOT MODIFY BACKUP TARGET MIN SIZE , IT IS ONLY A INFO FOR YOU WITHOUT ANY OTHER FUNCTION\n");
-    file.write_all(b"# DO NOT RESTORE SUPER IF ON SYSTEM\n");
-    file.write_all(b"# Firmware too large ? add your custorm partitions to backup exclude list and regenerate \n");
-    file.write_all(
-        b"# On-system switch please make super or system and vender .etc to dynpt list \n",
-    );
-    file.write_all(b"# On-recovery switch is safe if umounted everything \n\n");
+    let toml = toml::to_string(&config)
+        .map_err(|e| RvabError::Config(format!("unable to convert config: {}", e)))?;
+
+    let path_str = path.display().to_string();
+    let mut file = std::fs::File::create(&path).map_err(|e| RvabError::io(&path_str, e))?;
     let backup_target_min_size_note = format!(
         "# NOTE : Backup Target Min SIZE = {} bytes , {}\n",
         back_min_size_sector * sector.as_u64(),
         bytes2ieee(back_min_size_sector * sector.as_u64())
     );
-    file.write_all(backup_target_min_size_note.as_bytes());
+    let mut content = String::new();
+    content.push_str("# DO NOT MODIFY BACKUP TARGET MIN SIZE , IT IS ONLY A INFO FOR YOU WITHOUT ANY OTHER FUNCTION\n");
+    content.push_str("# DO NOT RESTORE SUPER IF ON SYSTEM\n");
+    content.push_str("# Firmware too large ? add your custorm partitions to backup exclude list and regenerate \n");
+    content.push_str(
+        "# On-system switch please make super or system and vender .etc to dynpt list \n",
+    );
+    content.push_str("# On-recovery switch is safe if umounted everything \n\n");
+    content.push_str(&backup_target_min_size_note);
+    for usage in lun_usage {
+        let shared_note = match usage.shared {
+            0 => String::new(),
+            shared => format!(
+                "shared backup uses {} , ",
+                bytes2ieee(shared * usage.sector_size)
+            ),
+        };
+        let lun_note = format!(
+            "# NOTE : LUN {} : slot a uses {} , slot b uses {} , {}{} left free\n",
+            usage.driver,
+            bytes2ieee(usage.used[0] * usage.sector_size),
+            bytes2ieee(usage.used[1] * usage.sector_size),
+            shared_note,
+            bytes2ieee(usage.free * usage.sector_size)
+        );
+        print!("{}", lun_note.trim_start_matches("# NOTE : "));
+        content.push_str(&lun_note);
+    }
+
+    content.push_str("\n# Example config file\n\n");
+    content.push_str(&toml);
+    file.write_all(content.as_bytes())
+        .map_err(|e| RvabError::io(&path_str, e))
+}
+
+/// read and parse a slots config file
+fn read_slots_config(path: &str) -> Result<SlotsTomlConfig, RvabError> {
+    let data = fs::read_to_string(path).map_err(|e| RvabError::io(path, e))?;
+    toml::from_str(&data).map_err(|e| RvabError::Config(format!("unable to parse {}: {}", path, e)))
+}
+
+/// dedup slots on the same backup target share one chunk store,they must use the same region
+/// and can not be compressed since the chunk store keeps chunks uncompressed
+fn check_dedup_slots(slots: &[Slot]) -> Result<(), RvabError> {
+    let mut regions: HashMap<&str, &Slot> = HashMap::new();
+    for slot in slots {
+        if !slot_uses_chunk_store(slot)? {
+            continue;
+        };
+        if CompressionSetting::from_attr(&slot.backup_target_attr)?.compression != Compression::None
+        {
+            return Err(RvabError::Config(format!(
+                "dedup slot {} can not use compression, chunks are stored uncompressed",
+                slot.slot_name
+            )));
+        };
+        match regions.get(slot.backup_target.as_str()) {
+            Some(other)
+                if other.backup_target_start != slot.backup_target_start
+                    || other.backup_target_end != slot.backup_target_end =>
+            {
+                return Err(RvabError::Config(format!(
+                    "dedup slots {} and {} share backup target {} but use different regions",
+                    other.slot_name, slot.slot_name, slot.backup_target
+                )));
+            }
+            Some(_) => {}
+            None => {
+                regions.insert(&slot.backup_target, slot);
+            }
+        };
+    }
+    Ok(())
+}
 
-    file.write_all(b"\n# Example config file\n\n");
-    file.write_all(toml.as_bytes())
+/// find the slot to init (default the first slot)
+fn find_init_slot<'a>(
+    slots: &'a [Slot],
+    initial_slot: &Option<String>,
+) -> Result<&'a Slot, RvabError> {
+    match initial_slot {
+        Some(init_target) => slots
+            .iter()
+            .find(|&x| x.slot_name == *init_target)
+            .ok_or(RvabError::Config(format!("no such slot {}", init_target))),
+        None => slots.first().ok_or(RvabError::config("no slot found")),
+    }
 }
 
 /// Check slots config file without really modify
-pub fn check_slots_config(path: &str) {
+pub fn check_slots_config(path: &str) -> Result<CheckReport, RvabError> {
     // check the flowing things (there are 1-several slots : 1 is fw size larger than backup size
     // 2 is any part overlaps and is anything overflows disk size
-    let data = fs::read_to_string(path).expect("Error: read config file failed");
-    let slots_config: SlotsTomlConfig =
-        toml::from_str(&data).expect("Error: parse config file failed");
-    let slots = &slots_config.slot;
-    let mut all_fine = true;
-    for slot in slots.iter() {
-        println!("Checking for slot : {}", &slot.slot_name);
+    let slots_config = read_slots_config(path)?;
+    let mut report = CheckReport {
+        config_path: path.to_string(),
+        slots: Vec::new(),
+    };
+    for slot in slots_config.slot.iter() {
         // 1 first check fw size
         //merge exclude and dynamic partitions list into a total exclude list
         let mut exclude_list = HashSet::new();
@@ -130,126 +234,110 @@ pub fn check_slots_config(path: &str) {
             exclude_list.insert(name.clone());
         }
         //calculate fw size
-        let (num, size_bytes) = calculate_firmware_size(&exclude_list);
-        let backup_target_size = (slot.backup_target_end - slot.backup_target_start + 1)
-            * get_disk_sector_size(&slot.backup_target);
-        if size_bytes > backup_target_size {
-            println!("\t1 Error: firmware size {} bytes is larger than backup target size {} bytes , {} > {}",
-                     size_bytes, backup_target_size, bytes2ieee(size_bytes), bytes2ieee(backup_target_size));
-            all_fine = false;
-        } else {
-            println!("\t1 Pass: firmware size {} bytes is smaller than backup target size {} bytes , {} < {}",
-                     size_bytes, backup_target_size, bytes2ieee(size_bytes), bytes2ieee(backup_target_size));
+        let (num, size_bytes) = calculate_firmware_size(&exclude_list)?;
+        let mut firmware = FirmwareSizeCheck {
+            firmware_count: num,
+            firmware_size: size_bytes,
+            stored_size: size_bytes,
+            backup_target: slot.backup_target.clone(),
+            backup_target_size: None,
+            error: None,
+        };
+        if !matches!(
+            BackupType::code2type(slot.backup_type_code),
+            Ok(BackupType::Ftp)
+        ) {
+            //images are stored sparse (and compressed), compare the measured stored size
+            //dedup slots compare the chunk store holding both slots
+            let stored_size = match slot_uses_chunk_store(slot) {
+                Ok(true) => measure_chunk_store_size(&exclude_list),
+                Ok(false) => CompressionSetting::from_attr(&slot.backup_target_attr)
+                    .map_err(RvabError::from)
+                    .and_then(|compression| {
+                        measure_firmware_stored_size(&exclude_list, compression)
+                    }),
+                Err(e) => Err(e),
+            };
+            match stored_size {
+                Ok(size) => firmware.stored_size = size,
+                Err(err) => firmware.error = Some(err.to_string()),
+            };
+            firmware.backup_target_size = Some(
+                (slot.backup_target_end - slot.backup_target_start + 1)
+                    * get_disk_sector_size(&slot.backup_target)?,
+            );
         }
 
         // 2 check overlaps and is anything overflows disk size
-        let ret = try_init_partition_table_layout(
+        let layout_error = try_init_partition_table_layout(
             &path.to_string(),
             &Some(slot.slot_name.clone()),
             false,
             true,
-        );
-        if ret.is_err() {
-            eprintln!("\t2 {}", ret.err().unwrap());
-            all_fine = false;
-        } else {
-            println!("\t2 Pass: no overlaps and no overflow disk size");
-        }
+        )
+        .err()
+        .map(|e| e.to_string());
         //TODO feather check
+        report.slots.push(SlotCheck {
+            slot_name: slot.slot_name.clone(),
+            firmware,
+            layout_error,
+        });
     }
-    if !all_fine {
-        println!("##### FAIL #####");
-    } else {
-        println!("##### PASS #####");
-    };
+    Ok(report)
 }
 
 /// Try only init userdata partition
-/// ## Panic if any error occurs
 pub fn try_init_userdata_partition(
     cfg_path: &String,
     initial_slot: &Option<String>,
     silent: bool,
-) -> Result<(), ()> {
+) -> Result<(), RvabError> {
     //print silent warning if silent mode enabled
     if silent {
         println!("Warning: silent mode enabled, allow all dangerous actions");
     }
-    let data = fs::read_to_string(cfg_path).expect("Error: read config file failed");
-    let slots_config: SlotsTomlConfig =
-        toml::from_str(&data).expect("Error: parse config file failed");
-    let slots = &slots_config.slot;
-    let mut target_slot;
-    if let Some(init_target) = initial_slot {
-        target_slot = slots
-            .iter()
-            .find(|&x| x.slot_name == *init_target)
-            .expect("Error: no such slot found");
-    } else {
-        target_slot = slots.get(0).expect("Error: no slot found");
-    }
-    let userdata_driver = get_userdata_driver();
-    if let Some(userdata_raw) = target_slot.dyn_partition_set.get(USERDATA_NAME) {
-        if userdata_driver == userdata_raw.driver {
-            // delete userdata and recreate
-            let disk = get_gpt_disk(&userdata_driver, true);
-            if disk.is_none() {
-                eprintln!("Error: get disk failed");
-                return Err(());
-            }
-            let mut disk = disk.unwrap();
-            let userdata_id = disk
-                .partitions()
-                .iter()
-                .find(|(_, part)| part.name == USERDATA_NAME)
-                .map(|(id, _)| *id);
-            if userdata_id.is_none() {
-                eprintln!("Error: find userdata partition failed");
-                return Err(());
-            }
-            let userdata_id = userdata_id.unwrap();
-            let ret = disk.remove_partition(userdata_id);
-            if ret.is_none() {
-                eprintln!("Error: remove userdata partition failed");
-                return Err(());
-            }
-            let part_type = gpt::partition_types::Type::from_name(&userdata_raw.type_guid.clone());
-            if part_type.is_err() {
-                eprintln!("Error: invalid part type guid");
-                return Err(());
-            }
-            let part_type = part_type.unwrap();
-            let part = partition::Partition {
-                part_type_guid: part_type,
-                part_guid: uuid::Uuid::new_v4(),
-                first_lba: userdata_raw.start_lba,
-                last_lba: userdata_raw.end_lba,
-                flags: userdata_raw.flags,
-                name: USERDATA_NAME.to_string(),
-            };
-            let mut partitions = disk.take_partitions();
-            partitions.insert(userdata_id, part);
-            let ret = disk.update_partitions(partitions);
-            if ret.is_err() {
-                eprintln!("Error: update partitions failed");
-                return Err(());
-            }
-            let ret = disk.write();
-            if ret.is_err() {
-                eprintln!("Error: write disk failed");
-                return Err(());
-            }
-        } else {
-            eprintln!(
-                "Error: config userdata mismatch {}<>{}",
-                userdata_driver, userdata_raw.driver
-            );
-            return Err(());
-        }
-    } else {
-        eprintln!("Error: no userdata partition found in config");
-        return Err(());
+    let slots_config = read_slots_config(cfg_path)?;
+    let target_slot = find_init_slot(&slots_config.slot, initial_slot)?;
+    let userdata_driver = get_userdata_driver()?;
+    let userdata_raw = target_slot
+        .dyn_partition_set
+        .get(USERDATA_NAME)
+        .ok_or(RvabError::config("no userdata partition found in config"))?;
+    if userdata_driver != userdata_raw.driver {
+        return Err(RvabError::Config(format!(
+            "config userdata mismatch {}<>{}",
+            userdata_driver, userdata_raw.driver
+        )));
     }
+    // delete userdata and recreate
+    let mut disk = get_gpt_disk(&userdata_driver, true)?;
+    let userdata_id = disk
+        .partitions()
+        .iter()
+        .find(|(_, part)| part.name == USERDATA_NAME)
+        .map(|(id, _)| *id)
+        .ok_or(RvabError::gpt("find userdata partition failed"))?;
+    disk.remove_partition(userdata_id)
+        .ok_or(RvabError::gpt("remove userdata partition failed"))?;
+    let part_type = gpt::partition_types::Type::from_name(&userdata_raw.type_guid.clone())
+        .map_err(|_| {
+            RvabError::Config(format!("invalid part type guid {}", userdata_raw.type_guid))
+        })?;
+    let part = partition::Partition {
+        part_type_guid: part_type,
+        part_guid: uuid::Uuid::new_v4(),
+        first_lba: userdata_raw.start_lba,
+        last_lba: userdata_raw.end_lba,
+        flags: userdata_raw.flags,
+        name: USERDATA_NAME.to_string(),
+    };
+    let mut partitions = disk.take_partitions();
+    partitions.insert(userdata_id, part);
+    disk.update_partitions(partitions)
+        .map_err(|e| RvabError::Layout(format!("update partitions failed: {}", e)))?;
+    disk.write()
+        .map_err(|e| RvabError::Gpt(format!("write {} failed: {}", userdata_driver, e)))?;
     Ok(())
 }
 
@@ -260,78 +348,326 @@ pub fn try_init_partition_table_layout(
     initial_slot: &Option<String>,
     save_changes: bool,
     silent: bool,
-) -> Result<(), &'static str> {
+) -> Result<CloneReport, RvabError> {
     //print silent warning if silent mode enabled
     if silent && save_changes {
         println!("Warning: silent mode enabled, allow all dangerous actions");
     }
-    let data = fs::read_to_string(cfg_path).expect("Error: read config file failed");
-    let slots_config: SlotsTomlConfig =
-        toml::from_str(&data).expect("Error: parse config file failed");
+    let slots_config = read_slots_config(cfg_path)?;
     let slots = &slots_config.slot;
-    let mut target_slot;
-    if let Some(init_target) = initial_slot {
-        target_slot = slots
-            .iter()
-            .find(|&x| x.slot_name == *init_target)
-            .expect("Error: no such slot found");
-    } else {
-        target_slot = slots.get(0).expect("Error: no slot found");
-    }
+    check_dedup_slots(slots)?;
+    let target_slot = find_init_slot(slots, initial_slot)?;
     // check if done first init
-    let target_userdata = target_slot.dyn_partition_set.get(USERDATA_NAME).unwrap();
-    let (_, _, start_lba, end_lba, _) = get_part_accelerate_location(USERDATA_NAME).unwrap();
+    let target_userdata = target_slot
+        .dyn_partition_set
+        .get(USERDATA_NAME)
+        .ok_or(RvabError::config("no userdata partition found in config"))?;
+    let (_, _, start_lba, end_lba, _) = get_part_accelerate_location(USERDATA_NAME)?;
     if (target_userdata.start_lba != start_lba) || (target_userdata.end_lba != end_lba) {
         eprintln!(
             "Error: Please use -f to init userdata first and reboot to retry\
         \nYou shouldn't skip the userdata init process and directly do the full init process\
         \nSuch wrong operation may cause broken firmware and the result is totally unpredictable"
         );
-        return Err("Error: userdata not initialized");
+        return Err(RvabError::layout("userdata not initialized"));
     };
 
     // part tables backup , store orig part table in ram
     let mut tables_backup = HashMap::new();
     if save_changes {
-        for (part_name, raw_part) in &target_slot.dyn_partition_set {
-            // store orig part table in ram
-            let disk_ret = get_gpt_disk(&raw_part.driver, false).expect("Error: get disk failed");
-            tables_backup.insert(raw_part.driver.clone(), disk_ret);
-            let (driver2, id, _, _, _) = get_part_accelerate_location(part_name)
-                .expect("Error: get part accelerate location failed");
-            let disk_ret = get_gpt_disk(&driver2, false).expect("Error: get disk failed");
-            tables_backup.insert(driver2.clone(), disk_ret);
+        tables_backup = cache_partition_tables(target_slot)?;
+    }
+
+    // move to the target slot
+    // move to the target slot and clone fw
+    let ret = init_partition_table_layout(target_slot, save_changes, silent)
+        .and_then(|_| clone_firmware(slots));
+    if ret.is_err() {
+        // restore all changed tables
+        println!("Error: init partition table layout failed or clone firmware failed, restoring all changed tables");
+        restore_partition_tables(tables_backup);
+    };
+    ret
+}
+
+/// Cache all part tables touched when moving dyn partitions of the slot, return (driver,disk) map
+/// both the current location and the target location of each dyn partition are cached
+/// disks are opened writable so that they can be written back directly
+/// ## Never panics
+fn cache_partition_tables(slot: &Slot) -> Result<HashMap<String, GptDisk<fs::File>>, RvabError> {
+    let mut tables_backup = HashMap::new();
+    for (part_name, raw_part) in &slot.dyn_partition_set {
+        let (current_driver, _, _, _, _) = get_part_accelerate_location(part_name)?;
+        for driver in [raw_part.driver.clone(), current_driver] {
+            if tables_backup.contains_key(&driver) {
+                continue;
+            }
+            let disk = get_gpt_disk(&driver, true)?;
             debug!(
                 "Backup part table for part {} on disk {}",
-                part_name, raw_part.driver
+                part_name, driver
             );
+            tables_backup.insert(driver, disk);
         }
     }
+    Ok(tables_backup)
+}
 
-    // move to the target slot
-    let move_ret = init_partition_table_layout(target_slot, save_changes, silent);
-    // clone fw
-    let mut clone_success = false;
-    if move_ret.is_ok() {
-        let clone_ret = clone_firmware(slots);
-        if clone_ret.is_ok() {
-            clone_success = true;
-        } else {
-            println!("Error: clone firmware failed");
-        }
+/// Write all cached part tables back to their disks
+/// ## Never panics
+fn restore_partition_tables(tables_backup: HashMap<String, GptDisk<fs::File>>) {
+    for (driver, disk) in tables_backup {
+        let ret = disk.write();
+        if ret.is_err() {
+            println!("Terrible!!!: restore disk {} failed", driver);
+        };
     }
-    if move_ret.is_err() || !clone_success {
-        // restore all changed tables
-        println!("Error: init partition table layout failed or clone firmware failed, restoring all changed tables");
-        for (driver, disk) in tables_backup {
-            let ret = disk.write();
-            if ret.is_err() {
-                println!("Terrible!!!: restore disk {} failed", driver);
+}
+
+/// Switch to another slot
+/// 1 backup firmware of current slot through its backup backend
+/// 2 move every dyn partition of target slot into gpt (same as init_partition_table_layout)
+/// 3 restore firmware of target slot through its backup backend
+/// 4 commit new current slot to metadata area of every slot
+/// 5 set the android slot recorded for the target slot active
+/// If any step fails, all changed gpt tables are written back and current firmware is restored
+/// Every phase is recorded in the switch journal first, see `recover_interrupted_switch`
+pub fn switch_to_slot(target_slot_name: &str, silent: bool) -> Result<(), RvabError> {
+    //print silent warning if silent mode enabled
+    if silent {
+        println!("Warning: silent mode enabled, allow all dangerous actions");
+    }
+    let mut metadata = Metadata::from_fw_metadata()?;
+    if SwitchJournal::read(&metadata)?.is_some() {
+        return Err(RvabError::integrity(
+            "found an interrupted switch, please run recover first",
+        ));
+    };
+    let current_slot_name = metadata.current_slot.clone();
+    if current_slot_name == target_slot_name {
+        return Err(RvabError::config("target slot is already the current slot"));
+    }
+    let current_slot = metadata
+        .slots
+        .get(current_slot_name.as_str())
+        .ok_or(RvabError::config("current slot match no item"))?
+        .clone();
+    let target_slot = metadata
+        .slots
+        .get(target_slot_name)
+        .ok_or(RvabError::Config(format!(
+            "no such slot {}",
+            target_slot_name
+        )))?
+        .clone();
+    let current_backup = BackupType::code2type(current_slot.backup_type_code)?;
+    let target_backup = BackupType::code2type(target_slot.backup_type_code)?;
+    println!(
+        "Switch from slot {} to slot {}",
+        current_slot_name, target_slot_name
+    );
+
+    // part tables backup , store orig part table in ram and in journal
+    let tables_backup = cache_partition_tables(&target_slot)?;
+    let mut journal = SwitchJournal::new(&current_slot_name, target_slot_name);
+    journal.snapshot_gpts(tables_backup.keys())?;
+    journal.commit_phase(&metadata, JournalPhase::Started)?;
+
+    // 1 backup firmware of current slot, nothing is changed if it fails
+    println!("Backup firmware of slot {}", current_slot_name);
+    let ret = current_backup
+        .backup(&metadata, &current_slot_name)
+        .and_then(|_| journal.commit_phase(&metadata, JournalPhase::FirmwareBackedUp))
+        .and_then(|_| journal.commit_phase(&metadata, JournalPhase::GptRewriting));
+    if let Err(e) = ret {
+        println!("Error: backup firmware failed");
+        let _ = SwitchJournal::clear(&metadata);
+        return Err(e);
+    };
+    // gpt of current slot is kept for archives, the switch goes on without it
+    if let Err(e) = current_backup.backup_gpt(&metadata, &current_slot_name) {
+        eprintln!("{}", e);
+        println!(
+            "Warning: backup gpt of slot {} failed, archive -g of it will not work",
+            current_slot_name
+        );
+    };
+    // android A/B slot the current slot runs on, reapplied when switching back to it
+    match get_current_android_slot() {
+        Ok(android_slot) => {
+            if let Some(slot) = metadata.slots.get_mut(&current_slot_name) {
+                slot.android_slot = android_slot;
             };
         }
-        return Err("Error: init partition table layout failed");
+        Err(e) => {
+            eprintln!("{}", e);
+            println!(
+                "Warning: android slot of slot {} is not recorded",
+                current_slot_name
+            );
+        }
+    };
+
+    // 2 move dyn partitions to target slot
+    let ret = move_partition_table_layout(&target_slot, true, silent, &mut |driver| {
+        journal.commit_rewritten_lun(&metadata, driver)
+    })
+    .and_then(|_| journal.commit_phase(&metadata, JournalPhase::GptRewritten));
+    if let Err(e) = ret {
+        eprintln!("{}", e);
+        println!("Error: move dyn partitions failed, restoring all changed tables");
+        restore_partition_tables(tables_backup);
+        let _ = SwitchJournal::clear(&metadata);
+        return Err(e);
+    };
+
+    // 3 restore firmware of target slot
+    println!("Restore firmware of slot {}", target_slot_name);
+    let mut ret = target_backup
+        .restore(&metadata, target_slot_name)
+        .and_then(|_| journal.commit_phase(&metadata, JournalPhase::FirmwareRestored));
+
+    // 4 commit current slot to all metadata
+    if ret.is_ok() {
+        metadata.current_slot = target_slot_name.to_string();
+        ret = metadata
+            .write_fw_metadata()
+            .and_then(|_| journal.commit_phase(&metadata, JournalPhase::MetadataCommitted));
+    }
+    if let Err(e) = ret {
+        eprintln!("{}", e);
+        println!(
+            "Error: switch failed, restoring all changed tables and firmware of slot {}",
+            current_slot_name
+        );
+        restore_partition_tables(tables_backup);
+        if current_backup
+            .restore(&metadata, &current_slot_name)
+            .is_err()
+        {
+            println!(
+                "Terrible!!!: restore firmware of slot {} failed",
+                current_slot_name
+            );
+        };
+        metadata.current_slot = current_slot_name;
+        if metadata.write_fw_metadata().is_err() {
+            println!("Terrible!!!: restore metadata failed");
+        };
+        let _ = SwitchJournal::clear(&metadata);
+        return Err(e);
+    };
+    SwitchJournal::clear(&metadata)?;
+
+    // 5 boot target slot on the android slot it was running on
+    apply_android_slot(&target_slot);
+    Ok(())
+}
+
+/// set the android slot the slot was running on active, a failure only warns
+fn apply_android_slot(slot: &Slot) {
+    if slot.android_slot.is_empty() {
+        return;
+    };
+    println!("Set android slot {} active", slot.android_slot);
+    if let Err(e) = change_active_android_slot(&slot.android_slot) {
+        eprintln!("{}", e);
+        println!(
+            "Warning: set android slot {} failed, set it before reboot",
+            slot.android_slot
+        );
+    };
+}
+
+/// Recover an interrupted switch from the switch journal
+/// If all gpt tables were already rewritten the switch is finished,
+/// otherwise all gpt tables are rolled back from journal snapshots.
+/// Either way the android slot recorded for the resulting slot is set active like switch does.
+/// cfg_path is needed only if metadata can not be found via current userdata
+pub fn recover_interrupted_switch(cfg_path: &Option<String>) -> Result<(), RvabError> {
+    let mut metadata = match cfg_path {
+        Some(path) => {
+            //config only locates the metadata segments,slots state comes from the stored copy
+            let slots = read_slots_config(path)?
+                .slot
+                .into_iter()
+                .map(|x| (x.slot_name.clone(), x))
+                .collect();
+            Metadata::from_fw_metadata_located_by(&Metadata::new("unknown".to_string(), slots))?
+        }
+        None => Metadata::from_fw_metadata()?,
+    };
+    let journal = match SwitchJournal::read(&metadata)? {
+        Some(journal) => journal,
+        None => {
+            println!("No interrupted switch found");
+            return Ok(());
+        }
+    };
+    println!("Found interrupted switch\n{}", journal);
+    let from_backup = BackupType::code2type(
+        metadata
+            .slots
+            .get(journal.from_slot.as_str())
+            .ok_or(RvabError::integrity("journal source slot match no item"))?
+            .backup_type_code,
+    )?;
+    let to_backup = BackupType::code2type(
+        metadata
+            .slots
+            .get(journal.to_slot.as_str())
+            .ok_or(RvabError::integrity("journal target slot match no item"))?
+            .backup_type_code,
+    )?;
+
+    if journal.phase >= JournalPhase::GptRewritten {
+        // roll forward
+        println!("Finishing switch to slot {}", journal.to_slot);
+        let mut ret = Ok(());
+        if journal.phase < JournalPhase::FirmwareRestored {
+            ret = to_backup.restore(&metadata, &journal.to_slot);
+        };
+        if ret.is_ok() && journal.phase < JournalPhase::MetadataCommitted {
+            metadata.current_slot = journal.to_slot.clone();
+            ret = metadata.write_fw_metadata();
+        };
+        match ret {
+            Ok(_) => {
+                SwitchJournal::clear(&metadata)?;
+                apply_android_slot(&metadata.slots[&journal.to_slot]);
+                println!("Switch to slot {} finished", journal.to_slot);
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                println!("Error: finish switch failed, rolling back");
+            }
+        };
     };
 
+    // roll back
+    println!("Rolling back to slot {}", journal.from_slot);
+    if journal.phase >= JournalPhase::GptRewriting {
+        if journal.has_all_snapshots() {
+            journal.rollback_gpts()?;
+        } else {
+            // journal snapshots are gone, use the gpt stored with the source slot backup
+            println!(
+                "Warning: gpt snapshots of the journal are broken, using gpt backup of slot {}",
+                journal.from_slot
+            );
+            from_backup.restore_gpt(&metadata, &journal.from_slot)?;
+        };
+    };
+    if journal.phase >= JournalPhase::GptRewritten {
+        // target firmware may be partly restored
+        from_backup.restore(&metadata, &journal.from_slot)?;
+    };
+    metadata.current_slot = journal.from_slot.clone();
+    metadata.write_fw_metadata()?;
+    SwitchJournal::clear(&metadata)?;
+    apply_android_slot(&metadata.slots[&journal.from_slot]);
+    println!("Rolled back to slot {}", journal.from_slot);
     Ok(())
 }
 
@@ -341,7 +677,19 @@ pub fn init_partition_table_layout(
     target_slot: &Slot,
     save_changes: bool,
     silent: bool,
-) -> Result<(), &'static str> {
+) -> Result<(), RvabError> {
+    move_partition_table_layout(target_slot, save_changes, silent, &mut |_| Ok(()))
+}
+
+/// move all dyn partitions of the slot into gpt
+/// on_disk_written is called with the driver after each gpt write
+/// ## Never panics
+fn move_partition_table_layout(
+    target_slot: &Slot,
+    save_changes: bool,
+    silent: bool,
+    on_disk_written: &mut dyn FnMut(&str) -> Result<(), RvabError>,
+) -> Result<(), RvabError> {
     // move to the target slot
     for (part_name, raw_part) in &target_slot.dyn_partition_set {
         debug!(
@@ -351,15 +699,19 @@ pub fn init_partition_table_layout(
         //delete part
         if save_changes {
             //normal init
+            let (orig_driver, _, _, _, _) = get_part_accelerate_location(part_name)?;
             delete_part_by_name(part_name)?;
+            on_disk_written(&orig_driver)?;
         };
         //create part
         let disk = raw_part.driver.clone();
         let mut gptcfg = gpt::GptConfig::new()
             .writable(true)
-            .logical_block_size(try_get_disk_lba(&disk))
+            .logical_block_size(try_get_disk_lba(&disk)?)
             .change_partition_count(true);
-        let mut disk = gptcfg.open(&disk).map_err(|_| "Error: open disk failed")?;
+        let mut disk = gptcfg
+            .open(&disk)
+            .map_err(|e| RvabError::Gpt(format!("open {} failed: {}", disk, e)))?;
 
         let mut partition_id = 0;
 
@@ -372,45 +724,45 @@ pub fn init_partition_table_layout(
                 }
             }
             if partition_id != 0 {
-                let ret = disk.remove_partition(partition_id);
-                if ret.is_none() {
-                    return Err("Error: remove partition failed");
-                };
+                disk.remove_partition(partition_id)
+                    .ok_or(RvabError::Gpt(format!(
+                        "remove partition {} failed",
+                        part_name
+                    )))?;
             };
         }
         if partition_id == 0 {
-            partition_id = disk.find_next_partition_id().unwrap_or_else(|| {
-                println!("Warning: no free partition id found, will increase partition number");
-                if !silent {
-                    //ask if process
-                    let mut input = String::new();
-                    if save_changes {
-                        println!("Warning: We have cached all target gpt tables in ram");
-                        println!("Enter n will restore all gpt table to original state");
-                    };
+            partition_id = match disk.find_next_partition_id() {
+                Some(id) => id,
+                None => {
+                    println!("Warning: no free partition id found, will increase partition number");
+                    if !silent {
+                        //ask if process
+                        let mut input = String::new();
+                        if save_changes {
+                            println!("Warning: We have cached all target gpt tables in ram");
+                            println!("Enter n will restore all gpt table to original state");
+                        };
 
-                    println!("Do you want to continue ? (y/n)");
-                    let ret = std::io::stdin().read_line(&mut input);
-                    if ret.is_err() {
-                        println!("Std Error: read input failed, auto enter n");
-                        return 0;
-                    };
-                    if input.trim() != "y" || input.trim() != "Y" {
-                        // give an impossible id to stop process
-                        return 0;
+                        println!("Do you want to continue ? (y/n)");
+                        std::io::stdin()
+                            .read_line(&mut input)
+                            .map_err(|e| RvabError::io("stdin", e))?;
+                        if input.trim().to_uppercase() != "Y" {
+                            return Err(RvabError::Cancelled);
+                        };
                     };
-                };
-                disk.header().num_parts + 1
-            });
+                    disk.header().num_parts + 1
+                }
+            };
         }
-        if partition_id == 0 {
-            return Err("Error: user cancel process : invalid partition id");
-        };
         let orig_part_num = disk.header().num_parts;
         let mut partitions = disk.take_partitions();
 
         let part_type = gpt::partition_types::Type::from_name(&raw_part.type_guid.clone())
-            .map_err(|_| "Error: invalid part type guid")?;
+            .map_err(|_| {
+                RvabError::Config(format!("invalid part type guid {}", raw_part.type_guid))
+            })?;
         let part = partition::Partition {
             part_type_guid: part_type,
             part_guid: uuid::Uuid::new_v4(),
@@ -420,8 +772,12 @@ pub fn init_partition_table_layout(
             name: part_name.to_string(),
         };
         partitions.insert(partition_id, part);
-        disk.update_partitions(partitions)
-            .map_err(|_| "Error: update partitions failed")?;
+        disk.update_partitions(partitions).map_err(|e| {
+            RvabError::Layout(format!(
+                "place {} on {} failed: {}",
+                part_name, raw_part.driver, e
+            ))
+        })?;
         let new_part_num = disk.header().num_parts;
         if new_part_num != orig_part_num {
             println!(
@@ -430,7 +786,9 @@ pub fn init_partition_table_layout(
             );
         };
         if save_changes {
-            disk.write().map_err(|_| "Error: write disk failed")?;
+            disk.write()
+                .map_err(|e| RvabError::Gpt(format!("write {} failed: {}", raw_part.driver, e)))?;
+            on_disk_written(&raw_part.driver)?;
         };
     }
     Ok(())
@@ -440,7 +798,7 @@ pub fn init_partition_table_layout(
 /// This is very important,if you switch to a slot with blank firmware partition,your device will be bricked
 /// for example,will a blank abl partition
 /// ## Never Panic
-pub fn clone_firmware(slots: &Vec<Slot>) -> Result<(), &'static str> {
+pub fn clone_firmware(slots: &Vec<Slot>) -> Result<CloneReport, RvabError> {
     let mut total_size: u64 = 0;
     for slot in slots.iter() {
         for (name, raw_part) in slot.dyn_partition_set.iter() {
@@ -452,7 +810,7 @@ pub fn clone_firmware(slots: &Vec<Slot>) -> Result<(), &'static str> {
             total_size += slength;
         }
     }
-    let mut cloned: u64 = 0;
+    let mut report = CloneReport::default();
     let pb = ProgressBar::new(total_size);
     pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
         .unwrap()
@@ -468,116 +826,159 @@ pub fn clone_firmware(slots: &Vec<Slot>) -> Result<(), &'static str> {
             let tdisk = &raw_part.driver;
             let soffset = first_lba * sector_size;
             let slength = (last_lba - first_lba + 1) * sector_size;
-            let tsector = get_disk_sector_size(&raw_part.driver);
+            let tsector = get_disk_sector_size(&raw_part.driver)?;
             let toffset = (raw_part.start_lba) * tsector;
             let tlength = (raw_part.end_lba - raw_part.start_lba + 1) * tsector;
             // clone
             if slength != tlength {
-                return Err("Error: source and target length must be equal");
+                return Err(RvabError::Layout(format!(
+                    "source and target length of {} must be equal",
+                    name
+                )));
             };
             // no need to check,because check is already done previously
             let sfile = fs::OpenOptions::new()
                 .read(true)
-                .open(sdisk)
-                .map_err(|_| "Error: open source disk failed")?;
+                .open(&sdisk)
+                .map_err(|e| RvabError::io(&sdisk, e))?;
             let tfile = fs::OpenOptions::new()
                 .write(true)
                 .open(tdisk)
-                .map_err(|_| "Error: open target disk failed")?;
+                .map_err(|e| RvabError::io(tdisk, e))?;
             let mut sfile = io::BufReader::new(sfile);
             let mut tfile = io::BufWriter::new(tfile);
             sfile
                 .seek(io::SeekFrom::Start(soffset))
-                .map_err(|_| "Error: seek source disk failed")?;
+                .map_err(|e| RvabError::io(&sdisk, e))?;
             tfile
                 .seek(io::SeekFrom::Start(toffset))
-                .map_err(|_| "Error: seek target disk failed")?;
+                .map_err(|e| RvabError::io(tdisk, e))?;
             let mut buffer = vec![0; 4096];
             let mut remain = slength;
             while remain > 0 {
                 let read_size = if remain > 4096 { 4096 } else { remain as usize };
                 sfile
                     .read_exact(&mut buffer[..read_size])
-                    .map_err(|_| "Error: read source disk failed")?;
+                    .map_err(|e| RvabError::io(&sdisk, e))?;
                 tfile
                     .write_all(&buffer[..read_size])
-                    .map_err(|_| "Error: write target disk failed")?;
-                cloned += read_size as u64;
+                    .map_err(|e| RvabError::io(tdisk, e))?;
                 pb.inc(read_size as u64);
                 remain -= read_size as u64;
             }
+            tfile.flush().map_err(|e| RvabError::io(tdisk, e))?;
+            report.parts.push(ClonedPart {
+                slot_name: slot.slot_name.clone(),
+                part_name: name.clone(),
+                source: sdisk,
+                source_offset: soffset,
+                target: tdisk.clone(),
+                target_offset: toffset,
+                length: slength,
+            });
         }
     }
     pb.finish_with_message("clone finished");
-    Ok(())
+    Ok(report)
 }
 
 /// update config to all slots
-pub fn update_config_to_all_slots(path: &str) {
-    let data = fs::read_to_string(path).expect("Error: read config file failed");
-    let slots_config: SlotsTomlConfig =
-        toml::from_str(&data).expect("Error: parse config file failed");
-    let slots = slots_config.slot;
+pub fn update_config_to_all_slots(path: &str) -> Result<(), RvabError> {
+    let slots = read_slots_config(path)?.slot;
+    check_dedup_slots(&slots)?;
     if cfg!(debug_assertions) {
         println!("Debug: update config to all slots {:?}", slots);
     };
     //vec to hashmap
+    //android slots are recorded on switch, keep them unless the config sets them
+    let (old_slots, old_generation) = Metadata::from_fw_metadata()
+        .map(|x| (x.slots, x.generation))
+        .unwrap_or_default();
     let mut slots_map: HashMap<String, Slot> = HashMap::new();
-    for slot in slots {
+    for mut slot in slots {
+        if slot.android_slot.is_empty() {
+            if let Some(old_slot) = old_slots.get(&slot.slot_name) {
+                slot.android_slot = old_slot.android_slot.clone();
+            };
+        };
         slots_map.insert(slot.slot_name.clone(), slot);
     }
     let mut metadata = Metadata::new("unknown".to_string(), slots_map);
-    metadata.calculate_current_slot();
-    metadata.write_fw_metadata().unwrap();
+    metadata.generation = old_generation;
+    metadata.calculate_current_slot()?;
+    //backup targets are made before any switch, see `BackupTrait::prepare`
+    let mut slot_names: Vec<&String> = metadata.slots.keys().collect();
+    slot_names.sort();
+    for slot_name in slot_names {
+        BackupType::code2type(metadata.slots[slot_name].backup_type_code)?
+            .prepare(&metadata, slot_name)?;
+    }
+    metadata.write_fw_metadata()
 }
 
-/// show current slot and its metadata
-pub fn show_current_slot(only_name: bool) {
-    let metadata = Metadata::from_fw_metadata().unwrap();
+/// verify the firmware backup of a slot (default current slot) against live partitions
+pub fn verify_slot_backup(slot_name: Option<String>) -> Result<VerifyReport, RvabError> {
+    let metadata = Metadata::from_fw_metadata()?;
+    let slot_name = slot_name.unwrap_or(metadata.current_slot.clone());
     let slot = metadata
+        .slots
+        .get(slot_name.as_str())
+        .ok_or(RvabError::Config(format!("no such slot {}", slot_name)))?;
+    let backup = BackupType::code2type(slot.backup_type_code)?;
+    println!("Verify backup of slot {} ({})", slot_name, backup);
+    backup.verify(&metadata, &slot_name)
+}
+
+/// pack a slot into a recovery flashable zip at output
+pub fn archive_slot(slot_name: &str, output: &str, gpt: bool, simg: bool) -> Result<(), RvabError> {
+    let metadata = Metadata::from_fw_metadata()?;
+    android_flashable::archive_slot(&metadata, slot_name, output, gpt, simg)
+}
+
+/// import a flashable zip made by archive into a slot
+pub fn import_archive(input: &str, slot_name: &str) -> Result<(), RvabError> {
+    let metadata = Metadata::from_fw_metadata()?;
+    android_flashable::import_archive(&metadata, slot_name, input)
+}
+
+/// current slot and its metadata
+pub fn get_current_slot() -> Result<Slot, RvabError> {
+    let metadata = Metadata::from_fw_metadata()?;
+    metadata
         .slots
         .get(metadata.current_slot.as_str())
-        .expect("Error: current slot match no item");
-    println!("Current Slot : {}", metadata.current_slot);
-    if only_name {
-        return;
-    }
-    //print current slot metadata
-    println!("Slot Name : {:?}", slot);
+        .cloned()
+        .ok_or(RvabError::config("current slot match no item"))
 }
 
-/// list slots (slot)
-pub fn list_slots(slot_name: Option<String>, only_name: bool) {
-    let metadata = Metadata::from_fw_metadata().unwrap();
-    if let Some(slot_name) = slot_name {
-        let slot = metadata
+/// list slots sorted by name,or only the slot named slot_name
+pub fn list_slots(slot_name: Option<String>) -> Result<SlotListing, RvabError> {
+    let metadata = Metadata::from_fw_metadata()?;
+    let mut slots: Vec<Slot> = match slot_name {
+        Some(slot_name) => vec![metadata
             .slots
             .get(slot_name.as_str())
-            .expect("Error: no such slot found");
-        println!("{}", slot);
-        return;
-    }
-
-    if only_name {
-        let mut counter = 0;
-        for (slot_name, _) in metadata.slots.iter() {
-            counter += 1;
-            println!("{} : {}", counter, slot_name);
-        }
-        return;
-    }
-    println!("{}", metadata);
+            .cloned()
+            .ok_or(RvabError::Config(format!("no such slot {}", slot_name)))?],
+        None => metadata.slots.values().cloned().collect(),
+    };
+    slots.sort_by(|a, b| a.slot_name.cmp(&b.slot_name));
+    Ok(SlotListing {
+        is_dirty: metadata.is_dirty(),
+        generation: metadata.generation,
+        current_slot: metadata.current_slot.clone(),
+        slots,
+    })
 }
 
 /// dump current metadata to a config file
-pub fn dump_current_metadata(path: &str) {
-    let metadata = Metadata::from_fw_metadata().unwrap();
+pub fn dump_current_metadata(path: &str) -> Result<(), RvabError> {
+    let metadata = Metadata::from_fw_metadata()?;
     //convert slot toml
     let slots_config = SlotsTomlConfig {
         slot: metadata.slots.values().cloned().collect(),
     };
-    let toml = toml::to_string(&slots_config).unwrap();
-    let mut file = std::fs::File::create(path).expect("Error: create dump file failed");
-    file.write_all(toml.as_bytes())
-        .expect("Error: write dump file failed");
+    let toml = toml::to_string(&slots_config)
+        .map_err(|e| RvabError::Config(format!("unable to convert metadata: {}", e)))?;
+    fs::write(path, toml).map_err(|e| RvabError::io(path, e))
 }
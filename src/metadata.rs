@@ -3,12 +3,19 @@ use serde::{Deserialize, Serialize};
 
 //64MIB
 pub const METADATA_SIZE_BYTES: u64 = 1024 * 1024 * 64;
+//switch journal segment inside metadata segment, toml metadata must stay below it
+pub const METADATA_JOURNAL_OFFSET: u64 = 1024 * 1024 * 16;
+pub const METADATA_JOURNAL_SIZE: u64 = 1024 * 1024 * 16;
+/// on-disk format written by this version, v1 is still read and migrated
+pub const METADATA_FORMAT_VERSION: u16 = 2;
+const METADATA_V2_HEADER_SIZE: u16 = 40;
 
 use crate::backup_factory::BackupType;
 use crate::constants::{
     get_block_dev_dir, METADATA_HEAD_MAGIC, METADATA_PARTITION_NAME, METADATA_TAIL_MAGIC,
-    USERDATA_NAME,
+    METADATA_V2_MAGIC, USERDATA_NAME,
 };
+use crate::error::RvabError;
 use crate::gpt_helper::{
     get_disk_sector_size, get_part_accelerate_location, get_userdata_driver, is_disk_segment_used,
 };
@@ -29,6 +36,106 @@ pub struct Metadata {
     is_dirty: bool,
     pub current_slot: String,
     pub slots: HashMap<String, Slot>,
+    //kept in the v2 header,increased on every write
+    #[serde(skip)]
+    pub generation: u64,
+}
+
+/// Encoding of the v2 metadata payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataEncoding {
+    Toml,
+}
+impl MetadataEncoding {
+    /// Convert a code number from the v2 header to a MetadataEncoding
+    pub fn code2type(code: u16) -> Result<MetadataEncoding, RvabError> {
+        match code {
+            1 => Ok(MetadataEncoding::Toml),
+            _ => Err(RvabError::Integrity(format!(
+                "invalid metadata encoding code {}",
+                code
+            ))),
+        }
+    }
+    /// Convert a MetadataEncoding to a code number for the v2 header
+    pub fn type2code(encoding: MetadataEncoding) -> u16 {
+        match encoding {
+            MetadataEncoding::Toml => 1,
+        }
+    }
+}
+
+/// v2 metadata header at the start of the metadata segment,little endian,followed by the payload
+/// magic[8] version:u16 header_size:u16 encoding:u16 reserved:u16
+/// payload_length:u64 generation:u64 crc32:u32 reserved:u32
+/// crc32 covers the header (with crc32 zeroed) and the payload,
+/// a reader skips header bytes behind the fields it knows using header_size
+#[derive(Debug, Clone)]
+pub struct MetadataHeader {
+    pub version: u16,
+    pub header_size: u16,
+    pub encoding: MetadataEncoding,
+    pub payload_length: u64,
+    pub generation: u64,
+    pub crc32: u32,
+}
+impl MetadataHeader {
+    /// header bytes with crc32 zeroed
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(METADATA_V2_HEADER_SIZE as usize);
+        buffer.extend_from_slice(METADATA_V2_MAGIC.as_bytes());
+        buffer.extend_from_slice(&self.version.to_le_bytes());
+        buffer.extend_from_slice(&self.header_size.to_le_bytes());
+        buffer.extend_from_slice(&MetadataEncoding::type2code(self.encoding).to_le_bytes());
+        buffer.extend_from_slice(&0u16.to_le_bytes());
+        buffer.extend_from_slice(&self.payload_length.to_le_bytes());
+        buffer.extend_from_slice(&self.generation.to_le_bytes());
+        buffer.extend_from_slice(&0u32.to_le_bytes());
+        buffer.extend_from_slice(&0u32.to_le_bytes());
+        buffer
+    }
+
+    /// parse the fixed fields, the crc32 is not checked here
+    fn parse(buffer: &[u8]) -> Result<Self, RvabError> {
+        let u16_at = |x: usize| u16::from_le_bytes([buffer[x], buffer[x + 1]]);
+        let u64_at = |x: usize| u64::from_le_bytes(buffer[x..x + 8].try_into().unwrap_or_default());
+        if buffer.len() < METADATA_V2_HEADER_SIZE as usize
+            || &buffer[..METADATA_V2_MAGIC.len()] != METADATA_V2_MAGIC.as_bytes()
+        {
+            return Err(RvabError::integrity("metadata v2 header magic not match"));
+        };
+        let header = MetadataHeader {
+            version: u16_at(8),
+            header_size: u16_at(10),
+            encoding: MetadataEncoding::code2type(u16_at(12))?,
+            payload_length: u64_at(16),
+            generation: u64_at(24),
+            crc32: u32::from_le_bytes([buffer[32], buffer[33], buffer[34], buffer[35]]),
+        };
+        if header.version != METADATA_FORMAT_VERSION {
+            return Err(RvabError::Integrity(format!(
+                "unsupported metadata format version {}",
+                header.version
+            )));
+        };
+        if header.header_size < METADATA_V2_HEADER_SIZE {
+            return Err(RvabError::Integrity(format!(
+                "metadata header size {} too small",
+                header.header_size
+            )));
+        };
+        Ok(header)
+    }
+
+    /// crc32 of the raw header (header_size bytes) and payload, the crc32 field counts as zero
+    fn checksum(raw_header: &[u8], payload: &[u8]) -> u32 {
+        let mut hasher = Hasher::new();
+        hasher.update(&raw_header[..32]);
+        hasher.update(&[0; 4]);
+        hasher.update(&raw_header[36..]);
+        hasher.update(payload);
+        hasher.finalize()
+    }
 }
 impl fmt::Display for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -49,32 +156,24 @@ impl Metadata {
             is_dirty: false,
             current_slot,
             slots,
+            generation: 0,
         }
     }
     pub fn is_dirty(&self) -> bool {
         self.is_dirty
     }
-    pub fn from_toml(path: &str) -> Result<Self, &'static str> {
-        let toml_str = std::fs::read_to_string(path);
-        if toml_str.is_ok() {
-            let ret = toml::from_str(&toml_str.unwrap());
-            if ret.is_ok() {
-                ret.unwrap()
-            }
-            Err("Unable to parse toml file")
-        } else {
-            Err("Unable to read toml file")
-        }
+    pub fn from_toml(path: &str) -> Result<Self, RvabError> {
+        let toml_str = std::fs::read_to_string(path).map_err(|e| RvabError::io(path, e))?;
+        toml::from_str(&toml_str)
+            .map_err(|e| RvabError::Config(format!("unable to parse {}: {}", path, e)))
     }
 
     /// lazy match current slot (just match userdata)
-    pub fn calculate_current_slot(&mut self) -> Option<String> {
+    /// Ok(None) and current slot "unknown" if no slot matches the live userdata
+    pub fn calculate_current_slot(&mut self) -> Result<Option<String>, RvabError> {
         let mut current_slot = String::new();
         let (driver, _, first_lba, last_lba, sector_size) =
-            get_part_accelerate_location(USERDATA_NAME).unwrap_or(("".to_string(), 0, 0, 0, 0));
-        if driver.is_empty() {
-            return None;
-        };
+            get_part_accelerate_location(USERDATA_NAME)?;
         for (slot_name, slot) in self.slots.iter() {
             let userdata_target = &slot.dyn_partition_set.get(USERDATA_NAME);
             if userdata_target.is_none() {
@@ -87,43 +186,156 @@ impl Metadata {
             {
                 current_slot = slot_name.clone();
                 self.current_slot = current_slot.clone();
-                return Some(current_slot);
+                return Ok(Some(current_slot));
             }
         }
         self.current_slot = "unknown".to_string();
-        None
+        Ok(None)
+    }
+    /// read metadata from fw metadata segment,v2 or v1 layout
+    /// if crc32 not match the user is asked, answering N cancels
+    /// clean v1 metadata is migrated to v2 at once
+    pub fn from_fw_metadata() -> Result<Self, RvabError> {
+        Metadata::read_fw_metadata_at(Metadata::get_current_metadata()?)
     }
-    /// read metadata from fw metadata segment
-    /// panic if crc32 not match and give N option
-    pub fn from_fw_metadata() -> Result<Self, &'static str> {
-        let (main_driver, id, start_lba, end_lba, sector_size) = Metadata::get_current_metadata()?;
+
+    /// read stored metadata from the segments located by the given metadata,e.g. one built
+    /// from a slots config when userdata is missing from the live gpt
+    /// the first segment that reads back is used
+    pub fn from_fw_metadata_located_by(locator: &Metadata) -> Result<Self, RvabError> {
+        let mut ret = Err(RvabError::config("no metadata segment found"));
+        for location in Metadata::get_all_metadata_location(locator)? {
+            ret = Metadata::read_fw_metadata_at(location);
+            match &ret {
+                Ok(_) | Err(RvabError::Cancelled) => break,
+                Err(e) => eprintln!("{}", e),
+            };
+        }
+        ret
+    }
+
+    /// read metadata from the segment (main_driver,id,start_lba,end_lba,sector_size)
+    fn read_fw_metadata_at(
+        (main_driver, _, start_lba, end_lba, sector_size): (String, u32, u64, u64, u64),
+    ) -> Result<Self, RvabError> {
         let offset = start_lba * sector_size;
+        let length = (end_lba - start_lba + 1) * sector_size;
+        let file = File::open(&main_driver).map_err(|e| RvabError::io(&main_driver, e))?;
 
-        //tail reserved 4 bytes for crc32
-        let length = (end_lba - start_lba + 1) * sector_size - 4;
-        // read data from main_driver using offset and limited max length,and read crc32 from tail
-        //the actrual data is between METADATA_HEAD_MAGIC and METADATA_TAIL_MAGIC
-        let mut toml_str = String::new();
-        let crc32 = 0;
-        let mut count: u64 = 0;
+        let mut magic_buffer = [0; METADATA_V2_MAGIC.len()];
+        file.read_exact_at(&mut magic_buffer, offset)
+            .map_err(|e| RvabError::io(&main_driver, e))?;
+        let is_v1 = magic_buffer != METADATA_V2_MAGIC.as_bytes();
+        let (toml_str, crc_match, generation) = if is_v1 {
+            let (toml_str, crc_match) = Metadata::read_v1(&file, &main_driver, offset, length)?;
+            (toml_str, crc_match, 0)
+        } else {
+            Metadata::read_v2(&file, &main_driver, offset, length)?
+        };
 
         //warning : this field on disk is always false to avoid crc32 mess
         let mut is_dirty = false;
+        if !crc_match {
+            is_dirty = true;
+            println!(
+                "Warning: metadata crc32 not match,maybe metadata is dirty.\n\
+            Please update metadata using rvab instead of modify it manually\n"
+            );
+            //ask if continue
+            println!("Force use dirty metadata ? (Y/N) ");
+            let mut input = String::new();
+            std::io::stdin()
+                .read_line(&mut input)
+                .map_err(|e| RvabError::io("stdin", e))?;
+            if input.trim().to_uppercase() != "Y" {
+                return Err(RvabError::Cancelled);
+            };
+        };
+        //parse toml
+        let mut metadata: Metadata = toml::from_str(&toml_str)
+            .map_err(|e| RvabError::Config(format!("unable to parse metadata: {}", e)))?;
+        metadata.is_dirty = is_dirty;
+        metadata.generation = generation;
+        if let Err(e) = metadata.calculate_current_slot() {
+            eprintln!("{}", e);
+            println!(
+                "Warning: userdata not found, current slot {} is taken from metadata",
+                metadata.current_slot
+            );
+        };
+        if is_v1 && !is_dirty {
+            match metadata.write_fw_metadata() {
+                Ok(_) => println!("Metadata migrated to format v{}", METADATA_FORMAT_VERSION),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    println!("Warning: migrate metadata failed, it stays in format v1");
+                }
+            };
+        };
+        Ok(metadata)
+    }
+
+    /// read v2 payload of the metadata segment at offset,return (payload,crc32 match,generation)
+    fn read_v2(
+        file: &File,
+        path: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<(String, bool, u64), RvabError> {
+        let mut raw_header = vec![0; METADATA_V2_HEADER_SIZE as usize];
+        file.read_exact_at(&mut raw_header, offset)
+            .map_err(|e| RvabError::io(path, e))?;
+        let header = MetadataHeader::parse(&raw_header)?;
+        let overflow = || {
+            RvabError::Integrity(format!(
+                "metadata payload length {} overflows its segment",
+                header.payload_length
+            ))
+        };
+        let record_length = (header.header_size as u64)
+            .checked_add(header.payload_length)
+            .ok_or_else(overflow)?;
+        if record_length > length.min(METADATA_JOURNAL_OFFSET) {
+            return Err(overflow());
+        };
+        //header may be longer than the fields known here
+        raw_header.resize(header.header_size as usize, 0);
+        file.read_exact_at(&mut raw_header, offset)
+            .map_err(|e| RvabError::io(path, e))?;
+        let mut payload = vec![0; header.payload_length as usize];
+        file.read_exact_at(&mut payload, offset + header.header_size as u64)
+            .map_err(|e| RvabError::io(path, e))?;
+        let crc_match = MetadataHeader::checksum(&raw_header, &payload) == header.crc32;
+        let toml_str = String::from_utf8(payload)
+            .map_err(|_| RvabError::integrity("metadata payload is not utf-8"))?;
+        Ok((toml_str, crc_match, header.generation))
+    }
+
+    /// read v1 toml between METADATA_HEAD_MAGIC and METADATA_TAIL_MAGIC lines of the
+    /// metadata segment at offset,crc32 is at its tail,return (toml,crc32 match)
+    fn read_v1(
+        file: &File,
+        path: &str,
+        offset: u64,
+        length: u64,
+    ) -> Result<(String, bool), RvabError> {
+        //tail reserved 4 bytes for crc32
+        let length = length - 4;
+        let mut toml_str = String::new();
+        let mut count: u64 = 0;
 
         //check head magic
         let mut magic_head_buffer = [0; METADATA_HEAD_MAGIC.as_bytes().len()];
-        let mut file =
-            File::open(main_driver).map_err(|_| "Error: Failed to open metadata partition")?;
         file.read_exact_at(&mut magic_head_buffer, offset)
-            .map_err(|_| "Error: Failed to read header")?;
+            .map_err(|e| RvabError::io(path, e))?;
         if magic_head_buffer != METADATA_HEAD_MAGIC.as_bytes() {
-            return Err("Error: metadata head magic not match");
+            return Err(RvabError::integrity("metadata head magic not match"));
         };
         count += magic_head_buffer.len() as u64;
-        let mut reader = BufReader::new(&file);
+        let mut reader = BufReader::new(file);
         reader
             .seek(SeekFrom::Start(offset + (magic_head_buffer.len() as u64)))
-            .map_err(|_| "Error: Failed to seek")?;
+            .map_err(|e| RvabError::io(path, e))?;
         for line in reader.lines() {
             if line.is_ok() {
                 let lin_str = line.unwrap();
@@ -140,57 +352,18 @@ impl Metadata {
         //read crc32
         let mut crc32_buffer = [0; 4];
         file.read_exact_at(&mut crc32_buffer, offset + length)
-            .map_err(|_| "Error: Failed to read crc32")?;
-        //get crc32
+            .map_err(|e| RvabError::io(path, e))?;
         let crc32 = u32::from_le_bytes(crc32_buffer);
         //calculate string crc32
         let mut hasher = Hasher::new();
         hasher.update(toml_str.as_bytes());
-        let checksum = hasher.finalize();
-        if checksum != crc32 {
-            is_dirty = true;
-            println!(
-                "Warning: metadata crc32 not match,maybe metadata is dirty.\n\
-            Please update metadata using rvab instead of modify it manually\n"
-            );
-            //ask if continue
-            println!("Force use dirty metadata ? (Y/N) ");
-            let mut input = String::new();
-            std::io::stdin()
-                .read_line(&mut input)
-                .expect("Error: Failed to read line");
-            if input.trim().to_uppercase() != "Y" {
-                panic!("Exit ...")
-            };
-        };
-        //parse toml
-        let mut metadata: Metadata =
-            toml::from_str(&toml_str).map_err(|_| "Failed to parse toml")?;
-        metadata.is_dirty = is_dirty;
-        metadata.calculate_current_slot();
-        Ok(metadata)
+        Ok((toml_str, hasher.finalize() == crc32))
     }
-    pub fn write_fw_metadata(&mut self) -> Result<(), &'static str> {
-        let metadata = Metadata::get_all_metadata_location(&self)?;
 
-        //warning : this field on disk is always false to avoid crc32 mess
-        let is_dirty_backup = self.is_dirty;
-        self.is_dirty = false;
-        let mut toml_str =
-            toml_to_string(&self).map_err(|_| "Error: Failed to convert to toml str")?;
-        //add \n to avoid read overflow
-        toml_str.push('\n');
-
-        //cal crc32
-        let mut hasher = Hasher::new();
-        hasher.update(toml_str.as_bytes());
-        let checksum = hasher.finalize();
-        let crc32_buffer = checksum.to_le_bytes();
-
-        toml_str.push_str(METADATA_TAIL_MAGIC);
-        toml_str.push('\n');
-        //restore is_dirty
-        self.is_dirty = is_dirty_backup;
+    /// write metadata in v2 layout to the metadata segment of every slot
+    pub fn write_fw_metadata(&mut self) -> Result<(), RvabError> {
+        let metadata = Metadata::get_all_metadata_location(&self)?;
+        let (record, generation) = self.to_v2_record()?;
 
         for (main_driver, _, start_lba, end_lba, sector_size) in metadata {
             if cfg!(debug_assertions) {
@@ -200,40 +373,55 @@ impl Metadata {
                 println!("Sector size: {}", sector_size);
             }
             let offset = start_lba * sector_size;
-
-            //tail reserved 4 bytes for crc32
-            let max_length = (end_lba - start_lba + 1) * sector_size - 4;
-
-            //write head magic
-            let magic_head_buffer = METADATA_HEAD_MAGIC.as_bytes();
-            let mut file = OpenOptions::new()
-                .write(true)
-                .open(main_driver)
-                .map_err(|_| "Error: Failed to open metadata partition")?;
-            file.write_all_at(&magic_head_buffer, offset)
-                .map_err(|_| "Error: Failed to write header")?;
-
-            if (toml_str.as_str().len() + magic_head_buffer.len()) as u64 > max_length {
-                return Err("Error: metadata size overflow");
+            let max_length = (end_lba - start_lba + 1) * sector_size;
+            if record.len() as u64 > max_length.min(METADATA_JOURNAL_OFFSET) {
+                return Err(RvabError::layout("metadata size overflow"));
             };
-            file.write_all_at(
-                toml_str.as_bytes(),
-                offset + (magic_head_buffer.len() as u64),
-            )
-            .map_err(|_| "Error: Failed to write to metadata partition")?;
-
-            file.write_all_at(&crc32_buffer, offset + max_length)
-                .map_err(|_| "Error: Failed to write crc32");
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&main_driver)
+                .map_err(|e| RvabError::io(&main_driver, e))?;
+            file.write_all_at(&record, offset)
+                .map_err(|e| RvabError::io(&main_driver, e))?;
+            file.sync_all()
+                .map_err(|e| RvabError::io(&main_driver, e))?;
         }
+        self.generation = generation;
         Ok(())
     }
 
+    /// encode header and toml payload of the next generation,return (record,generation)
+    fn to_v2_record(&mut self) -> Result<(Vec<u8>, u64), RvabError> {
+        //warning : this field on disk is always false to avoid crc32 mess
+        let is_dirty_backup = self.is_dirty;
+        self.is_dirty = false;
+        let toml_str = toml_to_string(&self)
+            .map_err(|e| RvabError::Config(format!("unable to convert metadata: {}", e)));
+        //restore is_dirty
+        self.is_dirty = is_dirty_backup;
+        let toml_str = toml_str?;
+
+        let header = MetadataHeader {
+            version: METADATA_FORMAT_VERSION,
+            header_size: METADATA_V2_HEADER_SIZE,
+            encoding: MetadataEncoding::Toml,
+            payload_length: toml_str.len() as u64,
+            generation: self.generation + 1,
+            crc32: 0,
+        };
+        let mut record = header.to_bytes();
+        let checksum = MetadataHeader::checksum(&record, toml_str.as_bytes());
+        record[32..36].copy_from_slice(&checksum.to_le_bytes());
+        record.extend_from_slice(toml_str.as_bytes());
+        Ok((record, header.generation))
+    }
+
     /// search and check all metadata partition ret vec (main_driver,id,start_lba,end_lba,sector_size)
     /// search priority: name mapped block device -> hidden segment
     /// id = 0 means hidden segment
     pub fn get_all_metadata_location(
         metadata: &Metadata,
-    ) -> Result<Vec<(String, u32, u64, u64, u64)>, &'static str> {
+    ) -> Result<Vec<(String, u32, u64, u64, u64)>, RvabError> {
         let mut ret = Vec::new();
         //first test name mapped block device
         let name_ret = get_part_accelerate_location(METADATA_PARTITION_NAME);
@@ -246,25 +434,23 @@ impl Metadata {
         for (_, slot) in metadata.slots.iter() {
             let userdata_target = slot.dyn_partition_set.get(USERDATA_NAME);
             if userdata_target.is_none() {
-                return Err("Error: userdata partition not found");
+                return Err(RvabError::config("userdata partition not found in slot"));
             };
             let userdata_target = userdata_target.unwrap();
             let userdata_driver = userdata_target.driver.clone();
             let userdata_start_lba = userdata_target.start_lba;
-            let sector_size = get_disk_sector_size(&userdata_driver);
+            let sector_size = get_disk_sector_size(&userdata_driver)?;
             //check hidden segment
             let (metadata_start_lba, metadata_end_lba) =
                 calculate_metadata_interval_from_low(userdata_start_lba - 1, sector_size);
             //check overflows
             let overflows =
-                is_disk_segment_used(&userdata_driver, metadata_start_lba, metadata_end_lba);
-            if overflows.is_some() {
-                let error_str = format!(
-                    "Error: metadata partition overflows at {:?}",
-                    overflows.unwrap()
-                );
-                eprintln!("{}", error_str.as_str());
-                return Err("Error: metadata partition overflows");
+                is_disk_segment_used(&userdata_driver, metadata_start_lba, metadata_end_lba)?;
+            if let Some(parts) = overflows {
+                return Err(RvabError::Layout(format!(
+                    "metadata partition overflows at {:?}",
+                    parts
+                )));
             };
             ret.push((
                 userdata_driver,
@@ -278,25 +464,20 @@ impl Metadata {
     }
 
     ///get current metadata
-    pub fn get_current_metadata() -> Result<(String, u32, u64, u64, u64), &'static str> {
-        let userdata_location = get_part_accelerate_location(USERDATA_NAME);
-        if userdata_location.is_err() {
-            return Err("Error: userdata partition not found");
-        };
-        let (userdata_driver, _, userdata_start_lba, _, sector_size) = userdata_location.unwrap();
+    pub fn get_current_metadata() -> Result<(String, u32, u64, u64, u64), RvabError> {
+        let (userdata_driver, _, userdata_start_lba, _, sector_size) =
+            get_part_accelerate_location(USERDATA_NAME)?;
         //check hidden segment
         let (metadata_start_lba, metadata_end_lba) =
             calculate_metadata_interval_from_low(userdata_start_lba - 1, sector_size);
         //check overflows
         let overflows =
-            is_disk_segment_used(&userdata_driver, metadata_start_lba, metadata_end_lba);
-        if overflows.is_some() {
-            let error_str = format!(
-                "Error: metadata partition overflows at {:?}",
-                overflows.unwrap()
-            );
-            eprintln!("{}", error_str.as_str());
-            return Err("Error: metadata partition overflows");
+            is_disk_segment_used(&userdata_driver, metadata_start_lba, metadata_end_lba)?;
+        if let Some(parts) = overflows {
+            return Err(RvabError::Layout(format!(
+                "metadata partition overflows at {:?}",
+                parts
+            )));
         };
         Ok((
             userdata_driver,
@@ -319,11 +500,23 @@ pub struct Slot {
     pub backup_target_attr: String,
     //reserve for other back_trait
     pub dyn_partition_set: HashMap<String, PartitionRawTarget>, // parts to be made into dyn partition,(part_name,part_target)
+    //android A/B slot (a/b) the slot runs on, empty if unknown
+    #[serde(default)]
+    pub android_slot: String,
+}
+impl Slot {
+    /// partitions not stored by backup backends: backup exclude list and all dyn partitions
+    pub fn backup_exclude_set(&self) -> HashSet<String> {
+        let mut exclude_list = HashSet::new();
+        exclude_list.extend(self.backup_exclude_list.iter().cloned());
+        exclude_list.extend(self.dyn_partition_set.keys().cloned());
+        exclude_list
+    }
 }
 impl fmt::Display for Slot {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Slot Name: {}\nBackup Type Code: {}\nBackup Target: {}\nBackup Target Start: {}\nBackup Target End: {}\nBackup Target Attr: {}\n",
-               self.slot_name, self.backup_type_code, self.backup_target, self.backup_target_start, self.backup_target_end, self.backup_target_attr)?;
+        write!(f, "Slot Name: {}\nBackup Type Code: {}\nBackup Target: {}\nBackup Target Start: {}\nBackup Target End: {}\nBackup Target Attr: {}\nAndroid Slot: {}\n",
+               self.slot_name, self.backup_type_code, self.backup_target, self.backup_target_start, self.backup_target_end, self.backup_target_attr, self.android_slot)?;
 
         write!(f, "Backup Exclude List:\n")?;
         let mut counter = 0;
@@ -377,3 +570,157 @@ pub fn calculate_metadata_interval_from_low(end_lba: u64, sector: u64) -> (u64,
     start_lba += 1;
     (start_lba, end_lba)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const SAMPLE: &str = r#"
+is_dirty = false
+current_slot = "a"
+
+[slots.a]
+slot_name = "a"
+backup_type_code = 1
+backup_target = "/dev/block/sda"
+backup_exclude_list = ["persist"]
+backup_target_start = 100
+backup_target_end = 200
+backup_target_attr = ""
+android_slot = "a"
+
+[slots.a.dyn_partition_set.userdata]
+part_name = "userdata"
+driver = "/dev/block/sda"
+start_lba = 300
+end_lba = 400
+type_guid = "0fc63daf-8483-4772-8e79-3d69d8477de4"
+flags = 0
+"#;
+    const OFFSET: u64 = 512;
+    const LENGTH: u64 = 64 * 1024;
+
+    fn sample() -> Metadata {
+        toml::from_str(SAMPLE).unwrap()
+    }
+
+    //segment image at OFFSET in a scratch file
+    fn segment_file(name: &str, record: &[u8]) -> (File, String) {
+        let path = std::env::temp_dir()
+            .join(format!("rvab_metadata_{}_{}", name, std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&vec![0; (OFFSET + LENGTH) as usize])
+            .unwrap();
+        file.write_all_at(record, OFFSET).unwrap();
+        (file, path)
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut metadata = sample();
+        metadata.generation = 6;
+        let (record, generation) = metadata.to_v2_record().unwrap();
+        assert_eq!(generation, 7);
+
+        let header = MetadataHeader::parse(&record).unwrap();
+        assert_eq!(header.version, METADATA_FORMAT_VERSION);
+        assert_eq!(header.header_size, METADATA_V2_HEADER_SIZE);
+        assert_eq!(header.encoding, MetadataEncoding::Toml);
+        assert_eq!(
+            header.payload_length,
+            (record.len() - METADATA_V2_HEADER_SIZE as usize) as u64
+        );
+        assert_eq!(header.generation, 7);
+
+        let (file, path) = segment_file("round_trip", &record);
+        let (toml_str, crc_match, generation) =
+            Metadata::read_v2(&file, &path, OFFSET, LENGTH).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(crc_match);
+        assert_eq!(generation, 7);
+        let read: Metadata = toml::from_str(&toml_str).unwrap();
+        assert_eq!(read.current_slot, "a");
+        assert_eq!(read.slots["a"].android_slot, "a");
+        assert_eq!(read.slots["a"].backup_target_end, 200);
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let (mut record, _) = sample().to_v2_record().unwrap();
+        let last = record.len() - 2;
+        record[last] ^= 0x01;
+        let (file, path) = segment_file("crc_mismatch", &record);
+        let (_, crc_match, _) = Metadata::read_v2(&file, &path, OFFSET, LENGTH).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!crc_match);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let (record, _) = sample().to_v2_record().unwrap();
+        let ret = MetadataHeader::parse(&record[..METADATA_V2_HEADER_SIZE as usize - 1]);
+        assert!(matches!(ret, Err(RvabError::Integrity(_))));
+        let ret = MetadataHeader::parse(&record[..4]);
+        assert!(matches!(ret, Err(RvabError::Integrity(_))));
+    }
+
+    #[test]
+    fn payload_length_overflow() {
+        let (mut record, _) = sample().to_v2_record().unwrap();
+        record[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        let (file, path) = segment_file("overflow", &record);
+        let ret = Metadata::read_v2(&file, &path, OFFSET, LENGTH);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(ret, Err(RvabError::Integrity(_))));
+    }
+
+    #[test]
+    fn unknown_encoding() {
+        let ret = MetadataEncoding::code2type(7);
+        assert!(matches!(ret, Err(RvabError::Integrity(_))));
+    }
+
+    #[test]
+    fn migrate_v1_to_v2() {
+        //v1 layout: head magic,toml,tail magic line,crc32 of the toml in the last 4 bytes
+        let mut toml_str = toml_to_string(&sample()).unwrap();
+        toml_str.push('\n');
+        let mut hasher = Hasher::new();
+        hasher.update(toml_str.as_bytes());
+        let mut record = METADATA_HEAD_MAGIC.as_bytes().to_vec();
+        record.extend_from_slice(toml_str.as_bytes());
+        record.extend_from_slice(format!("{}\n", METADATA_TAIL_MAGIC).as_bytes());
+        let (file, path) = segment_file("migrate", &record);
+        file.write_all_at(&hasher.finalize().to_le_bytes(), OFFSET + LENGTH - 4)
+            .unwrap();
+
+        let (v1_str, crc_match) = Metadata::read_v1(&file, &path, OFFSET, LENGTH).unwrap();
+        assert!(crc_match);
+        let mut metadata: Metadata = toml::from_str(&v1_str).unwrap();
+        let (record, generation) = metadata.to_v2_record().unwrap();
+        assert_eq!(generation, 1);
+        file.write_all_at(&record, OFFSET).unwrap();
+
+        let (v2_str, crc_match, generation) =
+            Metadata::read_v2(&file, &path, OFFSET, LENGTH).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(crc_match);
+        assert_eq!(generation, 1);
+        let migrated: Metadata = toml::from_str(&v2_str).unwrap();
+        assert_eq!(migrated.current_slot, metadata.current_slot);
+        assert_eq!(migrated.slots["a"].android_slot, "a");
+        assert_eq!(
+            migrated.slots["a"].dyn_partition_set["userdata"].start_lba,
+            300
+        );
+    }
+}
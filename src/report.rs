@@ -0,0 +1,426 @@
+///report module
+/// Results of library functions, rendered by the cli as text or as json documents
+use crate::backup_factory::{BackupType, VerifyReport, VerifyStatus};
+use crate::error::RvabError;
+use crate::gpt_helper::{bytes2ieee, get_disk_sector_size};
+use crate::metadata::{PartitionRawTarget, Slot};
+use serde::Serialize;
+use std::fmt;
+
+/// Firmware size check of a slot
+#[derive(Debug, Clone)]
+pub struct FirmwareSizeCheck {
+    /// number of firmware partitions
+    pub firmware_count: u64,
+    /// raw size of all firmware partitions
+    pub firmware_size: u64,
+    /// size the firmware takes on the backup target,the raw size if it can not be measured
+    pub stored_size: u64,
+    pub backup_target: String,
+    /// None if the backup target is remote
+    pub backup_target_size: Option<u64>,
+    /// measuring the stored size failed
+    pub error: Option<String>,
+}
+impl FirmwareSizeCheck {
+    /// true if the firmware fits into the backup target
+    pub fn is_pass(&self) -> bool {
+        self.error.is_none()
+            && self
+                .backup_target_size
+                .is_none_or(|size| self.stored_size <= size)
+    }
+}
+
+/// All checks of a slot
+#[derive(Debug, Clone)]
+pub struct SlotCheck {
+    pub slot_name: String,
+    /// 1 firmware size is not larger than backup target size
+    pub firmware: FirmwareSizeCheck,
+    /// 2 no part overlaps and nothing overflows disk size,Some(error) if failed
+    pub layout_error: Option<String>,
+}
+impl SlotCheck {
+    pub fn is_pass(&self) -> bool {
+        self.firmware.is_pass() && self.layout_error.is_none()
+    }
+}
+
+/// Result of checking a slots config file
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    pub config_path: String,
+    pub slots: Vec<SlotCheck>,
+}
+impl CheckReport {
+    /// true if every check of every slot passed
+    pub fn is_pass(&self) -> bool {
+        self.slots.iter().all(|x| x.is_pass())
+    }
+}
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for slot in self.slots.iter() {
+            writeln!(f, "Checking for slot : {}", slot.slot_name)?;
+            let fw = &slot.firmware;
+            match fw.backup_target_size {
+                None => writeln!(
+                    f,
+                    "\t1 Pass: firmware size {} bytes is stored on remote backup target {}",
+                    fw.firmware_size, fw.backup_target
+                )?,
+                Some(target_size) => {
+                    if let Some(err) = &fw.error {
+                        writeln!(f, "\t1 {}", err)?;
+                    };
+                    if fw.stored_size > target_size {
+                        writeln!(f, "\t1 Error: firmware size {} bytes is larger than backup target size {} bytes , {} > {}",
+                                 fw.stored_size, target_size, bytes2ieee(fw.stored_size), bytes2ieee(target_size))?;
+                    } else {
+                        writeln!(f, "\t1 Pass: firmware size {} bytes is smaller than backup target size {} bytes , {} < {}",
+                                 fw.stored_size, target_size, bytes2ieee(fw.stored_size), bytes2ieee(target_size))?;
+                    }
+                }
+            };
+            match &slot.layout_error {
+                Some(err) => writeln!(f, "\t2 {}", err)?,
+                None => writeln!(f, "\t2 Pass: no overlaps and no overflow disk size")?,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Slots recorded in metadata
+#[derive(Debug, Clone)]
+pub struct SlotListing {
+    pub is_dirty: bool,
+    /// metadata generation,0 if read from a v1 layout
+    pub generation: u64,
+    pub current_slot: String,
+    /// sorted by slot name
+    pub slots: Vec<Slot>,
+}
+impl fmt::Display for SlotListing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Is Dirty: {}\nGeneration: {}\nCurrent Slot: {}\n\n",
+            self.is_dirty, self.generation, self.current_slot
+        )?;
+        for slot in &self.slots {
+            writeln!(f, "{}", slot)?;
+        }
+        Ok(())
+    }
+}
+
+/// One dyn partition cloned to its place in a slot
+#[derive(Debug, Clone)]
+pub struct ClonedPart {
+    pub slot_name: String,
+    pub part_name: String,
+    pub source: String,
+    /// offset in bytes
+    pub source_offset: u64,
+    pub target: String,
+    /// offset in bytes
+    pub target_offset: u64,
+    pub length: u64,
+}
+
+/// Result of cloning firmware to all slots
+#[derive(Debug, Clone, Default)]
+pub struct CloneReport {
+    pub parts: Vec<ClonedPart>,
+}
+impl CloneReport {
+    pub fn total_bytes(&self) -> u64 {
+        self.parts.iter().map(|x| x.length).sum()
+    }
+}
+impl fmt::Display for CloneReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Cloned {} partitions , {}",
+            self.parts.len(),
+            bytes2ieee(self.total_bytes())
+        )?;
+        for part in self.parts.iter() {
+            writeln!(
+                f,
+                "\t{:<16} slot {} : {}@{} -> {}@{} {}",
+                part.part_name,
+                part.slot_name,
+                part.source,
+                part.source_offset,
+                part.target,
+                part.target_offset,
+                bytes2ieee(part.length)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Version of the json documents, bumped on any incompatible change
+/// fields may be added without bumping it
+pub const JSON_SCHEMA_VERSION: u32 = 2;
+
+/// Top level json document: {"schema_version":2,"kind":"list","data":{...}}
+#[derive(Debug, Serialize)]
+pub struct JsonDocument<T: Serialize> {
+    pub schema_version: u32,
+    /// list , current , check , verify or error
+    pub kind: &'static str,
+    pub data: T,
+}
+impl<T: Serialize> JsonDocument<T> {
+    pub fn new(kind: &'static str, data: T) -> Self {
+        JsonDocument {
+            schema_version: JSON_SCHEMA_VERSION,
+            kind,
+            data,
+        }
+    }
+    pub fn to_json(&self) -> Result<String, RvabError> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| RvabError::Config(format!("unable to convert to json: {}", e)))
+    }
+}
+
+/// A size in bytes with its bytes2ieee form
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonSize {
+    pub bytes: u64,
+    pub human: String,
+}
+impl JsonSize {
+    pub fn new(bytes: u64) -> Self {
+        JsonSize {
+            bytes,
+            human: bytes2ieee(bytes),
+        }
+    }
+}
+
+/// A PartitionRawTarget,size is null if the sector size of driver can not be read
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonPartition {
+    pub part_name: String,
+    pub driver: String,
+    pub start_lba: u64,
+    pub end_lba: u64,
+    pub sector_size: Option<u64>,
+    pub size: Option<JsonSize>,
+    pub type_guid: String,
+    pub flags: u64,
+}
+impl JsonPartition {
+    pub fn from_raw_target(part: &PartitionRawTarget) -> Self {
+        let sector_size = get_disk_sector_size(&part.driver).ok();
+        JsonPartition {
+            part_name: part.part_name.clone(),
+            driver: part.driver.clone(),
+            start_lba: part.start_lba,
+            end_lba: part.end_lba,
+            sector_size,
+            size: sector_size.map(|x| JsonSize::new((part.end_lba - part.start_lba + 1) * x)),
+            type_guid: part.type_guid.clone(),
+            flags: part.flags,
+        }
+    }
+}
+
+/// A Slot,lists are sorted by name
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonSlot {
+    pub slot_name: String,
+    /// a or b , empty if unknown
+    pub android_slot: String,
+    pub backup_type_code: i32,
+    /// partition , binaryspace , losetup , ftp , or empty for an unknown code
+    pub backup_type: String,
+    pub backup_target: String,
+    pub backup_target_start: u64,
+    pub backup_target_end: u64,
+    /// null for a remote backup target or if the sector size can not be read
+    pub backup_target_size: Option<JsonSize>,
+    pub backup_target_attr: String,
+    pub backup_exclude_list: Vec<String>,
+    pub dyn_partitions: Vec<JsonPartition>,
+}
+impl JsonSlot {
+    pub fn from_slot(slot: &Slot) -> Self {
+        let backup_type = BackupType::code2type(slot.backup_type_code).ok();
+        let backup_target_size = match backup_type {
+            Some(BackupType::Ftp) => None,
+            _ => get_disk_sector_size(&slot.backup_target).ok().map(|x| {
+                JsonSize::new((slot.backup_target_end - slot.backup_target_start + 1) * x)
+            }),
+        };
+        let mut backup_exclude_list: Vec<String> =
+            slot.backup_exclude_list.iter().cloned().collect();
+        backup_exclude_list.sort();
+        let mut dyn_partitions: Vec<JsonPartition> = slot
+            .dyn_partition_set
+            .values()
+            .map(JsonPartition::from_raw_target)
+            .collect();
+        dyn_partitions.sort_by(|a, b| a.part_name.cmp(&b.part_name));
+        JsonSlot {
+            slot_name: slot.slot_name.clone(),
+            android_slot: slot.android_slot.clone(),
+            backup_type_code: slot.backup_type_code,
+            backup_type: backup_type.map_or(String::new(), |x| x.to_string()),
+            backup_target: slot.backup_target.clone(),
+            backup_target_start: slot.backup_target_start,
+            backup_target_end: slot.backup_target_end,
+            backup_target_size,
+            backup_target_attr: slot.backup_target_attr.clone(),
+            backup_exclude_list,
+            dyn_partitions,
+        }
+    }
+}
+
+/// kind "list": Metadata with its slots
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonListing {
+    pub is_dirty: bool,
+    pub generation: u64,
+    pub current_slot: String,
+    pub slots: Vec<JsonSlot>,
+}
+impl From<&SlotListing> for JsonListing {
+    fn from(listing: &SlotListing) -> Self {
+        JsonListing {
+            is_dirty: listing.is_dirty,
+            generation: listing.generation,
+            current_slot: listing.current_slot.clone(),
+            slots: listing.slots.iter().map(JsonSlot::from_slot).collect(),
+        }
+    }
+}
+
+/// kind "current"
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonCurrent {
+    pub current_slot: JsonSlot,
+}
+
+/// firmware size check of a JsonSlotCheck
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonFirmwareCheck {
+    pub pass: bool,
+    pub firmware_count: u64,
+    pub firmware_size: JsonSize,
+    pub stored_size: JsonSize,
+    pub backup_target: String,
+    /// null if the backup target is remote
+    pub backup_target_size: Option<JsonSize>,
+    pub error: Option<String>,
+}
+
+/// layout check of a JsonSlotCheck
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonLayoutCheck {
+    pub pass: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonSlotCheck {
+    pub slot_name: String,
+    pub pass: bool,
+    pub firmware: JsonFirmwareCheck,
+    pub layout: JsonLayoutCheck,
+}
+
+/// kind "check"
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonCheck {
+    pub config_path: String,
+    pub pass: bool,
+    pub slots: Vec<JsonSlotCheck>,
+}
+impl From<&CheckReport> for JsonCheck {
+    fn from(report: &CheckReport) -> Self {
+        JsonCheck {
+            config_path: report.config_path.clone(),
+            pass: report.is_pass(),
+            slots: report
+                .slots
+                .iter()
+                .map(|slot| JsonSlotCheck {
+                    slot_name: slot.slot_name.clone(),
+                    pass: slot.is_pass(),
+                    firmware: JsonFirmwareCheck {
+                        pass: slot.firmware.is_pass(),
+                        firmware_count: slot.firmware.firmware_count,
+                        firmware_size: JsonSize::new(slot.firmware.firmware_size),
+                        stored_size: JsonSize::new(slot.firmware.stored_size),
+                        backup_target: slot.firmware.backup_target.clone(),
+                        backup_target_size: slot.firmware.backup_target_size.map(JsonSize::new),
+                        error: slot.firmware.error.clone(),
+                    },
+                    layout: JsonLayoutCheck {
+                        pass: slot.layout_error.is_none(),
+                        error: slot.layout_error.clone(),
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonVerifyEntry {
+    pub name: String,
+    /// match , mismatch , not_stored , no_live_partition or size_changed
+    pub status: &'static str,
+    /// null if there is no stored image
+    pub stored_size: Option<JsonSize>,
+    /// null if there is no live partition
+    pub live_size: Option<JsonSize>,
+}
+
+/// kind "verify"
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonVerify {
+    pub slot_name: String,
+    pub pass: bool,
+    pub entries: Vec<JsonVerifyEntry>,
+}
+impl From<&VerifyReport> for JsonVerify {
+    fn from(report: &VerifyReport) -> Self {
+        JsonVerify {
+            slot_name: report.slot_name.clone(),
+            pass: report.is_match(),
+            entries: report
+                .entries
+                .iter()
+                .map(|entry| JsonVerifyEntry {
+                    name: entry.name.clone(),
+                    status: match entry.status {
+                        VerifyStatus::Match => "match",
+                        VerifyStatus::Mismatch => "mismatch",
+                        VerifyStatus::NotStored => "not_stored",
+                        VerifyStatus::NoLivePartition => "no_live_partition",
+                        VerifyStatus::SizeChanged => "size_changed",
+                    },
+                    stored_size: entry.stored_length.map(JsonSize::new),
+                    live_size: entry.live_length.map(JsonSize::new),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// kind "error"
+#[derive(Debug, Clone, Serialize)]
+pub struct JsonError {
+    pub message: String,
+}
@@ -0,0 +1,425 @@
+///android sparse image helper module
+/// Android sparse images (simg) are understood by fastboot and simg2img:
+/// file head (magic,version,head sizes,block size,total blocks,total chunks,checksum),
+/// then chunks, each a chunk head (type u16,reserved u16,blocks u32,total bytes u32) and its data.
+/// RAW chunks carry the blocks, FILL chunks a 4 byte pattern, DONT_CARE chunks nothing.
+/// The encoder cuts the image into fixed SIMG_GROUP_SIZE chunks so the chunk count is known
+/// before any data is seen, all-zero groups become FILL chunks, everything else RAW chunks.
+use std::io::{Read, Write};
+
+pub const SIMG_MAGIC: u32 = 0xED26FF3A;
+const SIMG_MAJOR_VERSION: u16 = 1;
+const SIMG_FILE_HEAD_LEN: usize = 28;
+const SIMG_CHUNK_HEAD_LEN: usize = 12;
+const SIMG_CHUNK_RAW: u16 = 0xCAC1;
+const SIMG_CHUNK_FILL: u16 = 0xCAC2;
+const SIMG_CHUNK_DONT_CARE: u16 = 0xCAC3;
+const SIMG_CHUNK_CRC32: u16 = 0xCAC4;
+/// logical bytes per encoded chunk
+pub const SIMG_GROUP_SIZE: u64 = 1024 * 1024;
+
+/// true if head starts with the sparse image magic
+pub fn is_simg(head: &[u8]) -> bool {
+    head.len() >= 4 && u32::from_le_bytes(head[..4].try_into().unwrap()) == SIMG_MAGIC
+}
+
+fn chunk_head(kind: u16, blocks: u32, total: u32) -> [u8; SIMG_CHUNK_HEAD_LEN] {
+    let mut head = [0; SIMG_CHUNK_HEAD_LEN];
+    head[..2].copy_from_slice(&kind.to_le_bytes());
+    head[4..8].copy_from_slice(&blocks.to_le_bytes());
+    head[8..].copy_from_slice(&total.to_le_bytes());
+    head
+}
+
+/// Encodes an image of known length into a sparse image, feed with `push`
+pub struct SimgEncoder {
+    length: u64,
+    block_size: u32,
+    group: Vec<u8>,
+    done: u64,
+}
+
+impl SimgEncoder {
+    pub fn new(length: u64, block_size: u32) -> Result<Self, &'static str> {
+        if block_size == 0
+            || !block_size.is_multiple_of(4)
+            || !SIMG_GROUP_SIZE.is_multiple_of(block_size as u64)
+            || !length.is_multiple_of(block_size as u64)
+        {
+            return Err("Error: image length does not fit sparse block size");
+        };
+        Ok(SimgEncoder {
+            length,
+            block_size,
+            group: Vec::with_capacity(SIMG_GROUP_SIZE as usize),
+            done: 0,
+        })
+    }
+
+    /// upper bound of the encoded length of an image
+    pub fn max_length(length: u64) -> u64 {
+        SIMG_FILE_HEAD_LEN as u64
+            + length.div_ceil(SIMG_GROUP_SIZE) * SIMG_CHUNK_HEAD_LEN as u64
+            + length
+    }
+
+    /// file head, written before any chunk
+    pub fn head(&self) -> [u8; SIMG_FILE_HEAD_LEN] {
+        let mut head = [0; SIMG_FILE_HEAD_LEN];
+        head[..4].copy_from_slice(&SIMG_MAGIC.to_le_bytes());
+        head[4..6].copy_from_slice(&SIMG_MAJOR_VERSION.to_le_bytes());
+        head[8..10].copy_from_slice(&(SIMG_FILE_HEAD_LEN as u16).to_le_bytes());
+        head[10..12].copy_from_slice(&(SIMG_CHUNK_HEAD_LEN as u16).to_le_bytes());
+        head[12..16].copy_from_slice(&self.block_size.to_le_bytes());
+        head[16..20]
+            .copy_from_slice(&((self.length / self.block_size as u64) as u32).to_le_bytes());
+        head[20..24].copy_from_slice(&(self.length.div_ceil(SIMG_GROUP_SIZE) as u32).to_le_bytes());
+        head
+    }
+
+    /// take image bytes, write finished chunks to out
+    pub fn push(&mut self, mut data: &[u8], out: &mut dyn Write) -> std::io::Result<()> {
+        if self.done + self.group.len() as u64 + data.len() as u64 > self.length {
+            return Err(std::io::Error::other("sparse image overflow"));
+        };
+        while !data.is_empty() {
+            let size = (SIMG_GROUP_SIZE as usize - self.group.len()).min(data.len());
+            self.group.extend_from_slice(&data[..size]);
+            data = &data[size..];
+            if self.group.len() == SIMG_GROUP_SIZE as usize {
+                self.write_group(out)?;
+            };
+        }
+        Ok(())
+    }
+
+    /// write the last chunk, fail if the image is not complete
+    pub fn finish(mut self, out: &mut dyn Write) -> std::io::Result<()> {
+        self.write_group(out)?;
+        if self.done != self.length {
+            return Err(std::io::Error::other("sparse image truncated"));
+        };
+        Ok(())
+    }
+
+    fn write_group(&mut self, out: &mut dyn Write) -> std::io::Result<()> {
+        if self.group.is_empty() {
+            return Ok(());
+        };
+        let blocks = (self.group.len() / self.block_size as usize) as u32;
+        if self.group.iter().all(|&x| x == 0) {
+            out.write_all(&chunk_head(
+                SIMG_CHUNK_FILL,
+                blocks,
+                (SIMG_CHUNK_HEAD_LEN + 4) as u32,
+            ))?;
+            out.write_all(&[0; 4])?;
+        } else {
+            out.write_all(&chunk_head(
+                SIMG_CHUNK_RAW,
+                blocks,
+                (SIMG_CHUNK_HEAD_LEN + self.group.len()) as u32,
+            ))?;
+            out.write_all(&self.group)?;
+        };
+        self.done += self.group.len() as u64;
+        self.group.clear();
+        Ok(())
+    }
+}
+
+/// Chunk being expanded by `SimgReader`
+enum SimgChunk {
+    Raw(u64),
+    Fill([u8; 4], u64),
+    Zero(u64),
+}
+
+/// Reads a sparse image and yields the expanded image, DONT_CARE blocks read as zeros
+pub struct SimgReader<R: Read> {
+    inner: R,
+    block_size: u64,
+    chunk_head_len: usize,
+    chunks_left: u32,
+    length: u64,
+    done: u64,
+    chunk: SimgChunk,
+}
+
+impl<R: Read> SimgReader<R> {
+    pub fn new(mut inner: R) -> Result<Self, &'static str> {
+        let mut head = [0; SIMG_FILE_HEAD_LEN];
+        inner
+            .read_exact(&mut head)
+            .map_err(|_| "Error: read sparse image failed")?;
+        let field16 = |i: usize| u16::from_le_bytes(head[i..i + 2].try_into().unwrap());
+        let field32 = |i: usize| u32::from_le_bytes(head[i..i + 4].try_into().unwrap());
+        if field32(0) != SIMG_MAGIC || field16(4) != SIMG_MAJOR_VERSION {
+            return Err("Error: unsupported sparse image");
+        };
+        let file_head_len = field16(8) as usize;
+        let chunk_head_len = field16(10) as usize;
+        let block_size = field32(12) as u64;
+        if file_head_len < SIMG_FILE_HEAD_LEN
+            || chunk_head_len < SIMG_CHUNK_HEAD_LEN
+            || block_size == 0
+            || !block_size.is_multiple_of(4)
+        {
+            return Err("Error: unsupported sparse image");
+        };
+        std::io::copy(
+            &mut (&mut inner).take((file_head_len - SIMG_FILE_HEAD_LEN) as u64),
+            &mut std::io::sink(),
+        )
+        .map_err(|_| "Error: read sparse image failed")?;
+        Ok(SimgReader {
+            inner,
+            block_size,
+            chunk_head_len,
+            chunks_left: field32(20),
+            length: field32(16) as u64 * block_size,
+            done: 0,
+            chunk: SimgChunk::Zero(0),
+        })
+    }
+
+    /// bytes of the expanded image
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// read the next chunk head, false if there is none left
+    fn next_chunk(&mut self) -> std::io::Result<bool> {
+        let invalid = || std::io::Error::other("invalid sparse image chunk");
+        loop {
+            if self.chunks_left == 0 {
+                if self.done != self.length {
+                    return Err(invalid());
+                };
+                return Ok(false);
+            };
+            self.chunks_left -= 1;
+            let mut head = vec![0; self.chunk_head_len];
+            self.inner.read_exact(&mut head)?;
+            let kind = u16::from_le_bytes(head[..2].try_into().unwrap());
+            let size = u32::from_le_bytes(head[4..8].try_into().unwrap()) as u64 * self.block_size;
+            let total = u32::from_le_bytes(head[8..12].try_into().unwrap()) as u64;
+            let data_len = total
+                .checked_sub(self.chunk_head_len as u64)
+                .ok_or_else(invalid)?;
+            if self.done + size > self.length {
+                return Err(invalid());
+            };
+            let mut word = [0; 4];
+            self.chunk = match kind {
+                SIMG_CHUNK_RAW if data_len == size => SimgChunk::Raw(size),
+                SIMG_CHUNK_FILL if data_len == 4 => {
+                    self.inner.read_exact(&mut word)?;
+                    SimgChunk::Fill(word, size)
+                }
+                SIMG_CHUNK_DONT_CARE if data_len == 0 => SimgChunk::Zero(size),
+                SIMG_CHUNK_CRC32 if data_len == 4 => {
+                    self.inner.read_exact(&mut word)?;
+                    continue;
+                }
+                _ => return Err(invalid()),
+            };
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Read for SimgReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let remain = match self.chunk {
+                SimgChunk::Raw(remain) | SimgChunk::Fill(_, remain) | SimgChunk::Zero(remain) => {
+                    remain
+                }
+            };
+            if remain == 0 {
+                if !self.next_chunk()? {
+                    return Ok(0);
+                };
+                continue;
+            };
+            let size = remain.min(buf.len() as u64) as usize;
+            let size = match &mut self.chunk {
+                SimgChunk::Raw(remain) => {
+                    let size = self.inner.read(&mut buf[..size])?;
+                    if size == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    };
+                    *remain -= size as u64;
+                    size
+                }
+                SimgChunk::Fill(word, remain) => {
+                    //chunk sizes are multiples of 4,so the pattern phase follows from remain
+                    let phase = (4 - *remain % 4) % 4;
+                    for (i, x) in buf[..size].iter_mut().enumerate() {
+                        *x = word[(phase as usize + i) % 4];
+                    }
+                    *remain -= size as u64;
+                    size
+                }
+                SimgChunk::Zero(remain) => {
+                    buf[..size].fill(0);
+                    *remain -= size as u64;
+                    size
+                }
+            };
+            self.done += size as u64;
+            return Ok(size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// image of `length` bytes, groups alternate between zeros and a counting pattern
+    fn sample_image(length: usize) -> Vec<u8> {
+        (0..length)
+            .map(|i| {
+                if (i / SIMG_GROUP_SIZE as usize).is_multiple_of(2) {
+                    0
+                } else {
+                    (i % 251) as u8 + 1
+                }
+            })
+            .collect()
+    }
+
+    fn encode(image: &[u8], block_size: u32) -> Vec<u8> {
+        let mut encoder = SimgEncoder::new(image.len() as u64, block_size).unwrap();
+        let mut out = encoder.head().to_vec();
+        //odd sized pushes so groups are cut across calls
+        for part in image.chunks(300_007) {
+            encoder.push(part, &mut out).unwrap();
+        }
+        encoder.finish(&mut out).unwrap();
+        out
+    }
+
+    fn expand(simg: &[u8]) -> (u64, Vec<u8>) {
+        let mut reader = SimgReader::new(simg).unwrap();
+        let mut image = Vec::new();
+        reader.read_to_end(&mut image).unwrap();
+        (reader.length(), image)
+    }
+
+    #[test]
+    fn round_trip() {
+        for block_size in [512, 4096] {
+            //zero group,data group,zero group and a tail shorter than a group
+            let length = 3 * SIMG_GROUP_SIZE as usize + 5 * block_size as usize;
+            let mut image = sample_image(length);
+            image[length - 1] = 0xAA;
+            let simg = encode(&image, block_size);
+            assert!(is_simg(&simg));
+            assert!(simg.len() as u64 <= SimgEncoder::max_length(length as u64));
+            //zero groups are stored as fills
+            assert!(simg.len() < 2 * SIMG_GROUP_SIZE as usize);
+            assert_eq!(expand(&simg), (length as u64, image));
+        }
+    }
+
+    #[test]
+    fn round_trip_zero_image() {
+        let image = vec![0; 2 * SIMG_GROUP_SIZE as usize];
+        let simg = encode(&image, 4096);
+        assert_eq!(
+            simg.len(),
+            SIMG_FILE_HEAD_LEN + 2 * (SIMG_CHUNK_HEAD_LEN + 4)
+        );
+        assert_eq!(expand(&simg), (image.len() as u64, image));
+    }
+
+    #[test]
+    fn encoder_rejects_bad_input() {
+        assert!(SimgEncoder::new(4096 + 512, 4096).is_err());
+        assert!(SimgEncoder::new(4096, 0).is_err());
+        let mut encoder = SimgEncoder::new(4096, 512).unwrap();
+        let mut out = Vec::new();
+        assert!(encoder.push(&[0; 4097], &mut out).is_err());
+        encoder.push(&[1; 512], &mut out).unwrap();
+        assert!(encoder.finish(&mut out).is_err());
+    }
+
+    /// sparse image as written by AOSP img2simg/fastboot: crc32 chunks, don't care holes, fills
+    fn aosp_image(block_size: u32) -> (Vec<u8>, Vec<u8>) {
+        let bs = block_size as usize;
+        let raw: Vec<u8> = (0..2 * bs).map(|i| (i % 253) as u8).collect();
+        let mut simg = Vec::new();
+        let mut image = Vec::new();
+        let mut chunks = 0;
+        let mut push = |simg: &mut Vec<u8>, head: [u8; SIMG_CHUNK_HEAD_LEN], data: &[u8]| {
+            simg.extend_from_slice(&head);
+            simg.extend_from_slice(data);
+            chunks += 1;
+        };
+        push(
+            &mut simg,
+            chunk_head(SIMG_CHUNK_CRC32, 0, 16),
+            &[1, 2, 3, 4],
+        );
+        push(
+            &mut simg,
+            chunk_head(SIMG_CHUNK_RAW, 2, (SIMG_CHUNK_HEAD_LEN + 2 * bs) as u32),
+            &raw,
+        );
+        image.extend_from_slice(&raw);
+        push(&mut simg, chunk_head(SIMG_CHUNK_DONT_CARE, 3, 12), &[]);
+        image.extend(std::iter::repeat_n(0, 3 * bs));
+        push(
+            &mut simg,
+            chunk_head(SIMG_CHUNK_FILL, 1, 16),
+            &[0xDE, 0xAD, 0xBE, 0xEF],
+        );
+        image.extend([0xDE, 0xAD, 0xBE, 0xEF].repeat(bs / 4));
+        push(
+            &mut simg,
+            chunk_head(SIMG_CHUNK_CRC32, 0, 16),
+            &[5, 6, 7, 8],
+        );
+        let blocks = (image.len() / bs) as u32;
+        let mut head = [0; SIMG_FILE_HEAD_LEN];
+        head[..4].copy_from_slice(&SIMG_MAGIC.to_le_bytes());
+        head[4..6].copy_from_slice(&SIMG_MAJOR_VERSION.to_le_bytes());
+        head[8..10].copy_from_slice(&(SIMG_FILE_HEAD_LEN as u16).to_le_bytes());
+        head[10..12].copy_from_slice(&(SIMG_CHUNK_HEAD_LEN as u16).to_le_bytes());
+        head[12..16].copy_from_slice(&block_size.to_le_bytes());
+        head[16..20].copy_from_slice(&blocks.to_le_bytes());
+        head[20..24].copy_from_slice(&(chunks as u32).to_le_bytes());
+        simg.splice(0..0, head);
+        (simg, image)
+    }
+
+    #[test]
+    fn aosp_chunks() {
+        for block_size in [512, 4096] {
+            let (simg, image) = aosp_image(block_size);
+            assert_eq!(expand(&simg), (image.len() as u64, image));
+        }
+    }
+
+    #[test]
+    fn reader_rejects_broken_images() {
+        let (simg, _) = aosp_image(4096);
+        //chunk count larger than the chunks present
+        let mut truncated = simg.clone();
+        truncated.truncate(simg.len() - 16);
+        let mut reader = SimgReader::new(&truncated[..]).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        //fill chunk with a wrong total size
+        let mut bad_fill = simg.clone();
+        let fill = SIMG_FILE_HEAD_LEN + 16 + SIMG_CHUNK_HEAD_LEN + 2 * 4096 + 12;
+        bad_fill[fill + 8..fill + 12].copy_from_slice(&20u32.to_le_bytes());
+        let mut reader = SimgReader::new(&bad_fill[..]).unwrap();
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        let mut bad_magic = simg;
+        bad_magic[0] ^= 1;
+        assert!(SimgReader::new(&bad_magic[..]).is_err());
+    }
+}
@@ -0,0 +1,373 @@
+///sparse image helper module
+/// Firmware partitions are mostly zeros, images are stored as the concatenated non-zero
+/// blocks followed by an extent table (logical offset u64,length u64 per extent).
+/// All-zero blocks are dropped and written back as zeros on restore.
+/// The data may be packed by `compress_helper`, the extent table is never compressed.
+use crate::backup_factory::SegmentReader;
+use crate::compress_helper::{BlockPacker, Compression, CompressionSetting};
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// granularity of zero block detection
+pub const SPARSE_BLOCK_SIZE: usize = 4096;
+/// bytes of an encoded extent
+pub const SPARSE_EXTENT_LEN: usize = 16;
+const SPARSE_CHUNK_SIZE: usize = 1024 * 1024;
+static ZERO_CHUNK: [u8; SPARSE_CHUNK_SIZE] = [0; SPARSE_CHUNK_SIZE];
+
+/// A run of non-zero data in the logical image (bytes)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SparseExtent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// encode extent table
+pub fn encode_extents(extents: &[SparseExtent]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(extents.len() * SPARSE_EXTENT_LEN);
+    for extent in extents {
+        buffer.extend_from_slice(&extent.offset.to_le_bytes());
+        buffer.extend_from_slice(&extent.length.to_le_bytes());
+    }
+    buffer
+}
+
+/// decode and check extent table of an image with given logical and stored length
+pub fn decode_extents(
+    buffer: &[u8],
+    length: u64,
+    stored_length: u64,
+) -> Result<Vec<SparseExtent>, &'static str> {
+    if !buffer.len().is_multiple_of(SPARSE_EXTENT_LEN) {
+        return Err("Error: invalid sparse extent table");
+    };
+    let mut extents = Vec::new();
+    let mut end = 0;
+    let mut total = 0;
+    for raw in buffer.chunks(SPARSE_EXTENT_LEN) {
+        let extent = SparseExtent {
+            offset: u64::from_le_bytes(raw[..8].try_into().unwrap()),
+            length: u64::from_le_bytes(raw[8..].try_into().unwrap()),
+        };
+        //offsets and lengths come from disk, reject tables that overflow
+        let extent_end = extent
+            .offset
+            .checked_add(extent.length)
+            .ok_or("Error: invalid sparse extent table")?;
+        if extent.offset < end || extent_end > length {
+            return Err("Error: invalid sparse extent table");
+        };
+        end = extent_end;
+        total += extent.length;
+        extents.push(extent);
+    }
+    if total != stored_length {
+        return Err("Error: invalid sparse extent table");
+    };
+    Ok(extents)
+}
+
+/// Reads an image and yields only its non-zero blocks followed by the extent table
+/// the sha256 of the full (logical) segment is available after everything is read
+pub struct SparseReader<'a> {
+    source: Box<dyn Read + 'a>,
+    length: u64,
+    chunk: Vec<u8>,
+    //logical offset and valid bytes of chunk
+    chunk_base: u64,
+    chunk_len: usize,
+    //next unexamined byte of chunk
+    chunk_pos: usize,
+    //pending output range of chunk
+    out: (usize, usize),
+    extents: Vec<SparseExtent>,
+    table: Option<Vec<u8>>,
+    table_pos: usize,
+    stored_length: u64,
+    //packs the data if compressed, None after the last block is packed
+    packer: Option<BlockPacker>,
+    packed_length: u64,
+    hasher: Sha256,
+    pb: Option<ProgressBar>,
+}
+
+impl<'a> SparseReader<'a> {
+    /// source yields the length bytes of the image
+    pub fn new(source: Box<dyn Read + 'a>, length: u64, pb: Option<ProgressBar>) -> Self {
+        SparseReader {
+            source,
+            length,
+            chunk: vec![0; SPARSE_CHUNK_SIZE],
+            chunk_base: 0,
+            chunk_len: 0,
+            chunk_pos: 0,
+            out: (0, 0),
+            extents: Vec::new(),
+            table: None,
+            table_pos: 0,
+            stored_length: 0,
+            packer: None,
+            packed_length: 0,
+            hasher: Sha256::new(),
+            pb,
+        }
+    }
+
+    /// compress the data (not the extent table)
+    pub fn compress(mut self, setting: CompressionSetting) -> Self {
+        if setting.compression != Compression::None {
+            self.packer = Some(BlockPacker::new(setting));
+        };
+        self
+    }
+
+    /// bytes of (packed) data yielded before the extent table
+    pub fn packed_length(&self) -> u64 {
+        self.packed_length
+    }
+
+    /// return (extents,stored data length,sha256 of the logical image), call after EOF
+    pub fn finish(self) -> (Vec<SparseExtent>, u64, [u8; 32]) {
+        (
+            self.extents,
+            self.stored_length,
+            self.hasher.finalize().into(),
+        )
+    }
+
+    fn is_zero_block(&self, start: usize) -> bool {
+        let end = (start + SPARSE_BLOCK_SIZE).min(self.chunk_len);
+        self.chunk[start..end].iter().all(|&x| x == 0)
+    }
+
+    /// examine next blocks of chunk, queue a run of non-zero blocks for output
+    fn next_run(&mut self) {
+        while self.chunk_pos < self.chunk_len && self.is_zero_block(self.chunk_pos) {
+            self.chunk_pos += SPARSE_BLOCK_SIZE;
+        }
+        let start = self.chunk_pos.min(self.chunk_len);
+        while self.chunk_pos < self.chunk_len && !self.is_zero_block(self.chunk_pos) {
+            self.chunk_pos += SPARSE_BLOCK_SIZE;
+        }
+        let end = self.chunk_pos.min(self.chunk_len);
+        if start == end {
+            return;
+        };
+        let offset = self.chunk_base + start as u64;
+        let length = (end - start) as u64;
+        match self.extents.last_mut() {
+            Some(last) if last.offset + last.length == offset => last.length += length,
+            _ => self.extents.push(SparseExtent { offset, length }),
+        }
+        self.stored_length += length;
+        self.out = (start, end);
+    }
+
+    fn next_chunk(&mut self) -> std::io::Result<()> {
+        self.chunk_base += self.chunk_len as u64;
+        self.chunk_len = (self.length - self.chunk_base).min(SPARSE_CHUNK_SIZE as u64) as usize;
+        self.chunk_pos = 0;
+        self.source.read_exact(&mut self.chunk[..self.chunk_len])?;
+        self.hasher.update(&self.chunk[..self.chunk_len]);
+        if let Some(pb) = &self.pb {
+            pb.inc(self.chunk_len as u64);
+        };
+        Ok(())
+    }
+}
+
+impl Read for SparseReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(packer) = &mut self.packer {
+                if packer.has_output() {
+                    let size = packer.read_output(buf);
+                    self.packed_length += size as u64;
+                    return Ok(size);
+                };
+            };
+            let (start, end) = self.out;
+            if start < end {
+                if let Some(packer) = &mut self.packer {
+                    self.out.0 += packer.push(&self.chunk[start..end])?;
+                    continue;
+                };
+                let size = (end - start).min(buf.len());
+                buf[..size].copy_from_slice(&self.chunk[start..start + size]);
+                self.out.0 += size;
+                self.packed_length += size as u64;
+                return Ok(size);
+            };
+            if self.chunk_pos < self.chunk_len {
+                self.next_run();
+                continue;
+            };
+            if self.chunk_base + (self.chunk_len as u64) < self.length {
+                self.next_chunk()?;
+                continue;
+            };
+            if let Some(mut packer) = self.packer.take() {
+                packer.finish()?;
+                if packer.has_output() {
+                    self.packer = Some(packer);
+                };
+                continue;
+            };
+            let table = self
+                .table
+                .get_or_insert_with(|| encode_extents(&self.extents));
+            let size = (table.len() - self.table_pos).min(buf.len());
+            buf[..size].copy_from_slice(&table[self.table_pos..self.table_pos + size]);
+            self.table_pos += size;
+            return Ok(size);
+        }
+    }
+}
+
+/// Takes the stored data of a sparse image and expands it to the logical image,
+/// written sequentially to target if any. Gaps are written as zeros.
+/// Bytes behind the stored data (the extent table) are ignored.
+pub struct SparseExpander<'a> {
+    target: Option<&'a mut dyn Write>,
+    length: u64,
+    extents: Vec<SparseExtent>,
+    stored_length: u64,
+    received: u64,
+    extent_index: usize,
+    extent_done: u64,
+    //logical bytes expanded
+    position: u64,
+    hasher: Sha256,
+    pb: Option<ProgressBar>,
+}
+
+impl<'a> SparseExpander<'a> {
+    pub fn new(
+        target: Option<&'a mut dyn Write>,
+        length: u64,
+        extents: Vec<SparseExtent>,
+        pb: Option<ProgressBar>,
+    ) -> Self {
+        SparseExpander {
+            target,
+            length,
+            stored_length: extents.iter().map(|x| x.length).sum(),
+            extents,
+            received: 0,
+            extent_index: 0,
+            extent_done: 0,
+            position: 0,
+            hasher: Sha256::new(),
+            pb,
+        }
+    }
+
+    /// write zeros up to logical offset end
+    fn fill_zero(&mut self, end: u64) -> std::io::Result<()> {
+        while self.position < end {
+            let size = (end - self.position).min(SPARSE_CHUNK_SIZE as u64) as usize;
+            self.emit(&ZERO_CHUNK[..size])?;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if let Some(target) = &mut self.target {
+            target.write_all(data)?;
+        };
+        self.hasher.update(data);
+        self.position += data.len() as u64;
+        if let Some(pb) = &self.pb {
+            pb.inc(data.len() as u64);
+        };
+        Ok(())
+    }
+
+    /// fill the tail with zeros, return sha256 of the logical image
+    pub fn finish(mut self) -> Result<[u8; 32], &'static str> {
+        if self.received != self.stored_length {
+            return Err("Error: sparse image truncated");
+        };
+        self.fill_zero(self.length)
+            .map_err(|_| "Error: write target disk failed")?;
+        Ok(self.hasher.finalize().into())
+    }
+}
+
+impl Write for SparseExpander<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let usable = (self.stored_length - self.received).min(buf.len() as u64) as usize;
+        let mut data = &buf[..usable];
+        while !data.is_empty() {
+            let extent = self.extents[self.extent_index];
+            self.fill_zero(extent.offset + self.extent_done)?;
+            let size = (extent.length - self.extent_done).min(data.len() as u64) as usize;
+            self.emit(&data[..size])?;
+            data = &data[size..];
+            self.extent_done += size as u64;
+            if self.extent_done == extent.length {
+                self.extent_index += 1;
+                self.extent_done = 0;
+            };
+        }
+        self.received += usable as u64;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.target {
+            Some(target) => target.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// stored size (non-zero data and extent table) of a file segment if saved sparse,
+/// the data is compressed as it would be stored
+pub fn measure_sparse_size(
+    file: &File,
+    offset: u64,
+    length: u64,
+    compression: CompressionSetting,
+) -> Result<u64, &'static str> {
+    let source = Box::new(SegmentReader::new(file, offset, length));
+    let mut reader = SparseReader::new(source, length, None).compress(compression);
+    let total = std::io::copy(&mut reader, &mut std::io::sink())
+        .map_err(|_| "Error: read source disk failed")?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extents_round_trip() {
+        let extents = [
+            SparseExtent {
+                offset: 0,
+                length: 4096,
+            },
+            SparseExtent {
+                offset: 8192,
+                length: 8192,
+            },
+        ];
+        let table = encode_extents(&extents);
+        assert_eq!(decode_extents(&table, 16384, 12288).unwrap(), extents);
+        // stored length, overlap and image length are checked
+        assert!(decode_extents(&table, 16384, 4096).is_err());
+        assert!(decode_extents(&table, 12288, 12288).is_err());
+        assert!(decode_extents(&table[..20], 16384, 12288).is_err());
+    }
+
+    #[test]
+    fn extents_overflow() {
+        let table = encode_extents(&[SparseExtent {
+            offset: u64::MAX - 1,
+            length: 4096,
+        }]);
+        assert!(decode_extents(&table, u64::MAX, 4096).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! Init,install and switch of a two slot layout on an image dir env,
+//! every "reboot" opens the env again so by-name links follow the gpt tables.
+use gpt::disk::LogicalBlockSize;
+use gpt::mbr::ProtectiveMBR;
+use gpt::{partition_types, GptConfig};
+use librvab_cli_r::device_env::{set_device_env, ImageDirEnv, IMAGE_ENV_CONFIG_NAME};
+use librvab_cli_r::metadata::Metadata;
+use librvab_cli_r::{
+    check_slots_config, generate_template_init_config_file, switch_to_slot,
+    try_init_partition_table_layout, try_init_userdata_partition, update_config_to_all_slots,
+};
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const SECTOR: u64 = 512;
+//each slot gets at least USERDATA_MIN_SIZE of userdata, the image is sparse
+const DISK_SIZE: u64 = 12 * 1024 * 1024 * 1024;
+
+/// sda with persist,modem_a (dyn),boot_a,boot_b (firmware) and userdata taking the rest
+fn create_env(root: &Path) {
+    let _ = fs::remove_dir_all(root);
+    fs::create_dir_all(root.join("block")).unwrap();
+    fs::create_dir_all(root.join("proc")).unwrap();
+    fs::write(
+        root.join(IMAGE_ENV_CONFIG_NAME),
+        "[[lun]]\nname = \"sda\"\nsector_size = 512\n",
+    )
+    .unwrap();
+    fs::write(
+        root.join("proc/bootconfig"),
+        "androidboot.slot_suffix = \"_a\"\n",
+    )
+    .unwrap();
+    let disk_path = root.join("block/sda");
+    let mut file = File::create(&disk_path).unwrap();
+    file.set_len(DISK_SIZE).unwrap();
+    ProtectiveMBR::with_lb_size(u32::try_from(DISK_SIZE / SECTOR - 1).unwrap())
+        .overwrite_lba0(&mut file)
+        .unwrap();
+    let mut disk = GptConfig::new()
+        .writable(true)
+        .logical_block_size(LogicalBlockSize::Lb512)
+        .create(&disk_path)
+        .unwrap();
+    for (name, size) in [
+        ("persist", 1024 * 1024),
+        ("modem_a", 1024 * 1024),
+        ("boot_a", 256 * 1024),
+        ("boot_b", 256 * 1024),
+    ] {
+        disk.add_partition(name, size, partition_types::LINUX_FS, 0, None)
+            .unwrap();
+    }
+    let free = disk.find_free_sectors().iter().map(|x| x.1).max().unwrap();
+    disk.add_partition(
+        "userdata",
+        free * SECTOR,
+        partition_types::LINUX_FS,
+        0,
+        None,
+    )
+    .unwrap();
+    disk.write().unwrap();
+}
+
+/// open the env again, like rebooting the device
+fn reboot(root: &Path) {
+    ImageDirEnv::open(root.to_str().unwrap()).unwrap();
+}
+
+/// (first lba,last lba) of a partition in the gpt of sda
+fn gpt_location(root: &Path, name: &str) -> (u64, u64) {
+    let disk = GptConfig::new()
+        .writable(false)
+        .logical_block_size(LogicalBlockSize::Lb512)
+        .open(root.join("block/sda"))
+        .unwrap();
+    let part = disk.partitions().values().find(|x| x.name == name).unwrap();
+    (part.first_lba, part.last_lba)
+}
+
+fn boot_a_bytes(root: &Path) -> Vec<u8> {
+    let (first_lba, last_lba) = gpt_location(root, "boot_a");
+    let mut buffer = vec![0; ((last_lba - first_lba + 1) * SECTOR) as usize];
+    File::open(root.join("block/sda"))
+        .unwrap()
+        .read_exact_at(&mut buffer, first_lba * SECTOR)
+        .unwrap();
+    buffer
+}
+
+fn write_boot_a(root: &Path, pattern: u8) {
+    let (first_lba, last_lba) = gpt_location(root, "boot_a");
+    let data = vec![pattern; ((last_lba - first_lba + 1) * SECTOR) as usize];
+    File::options()
+        .write(true)
+        .open(root.join("block/sda"))
+        .unwrap()
+        .write_all_at(&data, first_lba * SECTOR)
+        .unwrap();
+}
+
+/// dyn partitions of the slot are where the gpt has them and the metadata names it current
+fn assert_on_slot(root: &Path, slot_name: &str) {
+    let metadata = Metadata::from_fw_metadata().unwrap();
+    assert_eq!(metadata.current_slot, slot_name);
+    let slot = &metadata.slots[slot_name];
+    for (name, raw_part) in slot.dyn_partition_set.iter() {
+        assert_eq!(
+            gpt_location(root, name),
+            (raw_part.start_lba, raw_part.end_lba),
+            "{} of slot {}",
+            name,
+            slot_name
+        );
+    }
+}
+
+#[test]
+fn init_install_and_switch() {
+    let root = std::env::temp_dir().join(format!("rvab_image_env_{}", std::process::id()));
+    create_env(&root);
+    let env = ImageDirEnv::open(root.to_str().unwrap()).unwrap();
+    set_device_env(Box::new(env)).unwrap();
+    let path = |x: &str| -> PathBuf { root.join(x) };
+    fs::write(path("exclude.txt"), "persist\n").unwrap();
+    fs::write(path("dyn.txt"), "modem_a\n").unwrap();
+    write_boot_a(&root, 0x5A);
+
+    let cfg = path("cfg.toml");
+    generate_template_init_config_file(
+        cfg.clone(),
+        Some(path("exclude.txt").display().to_string()),
+        Some(path("dyn.txt").display().to_string()),
+        None,
+        false,
+    )
+    .unwrap();
+    //partition backups, a template picks losetup where loop devices work
+    let config = fs::read_to_string(&cfg).unwrap();
+    assert!(config.contains("backup_type_code = "));
+    let config: String = config
+        .lines()
+        .map(|x| match x.starts_with("backup_type_code = ") {
+            true => "backup_type_code = 0\n".to_string(),
+            false => format!("{}\n", x),
+        })
+        .collect();
+    fs::write(&cfg, config).unwrap();
+    let cfg = cfg.display().to_string();
+    try_init_userdata_partition(&cfg, &None, true).unwrap();
+    reboot(&root);
+    let report = check_slots_config(&cfg).unwrap();
+    //the layout check of slot b wants its own userdata in place, that is only true on slot b
+    assert!(report.slots.iter().all(|x| x.firmware.is_pass()));
+    assert!(report.slots[0].is_pass(), "{:?}", report.slots[0]);
+    try_init_partition_table_layout(&cfg, &None, true, true).unwrap();
+    reboot(&root);
+    update_config_to_all_slots(&cfg).unwrap();
+    assert_on_slot(&root, "a");
+    //backup partitions are made by install, a switch never changes them
+    let metadata = Metadata::from_fw_metadata().unwrap();
+    for slot_name in ["a", "b"] {
+        let slot = &metadata.slots[slot_name];
+        assert_eq!(
+            gpt_location(&root, &format!("rvab_backup_{}", slot_name)),
+            (slot.backup_target_start, slot.backup_target_end)
+        );
+    }
+
+    //slot b has no firmware backup yet and keeps the current firmware
+    switch_to_slot("b", true).unwrap();
+    reboot(&root);
+    assert_on_slot(&root, "b");
+    assert!(boot_a_bytes(&root).iter().all(|&x| x == 0x5A));
+    let metadata = Metadata::from_fw_metadata().unwrap();
+    assert_eq!(metadata.slots["a"].android_slot, "a");
+    assert_ne!(
+        metadata.slots["a"].dyn_partition_set["userdata"].start_lba,
+        metadata.slots["b"].dyn_partition_set["userdata"].start_lba
+    );
+
+    //back on slot a its own firmware is restored
+    write_boot_a(&root, 0xA5);
+    switch_to_slot("a", true).unwrap();
+    reboot(&root);
+    assert_on_slot(&root, "a");
+    assert!(boot_a_bytes(&root).iter().all(|&x| x == 0x5A));
+    fs::remove_dir_all(&root).unwrap();
+}